@@ -0,0 +1,117 @@
+// Benchmarks for how each encoding handles instructions that carry many messages,
+// such as a page of chat history.
+// Run with `cargo bench --bench encoding`.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use polychat_ipc::{
+    api::schema::{
+        instructions::{CoreInstructionType, DeserializableCoreInstr, SerializableCoreInstr},
+        protocol::{Encoding, Framing, DEFAULT_MAX_FRAME_SIZE}
+    },
+    core::socket_handler::{SocketHandler, SocketReader},
+    polychat_plugin_sdk_rust::socket::SocketCommunicator,
+    utils::socket::get_socket_name
+};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+/// How many messages are in each instruction.
+const MESSAGE_COUNT: u64 = 100;
+
+const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::MessagePack, Encoding::Cbor];
+
+/// Roughly what a protocol would send for each chat message.
+#[derive(Serialize, Deserialize, Debug)]
+struct Message {
+    id: String,
+    conversation_id: String,
+    author: String,
+    body: String,
+    timestamp: u64,
+    edited: bool,
+    reactions: Vec<String>,
+}
+
+fn create_messages() -> Vec<Message> {
+    (0..MESSAGE_COUNT).map(|i| Message {
+        id: format!("message-{}", i),
+        conversation_id: "conversation-1".to_string(),
+        author: format!("user-{}", i % 5),
+        body: format!("Message number {}, with a line break\nand some more text after it.", i),
+        timestamp: 1_700_000_000_000 + i * 1000,
+        edited: i % 10 == 0,
+        reactions: vec!["+1".to_string(); (i % 3) as usize],
+    }).collect()
+}
+
+fn create_instruction() -> SerializableCoreInstr<Vec<Message>> {
+    SerializableCoreInstr {
+        instruction_type: CoreInstructionType::AuthAccountResponse,
+        request_id: Some(1),
+        payload: create_messages(),
+    }
+}
+
+fn bench_encode_decode(c: &mut Criterion) {
+    let instruction = create_instruction();
+
+    let mut group = c.benchmark_group("encoding");
+    group.throughput(Throughput::Elements(MESSAGE_COUNT));
+    for encoding in ENCODINGS {
+        let data = instruction.encode(encoding).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("encode", encoding), &encoding, |b, encoding| {
+            b.iter(|| instruction.encode(*encoding).unwrap())
+        });
+        // Both the instruction and its payload, as the core does when handling it.
+        group.bench_with_input(BenchmarkId::new("decode", encoding), &data, |b, data| {
+            b.iter(|| {
+                let instr = DeserializableCoreInstr::decode(data, encoding).unwrap();
+                instr.payload.decode::<Vec<Message>>().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+async fn connect(name: &str, encoding: Encoding) -> (SocketReader, SocketCommunicator) {
+    let mut handler = SocketHandler::new(name).unwrap();
+    let socket_name = get_socket_name(name);
+    let (accepted, comms) = tokio::join!(handler.accept(), SocketCommunicator::new(&socket_name));
+    let (mut reader, _writer) = accepted.unwrap();
+    let mut comms = comms.unwrap();
+    reader.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+    reader.set_encoding(encoding);
+    comms.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE).await;
+    comms.set_encoding(encoding).await;
+    (reader, comms)
+}
+
+// From the plugin encoding an instruction to the core decoding its payload.
+fn bench_socket(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let instruction = create_instruction();
+
+    let mut group = c.benchmark_group("encoding_socket");
+    group.measurement_time(Duration::from_secs(10));
+    group.throughput(Throughput::Elements(MESSAGE_COUNT));
+    for encoding in ENCODINGS {
+        let name = format!("polychat_bench_encoding_{}", encoding);
+        let (mut reader, mut comms) = runtime.block_on(connect(&name, encoding));
+        group.bench_function(BenchmarkId::new("round_trip", encoding), |b| b.iter_custom(|iters| runtime.block_on(async {
+            let start = Instant::now();
+            for _ in 0..iters {
+                comms.send_core_instruction(&instruction).await.unwrap();
+                let instr = reader.get_instruction().await.unwrap();
+                instr.payload.decode::<Vec<Message>>().unwrap();
+            }
+            start.elapsed()
+        })));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode_decode, bench_socket);
+criterion_main!(benches);
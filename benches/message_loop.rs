@@ -0,0 +1,91 @@
+// Benchmarks for how quickly instructions from a plugin reach the core.
+// Run with `cargo bench --bench message_loop`.
+
+use std::{path::PathBuf, time::{Duration, Instant}};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use polychat_ipc::{
+    api::schema::instructions::{CoreInstructionType, SerializableCoreInstr},
+    core::socket_handler::SocketHandler,
+    polychat_plugin_sdk_rust::socket::SocketCommunicator,
+    process_management::process::Process,
+    utils::socket::get_socket_name
+};
+use serde_json::value::RawValue;
+use tokio::runtime::Runtime;
+
+// An executable that stays running without connecting, so that the benchmark
+// can connect in its place. It shouldn't print anything, since reading its
+// output would compete with the message loop.
+#[cfg(target_os = "windows")]
+fn test_program() -> PathBuf {
+    PathBuf::from("calc.exe")
+}
+#[cfg(not(target_os = "windows"))]
+fn test_program() -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join("polychat_bench_idle.sh");
+    std::fs::write(&path, "#!/bin/sh\nexec sleep 3600\n").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+/// How many instructions are sent at once when measuring throughput.
+const BATCH_SIZE: u64 = 50;
+
+fn create_message() -> SerializableCoreInstr<Box<RawValue>> {
+    SerializableCoreInstr {
+        instruction_type: CoreInstructionType::AuthAccountResponse,
+        request_id: None,
+        payload: RawValue::from_string("{}".to_string()).unwrap(),
+    }
+}
+
+async fn connect(name: &str) -> (Process, SocketCommunicator) {
+    let socket = SocketHandler::new(name).unwrap();
+    let process = Process::new(test_program(), socket).unwrap();
+    let mut comms = SocketCommunicator::new(&get_socket_name(name)).await.unwrap();
+    comms.authenticate(process.get_auth_token()).await.unwrap();
+    (process, comms)
+}
+
+fn bench_message_loop(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (mut process, mut comms) = runtime.block_on(connect("polychat_bench_message_loop"));
+    let message = create_message();
+
+    let mut group = c.benchmark_group("message_loop");
+    group.measurement_time(Duration::from_secs(10));
+
+    // From the plugin sending an instruction to the core receiving it.
+    group.bench_function("latency", |b| b.iter_custom(|iters| runtime.block_on(async {
+        let start = Instant::now();
+        for _ in 0..iters {
+            comms.send_core_instruction(&message).await.unwrap();
+            process.get_next_instruction().await.unwrap().unwrap();
+        }
+        start.elapsed()
+    })));
+
+    // Instructions sent back to back, as fast as the plugin can send them.
+    group.throughput(Throughput::Elements(BATCH_SIZE));
+    group.bench_function("throughput", |b| b.iter_custom(|iters| runtime.block_on(async {
+        let start = Instant::now();
+        for _ in 0..iters {
+            for _ in 0..BATCH_SIZE {
+                comms.send_core_instruction(&message).await.unwrap();
+            }
+            for _ in 0..BATCH_SIZE {
+                process.get_next_instruction().await.unwrap().unwrap();
+            }
+        }
+        start.elapsed()
+    })));
+    group.finish();
+
+    runtime.block_on(async move { drop(process) });
+}
+
+criterion_group!(benches, bench_message_loop);
+criterion_main!(benches);
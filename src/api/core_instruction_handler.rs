@@ -1,7 +1,13 @@
 use super::schema::{
     auth::{AuthAccountResponse},
+    conversation::ConversationListInstruction,
+    message::{
+        MessageReceivedInstruction, MessageSendResultInstruction, HistoryPageInstruction, MessageEditedInstruction,
+        MessageDeletedInstruction, ReactionsChangedInstruction, MessageActionFailedInstruction
+    },
     protocol::InitDataInstruction,
     keepalive::KeepaliveInstruction,
+    log::LogInstruction,
     instructions::{CoreInstructionType, DeserializableCoreInstr},
 };
 
@@ -16,6 +22,15 @@ pub trait CoreInstructionHandler {
     fn on_init(&self, data: InitDataInstruction);
     fn on_keepalive_response(&self, response: KeepaliveInstruction);
     fn on_auth_account_response(&self, response: AuthAccountResponse);
+    fn on_log(&self, record: LogInstruction);
+    fn on_conversation_list(&self, list: ConversationListInstruction);
+    fn on_message_received(&self, received: MessageReceivedInstruction);
+    fn on_message_send_result(&self, result: MessageSendResultInstruction);
+    fn on_history_page(&self, page: HistoryPageInstruction);
+    fn on_message_edited(&self, edited: MessageEditedInstruction);
+    fn on_message_deleted(&self, deleted: MessageDeletedInstruction);
+    fn on_reactions_changed(&self, changed: ReactionsChangedInstruction);
+    fn on_message_action_failed(&self, failed: MessageActionFailedInstruction);
 }
 
 /// A function that finishes processing the CoreInstruction, and sends the
@@ -25,7 +40,7 @@ pub fn call_core_handler(unprocessed_instr: &DeserializableCoreInstr,
 {
     match unprocessed_instr.instruction_type {
         CoreInstructionType::Init => {
-            match serde_json::from_str::<InitDataInstruction>(unprocessed_instr.payload.get()) {
+            match unprocessed_instr.payload.decode::<InitDataInstruction>() {
                 Ok(data) => {
                     trace!("Got valid data for init. Calling handler function.");
                     interface.as_ref().on_init(data);
@@ -33,12 +48,12 @@ pub fn call_core_handler(unprocessed_instr: &DeserializableCoreInstr,
                 },
                 Err(e) => {
                     error!("Invalid data for instruction type Init.");
-                    return Err(e.into());
+                    Err(e)
                 }
             }
         },
         CoreInstructionType::AuthAccountResponse => {
-            match serde_json::from_str::<AuthAccountResponse>(unprocessed_instr.payload.get()) {
+            match unprocessed_instr.payload.decode::<AuthAccountResponse>() {
                 Ok(data) => {
                     trace!("Got valid data for auth response. Calling handler function.");
                     interface.as_ref().on_auth_account_response(data);
@@ -46,13 +61,13 @@ pub fn call_core_handler(unprocessed_instr: &DeserializableCoreInstr,
                 },
                 Err(e) => {
                     error!("Invalid data for instruction type AuthAccountResponse.");
-                    return Err(e.into());
+                    Err(e)
                 }
             }
 
         },
         CoreInstructionType::KeepaliveResponse => {
-            match serde_json::from_str::<KeepaliveInstruction>(unprocessed_instr.payload.get()) {
+            match unprocessed_instr.payload.decode::<KeepaliveInstruction>() {
                 Ok(data) => {
                     trace!("Got valid data for init. Calling handler function.");
                     interface.as_ref().on_keepalive_response(data);
@@ -60,10 +75,127 @@ pub fn call_core_handler(unprocessed_instr: &DeserializableCoreInstr,
                 },
                 Err(e) => {
                     error!("Invalid data for instruction type Keepalive.");
-                    return Err(e.into());
+                    Err(e)
                 }
             }
 
         },
+        CoreInstructionType::Log => {
+            match unprocessed_instr.payload.decode::<LogInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for log. Calling handler function.");
+                    interface.as_ref().on_log(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type Log.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::ConversationList => {
+            match unprocessed_instr.payload.decode::<ConversationListInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for ConversationList. Calling handler function.");
+                    interface.as_ref().on_conversation_list(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type ConversationList.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::MessageReceived => {
+            match unprocessed_instr.payload.decode::<MessageReceivedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for MessageReceived. Calling handler function.");
+                    interface.as_ref().on_message_received(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type MessageReceived.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::MessageSendResult => {
+            match unprocessed_instr.payload.decode::<MessageSendResultInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for MessageSendResult. Calling handler function.");
+                    interface.as_ref().on_message_send_result(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type MessageSendResult.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::HistoryPage => {
+            match unprocessed_instr.payload.decode::<HistoryPageInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for HistoryPage. Calling handler function.");
+                    interface.as_ref().on_history_page(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type HistoryPage.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::MessageEdited => {
+            match unprocessed_instr.payload.decode::<MessageEditedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for MessageEdited. Calling handler function.");
+                    interface.as_ref().on_message_edited(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type MessageEdited.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::MessageDeleted => {
+            match unprocessed_instr.payload.decode::<MessageDeletedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for MessageDeleted. Calling handler function.");
+                    interface.as_ref().on_message_deleted(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type MessageDeleted.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::ReactionsChanged => {
+            match unprocessed_instr.payload.decode::<ReactionsChangedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for ReactionsChanged. Calling handler function.");
+                    interface.as_ref().on_reactions_changed(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type ReactionsChanged.");
+                    Err(e)
+                }
+            }
+        },
+        CoreInstructionType::MessageActionFailed => {
+            match unprocessed_instr.payload.decode::<MessageActionFailedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for MessageActionFailed. Calling handler function.");
+                    interface.as_ref().on_message_action_failed(data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type MessageActionFailed.");
+                    Err(e)
+                }
+            }
+        },
     }
 }
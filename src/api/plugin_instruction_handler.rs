@@ -1,7 +1,14 @@
 use super::schema::{
     auth::AuthAccountInstruction,
+    conversation::ListConversationsInstruction,
+    message::{
+        SendMessageInstruction, FetchHistoryInstruction, EditMessageInstruction, DeleteMessageInstruction,
+        ReactToMessageInstruction
+    },
     keepalive::KeepaliveInstruction,
-    instructions::{PluginInstructionType, DeserializablePluginInstr}
+    protocol::{InitAckInstruction, InitRejectedInstruction},
+    shutdown::ShutdownInstruction,
+    instructions::{PluginInstructionType, DeserializablePluginInstr, RequestId}
 };
 
 use anyhow::Result;
@@ -11,9 +18,30 @@ use std::sync::Arc;
 
 /// A trait to be implemented by the plugin for instructions sent from the
 /// core to the plugin.
+/// 
+/// Every instruction comes with the request ID allocated by the core, which
+/// must be set as the `request_id` of the core instruction sent in response.
 pub trait PluginInstructionHandler {
-    fn on_keepalive(&self, data: KeepaliveInstruction);
-    fn on_auth_account(&self, data: AuthAccountInstruction);
+    fn on_keepalive(&self, request_id: RequestId, data: KeepaliveInstruction);
+    fn on_auth_account(&self, request_id: RequestId, data: AuthAccountInstruction);
+    fn on_init_ack(&self, request_id: RequestId, data: InitAckInstruction);
+    /// The core will not send any more instructions after this.
+    fn on_init_rejected(&self, request_id: RequestId, data: InitRejectedInstruction);
+    /// The plugin should clean up and exit before the deadline in the instruction.
+    fn on_shutdown(&self, request_id: RequestId, data: ShutdownInstruction);
+    /// The plugin should answer with a ConversationList core instruction.
+    fn on_list_conversations(&self, request_id: RequestId, data: ListConversationsInstruction);
+    /// The plugin should answer with a MessageSendResult core instruction, and
+    /// send more without a request ID as the message's delivery state changes.
+    fn on_send_message(&self, request_id: RequestId, data: SendMessageInstruction);
+    /// The plugin should answer with a HistoryPage core instruction.
+    fn on_fetch_history(&self, request_id: RequestId, data: FetchHistoryInstruction);
+    /// The plugin should answer with a MessageEdited core instruction, or MessageActionFailed.
+    fn on_edit_message(&self, request_id: RequestId, data: EditMessageInstruction);
+    /// The plugin should answer with a MessageDeleted core instruction, or MessageActionFailed.
+    fn on_delete_message(&self, request_id: RequestId, data: DeleteMessageInstruction);
+    /// The plugin should answer with a ReactionsChanged core instruction, or MessageActionFailed.
+    fn on_react_to_message(&self, request_id: RequestId, data: ReactToMessageInstruction);
 }
 
 /// A function that finishes processing the PluginInstruction, and sends the
@@ -21,34 +49,152 @@ pub trait PluginInstructionHandler {
 pub fn call_core_handler(unprocessed_instr: &DeserializablePluginInstr,
     interface: Arc<dyn PluginInstructionHandler>) -> Result<()>
 {
+    let request_id = unprocessed_instr.request_id;
     match unprocessed_instr.instruction_type {
         PluginInstructionType::AuthAccount => {
-            match serde_json::from_str::<AuthAccountInstruction>(unprocessed_instr.payload.get()) {
+            match unprocessed_instr.payload.decode::<AuthAccountInstruction>() {
                 Ok(data) => {
                     trace!("Got valid data for AuthAccountInstruction. Calling handler function.");
-                    interface.as_ref().on_auth_account(data);
+                    interface.as_ref().on_auth_account(request_id, data);
                     Ok(())
                 },
                 Err(e) => {
                     error!("Invalid data for instruction type AuthAccountInstruction.");
-                    return Err(e.into());
+                    Err(e)
                 }
             }
 
         },
         PluginInstructionType::Keepalive => {
-            match serde_json::from_str::<KeepaliveInstruction>(unprocessed_instr.payload.get()) {
+            match unprocessed_instr.payload.decode::<KeepaliveInstruction>() {
                 Ok(data) => {
                     trace!("Got valid data for init. Calling handler function.");
-                    interface.as_ref().on_keepalive(data);
+                    interface.as_ref().on_keepalive(request_id, data);
                     Ok(())
                 },
                 Err(e) => {
                     error!("Invalid data for instruction type Keepalive.");
-                    return Err(e.into());
+                    Err(e)
                 }
             }
 
         },
+        PluginInstructionType::InitAck => {
+            match unprocessed_instr.payload.decode::<InitAckInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for InitAck. Calling handler function.");
+                    interface.as_ref().on_init_ack(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type InitAck.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::InitRejected => {
+            match unprocessed_instr.payload.decode::<InitRejectedInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for InitRejected. Calling handler function.");
+                    interface.as_ref().on_init_rejected(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type InitRejected.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::Shutdown => {
+            match unprocessed_instr.payload.decode::<ShutdownInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for Shutdown. Calling handler function.");
+                    interface.as_ref().on_shutdown(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type Shutdown.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::ListConversations => {
+            match unprocessed_instr.payload.decode::<ListConversationsInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for ListConversations. Calling handler function.");
+                    interface.as_ref().on_list_conversations(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type ListConversations.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::SendMessage => {
+            match unprocessed_instr.payload.decode::<SendMessageInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for SendMessage. Calling handler function.");
+                    interface.as_ref().on_send_message(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type SendMessage.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::FetchHistory => {
+            match unprocessed_instr.payload.decode::<FetchHistoryInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for FetchHistory. Calling handler function.");
+                    interface.as_ref().on_fetch_history(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type FetchHistory.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::EditMessage => {
+            match unprocessed_instr.payload.decode::<EditMessageInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for EditMessage. Calling handler function.");
+                    interface.as_ref().on_edit_message(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type EditMessage.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::DeleteMessage => {
+            match unprocessed_instr.payload.decode::<DeleteMessageInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for DeleteMessage. Calling handler function.");
+                    interface.as_ref().on_delete_message(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type DeleteMessage.");
+                    Err(e)
+                }
+            }
+        },
+        PluginInstructionType::ReactToMessage => {
+            match unprocessed_instr.payload.decode::<ReactToMessageInstruction>() {
+                Ok(data) => {
+                    trace!("Got valid data for ReactToMessage. Calling handler function.");
+                    interface.as_ref().on_react_to_message(request_id, data);
+                    Ok(())
+                },
+                Err(e) => {
+                    error!("Invalid data for instruction type ReactToMessage.");
+                    Err(e)
+                }
+            }
+        },
     }
 }
@@ -29,26 +29,43 @@ pub struct Field {
 }
 
 /// The possible values for the AuthResult.
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum AuthResult {
     Success,
     FailRejected,
     FailConnectionError,
-    Connecting
+    Connecting,
+    /// The account was logged in, but the service ended its session.
+    /// Sent without a request ID, since the core didn't ask for it.
+    SessionExpired
+}
+
+/// Identifies an account that a plugin logs in to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct AccountIdentity {
+    /// Only unique among the accounts of the plugin that sent it, such as a username.
+    pub id: String,
+    /// What the GUI shows for the account.
+    pub display_name: String,
+    /// The `protocol_service_name` of the plugin that sent it.
+    pub protocol: String,
 }
 
 // The actual auth instruction and response instruction.
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct AuthAccountInstruction {
-    used_authmethod: AuthMethod,
+    pub used_authmethod: AuthMethod,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct AuthAccountResponse {
-    // TODO: Account ID
-    result: AuthResult,
-    details: String,
+    /// The account that the result is for. Plugins written before accounts were
+    /// identified don't send it, and their account has an empty ID.
+    #[serde(default)]
+    pub account: AccountIdentity,
+    pub result: AuthResult,
+    pub details: String,
 }
 
 
@@ -85,6 +102,11 @@ mod tests {
     #[test]
     fn test_auth_account_response_serialization() {
         let original = AuthAccountResponse {
+            account: AccountIdentity {
+                id: "test".to_string(),
+                display_name: "Test".to_string(),
+                protocol: "test_protocol".to_string(),
+            },
             result: AuthResult::Success,
             details: "test".to_string(),
         };
@@ -97,4 +119,11 @@ mod tests {
 
         assert_eq!(original, deserialized);
     }
+
+    #[test]
+    fn test_auth_account_response_without_account() {
+        let deserialized: AuthAccountResponse = serde_json::from_str(r#"{"result":"Success","details":""}"#).unwrap();
+        assert_eq!(AccountIdentity::default(), deserialized.account);
+        assert_eq!(AuthResult::Success, deserialized.result);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,108 @@
+use serde::{Serialize, Deserialize};
+
+/// What sort of conversation it is, so the GUI can show it accordingly.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConversationKind {
+    /// Between the account and one other person.
+    Direct,
+    /// Between the account and several people, who were added to it.
+    Group,
+    /// One that people join on their own, such as a room on a server.
+    Channel,
+}
+
+/// Someone taking part in a conversation.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Participant {
+    /// Only unique among the participants the plugin sends, such as a username.
+    pub id: String,
+    pub display_name: String,
+}
+
+/// A conversation that an account is part of.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Conversation {
+    /// Only unique among the conversations of the account it belongs to.
+    pub id: String,
+    pub kind: ConversationKind,
+    pub title: String,
+    /// Everyone in the conversation, including the account itself.
+    pub participants: Vec<Participant>,
+    /// How many messages the account hasn't read yet.
+    pub unread_count: u32,
+    /// When something last happened in the conversation, in milliseconds since the Unix epoch.
+    pub last_activity_ms: u64,
+}
+
+/// Asks the plugin for the conversations an account is part of.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ListConversationsInstruction {
+    /// The [AccountIdentity](super::auth::AccountIdentity) ID of the account.
+    pub account_id: String,
+}
+
+/// The plugin's answer to [ListConversationsInstruction].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ConversationListInstruction {
+    pub account_id: String,
+    pub conversations: Vec<Conversation>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+    use log::debug;
+
+    // Serialization + Deserialization tests
+    // For all of the types, these tests serialize and deserialize them to
+    // ensure it behaves as expected
+    // To see the serialized structs as json when you run the tests, run it
+    // as `cargo test -- --nocapture`
+    #[test]
+    fn test_list_conversations_instruction_serialization() {
+        let original = ListConversationsInstruction { account_id: "user".to_string() };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized ListConversationsInstruction = {}", serialized);
+
+        let deserialized: ListConversationsInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_conversation_list_instruction_serialization() {
+        let original = ConversationListInstruction {
+            account_id: "user".to_string(),
+            conversations: vec![
+                Conversation {
+                    id: "1".to_string(),
+                    kind: ConversationKind::Direct,
+                    title: "Friend".to_string(),
+                    participants: vec![
+                        Participant { id: "user".to_string(), display_name: "User".to_string() },
+                        Participant { id: "friend".to_string(), display_name: "Friend".to_string() },
+                    ],
+                    unread_count: 2,
+                    last_activity_ms: 1_700_000_000_000,
+                },
+                Conversation {
+                    id: "2".to_string(),
+                    kind: ConversationKind::Channel,
+                    title: "#general".to_string(),
+                    participants: vec![],
+                    unread_count: 0,
+                    last_activity_ms: 0,
+                },
+            ],
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized ConversationListInstruction = {}", serialized);
+
+        let deserialized: ConversationListInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+}
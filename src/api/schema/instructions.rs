@@ -1,26 +1,171 @@
 use std::fmt::{Display, Debug};
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
 use serde_json::value::RawValue;
 
+use anyhow::Result;
+
+use crate::{
+    api::schema::protocol::Encoding,
+    utils::socket::{decode_value, encode_value}
+};
+
+/// Identifies a single request sent from the core to a plugin.
+/// The core allocates one for every plugin instruction, and the plugin echoes
+/// it back on the core instruction that answers it.
+pub type RequestId = u64;
+
 /// An enum for every instruction that can be sent from the plugin to the core
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum CoreInstructionType {
     Init,
     KeepaliveResponse,
     AuthAccountResponse,
+    Log,
+    ConversationList,
+    MessageReceived,
+    MessageSendResult,
+    HistoryPage,
+    MessageEdited,
+    MessageDeleted,
+    ReactionsChanged,
+    MessageActionFailed,
 }
 
 /// An enum for every instruction that can be sent from the core to the plugin
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum PluginInstructionType {
     Keepalive,
     AuthAccount,
+    InitAck,
+    InitRejected,
+    Shutdown,
+    ListConversations,
+    SendMessage,
+    FetchHistory,
+    EditMessage,
+    DeleteMessage,
+    ReactToMessage,
+}
+
+/// The payload of a received instruction, still in the encoding it was sent with.
+/// It's only decoded once whoever handles the instruction knows what type
+/// its instruction type calls for, with [Payload::decode].
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Json(Box<RawValue>),
+    MessagePack(Vec<u8>),
+    Cbor(Vec<u8>),
+}
+
+impl Payload {
+    /// Encodes a value as a payload.
+    pub fn encode<T: Serialize + Debug>(value: &T, encoding: Encoding) -> Result<Payload> {
+        match encoding {
+            Encoding::Json => Ok(Payload::Json(serde_json::value::to_raw_value(value)?)),
+            Encoding::MessagePack => Ok(Payload::MessagePack(encode_value(value, encoding)?)),
+            Encoding::Cbor => Ok(Payload::Cbor(encode_value(value, encoding)?)),
+        }
+    }
+
+    /**
+     * Decodes the payload into the type its instruction type calls for.
+     * 
+     * # Returns
+     * The decoded payload on success, or an error describing why it isn't a valid T.
+     */
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        match self {
+            Payload::Json(data) => decode_value(data.get().as_bytes(), Encoding::Json),
+            Payload::MessagePack(data) | Payload::Cbor(data) => decode_value(data, self.encoding()),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        match self {
+            Payload::Json(_) => Encoding::Json,
+            Payload::MessagePack(_) => Encoding::MessagePack,
+            Payload::Cbor(_) => Encoding::Cbor,
+        }
+    }
+
+    fn from_encoded(data: Vec<u8>, encoding: Encoding) -> Result<Payload> {
+        match encoding {
+            Encoding::Json => Ok(Payload::Json(RawValue::from_string(String::from_utf8(data)?)?)),
+            Encoding::MessagePack => Ok(Payload::MessagePack(data)),
+            Encoding::Cbor => Ok(Payload::Cbor(data)),
+        }
+    }
+}
+
+impl From<Box<RawValue>> for Payload {
+    fn from(value: Box<RawValue>) -> Self {
+        Payload::Json(value)
+    }
+}
+
+/// Shows the payload as JSON, whatever its encoding.
+impl Display for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Payload::Json(data) => write!(f, "{}", data),
+            _ => match self.decode::<serde_json::Value>() {
+                Ok(value) => write!(f, "{}", value),
+                Err(_) => write!(f, "<invalid {} payload>", self.encoding()),
+            }
+        }
+    }
+}
+
+/// JSON payloads are written out as they are. Others are decoded and
+/// written with the serializer, so a payload can be sent on in any encoding.
+impl Serialize for Payload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Payload::Json(data) if serializer.is_human_readable() => data.serialize(serializer),
+            _ => {
+                let value = self.decode::<serde_json::Value>().map_err(serde::ser::Error::custom)?;
+                value.serialize(serializer)
+            }
+        }
+    }
+}
+
+/// Only JSON payloads can be deserialized as part of an instruction, since
+/// they're the only ones kept as they are. The others are nested as bytes,
+/// and are read with [DeserializableCoreInstr::decode] or [DeserializablePluginInstr::decode].
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Box::<RawValue>::deserialize(deserializer).map(Payload::Json)
+    }
+}
+
+/// How a core instruction is sent with a binary encoding. The payload is
+/// encoded on its own and nested as bytes, so it can be decoded later.
+#[derive(Serialize, Deserialize, Debug)]
+struct BinaryCoreInstr {
+    instruction_type: CoreInstructionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<RequestId>,
+    payload: ByteBuf,
+}
+
+/// How a plugin instruction is sent with a binary encoding.
+#[derive(Serialize, Deserialize, Debug)]
+struct BinaryPluginInstr {
+    instruction_type: PluginInstructionType,
+    request_id: RequestId,
+    payload: ByteBuf,
 }
 
 /// An instruction to be sent from plugin to core.
 #[derive(Serialize, Debug)]
 pub struct SerializableCoreInstr<P: Serialize + Debug> {
     pub instruction_type: CoreInstructionType,
+    /// The ID of the plugin instruction this is responding to.
+    /// None for instructions the plugin sends on its own, such as Init.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<RequestId>,
     // If further optimization is desired, you can use: #[serde(borrow)]
     // But this comes at the cost of needing to ensure the String that is used
     // to create this struct has a lifetime that matches or exceeds this.
@@ -31,6 +176,8 @@ pub struct SerializableCoreInstr<P: Serialize + Debug> {
 #[derive(Serialize, Debug)]
 pub struct SerializablePluginInstr<P: Serialize + Debug> {
     pub instruction_type: PluginInstructionType,
+    /// Allocated by the core. The plugin must echo it back in its response.
+    pub request_id: RequestId,
     // If further optimization is desired, you can use: #[serde(borrow)]
     // But this comes at the cost of needing to ensure the String that is used
     // to create this struct has a lifetime that matches or exceeds this.
@@ -40,21 +187,81 @@ pub struct SerializablePluginInstr<P: Serialize + Debug> {
 #[derive(Deserialize, Debug)]
 pub struct DeserializableCoreInstr {
     pub instruction_type: CoreInstructionType,
-    // If further optimization is desired, you can use: #[serde(borrow)]
-    // But this comes at the cost of needing to ensure the String that is used
-    // to create this struct has a lifetime that matches or exceeds this.
-    pub payload: Box<RawValue>,
+    #[serde(default)]
+    pub request_id: Option<RequestId>,
+    pub payload: Payload,
 }
 
 /// An instruction that was sent from core to plugin
 #[derive(Deserialize, Debug)]
 pub struct DeserializablePluginInstr {
     pub instruction_type: PluginInstructionType,
-    // If further optimization is desired, you can use: #[serde(borrow)]
-    // But this comes at the cost of needing to ensure the String that is used
-    // to create this struct has a lifetime that matches or exceeds this.
-    // Can also use &'a instead of Box
-    pub payload: Box<RawValue>,
+    pub request_id: RequestId,
+    pub payload: Payload,
+}
+
+impl<P: Serialize + Debug> SerializableCoreInstr<P> {
+    /// Encodes the instruction to be sent on a connection that uses the given encoding.
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Json => encode_value(self, encoding),
+            _ => encode_value(&BinaryCoreInstr {
+                instruction_type: self.instruction_type.clone(),
+                request_id: self.request_id,
+                payload: ByteBuf::from(encode_value(&self.payload, encoding)?),
+            }, encoding)
+        }
+    }
+}
+
+impl<P: Serialize + Debug> SerializablePluginInstr<P> {
+    /// Encodes the instruction to be sent on a connection that uses the given encoding.
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Json => encode_value(self, encoding),
+            _ => encode_value(&BinaryPluginInstr {
+                instruction_type: self.instruction_type,
+                request_id: self.request_id,
+                payload: ByteBuf::from(encode_value(&self.payload, encoding)?),
+            }, encoding)
+        }
+    }
+}
+
+impl DeserializableCoreInstr {
+    /// Decodes an instruction received on a connection that uses the given encoding.
+    /// The payload is left encoded.
+    pub fn decode(data: &[u8], encoding: Encoding) -> Result<DeserializableCoreInstr> {
+        match encoding {
+            Encoding::Json => decode_value(data, encoding),
+            _ => {
+                let instr: BinaryCoreInstr = decode_value(data, encoding)?;
+                Ok(DeserializableCoreInstr {
+                    instruction_type: instr.instruction_type,
+                    request_id: instr.request_id,
+                    payload: Payload::from_encoded(instr.payload.into_vec(), encoding)?,
+                })
+            }
+        }
+    }
+}
+
+impl DeserializablePluginInstr {
+    /// Decodes an instruction received on a connection that uses the given encoding.
+    /// The payload is left encoded.
+    pub fn decode(data: &[u8], encoding: Encoding) -> Result<DeserializablePluginInstr> {
+        match encoding {
+            Encoding::Json => decode_value(data, encoding),
+            _ => {
+                let instr: BinaryPluginInstr = decode_value(data, encoding)?;
+                Ok(DeserializablePluginInstr {
+                    instruction_type: instr.instruction_type,
+                    request_id: instr.request_id,
+                    payload: Payload::from_encoded(instr.payload.into_vec(), encoding)?,
+                })
+            }
+        }
+    }
 }
 
 impl Display for CoreInstructionType {
@@ -62,7 +269,16 @@ impl Display for CoreInstructionType {
         match self {
             CoreInstructionType::Init => write!(f, "Init"),
             CoreInstructionType::KeepaliveResponse => write!(f, "KeepaliveResponse"),
-            CoreInstructionType::AuthAccountResponse => write!(f, "AuthAccountResponse")
+            CoreInstructionType::AuthAccountResponse => write!(f, "AuthAccountResponse"),
+            CoreInstructionType::Log => write!(f, "Log"),
+            CoreInstructionType::ConversationList => write!(f, "ConversationList"),
+            CoreInstructionType::MessageReceived => write!(f, "MessageReceived"),
+            CoreInstructionType::MessageSendResult => write!(f, "MessageSendResult"),
+            CoreInstructionType::HistoryPage => write!(f, "HistoryPage"),
+            CoreInstructionType::MessageEdited => write!(f, "MessageEdited"),
+            CoreInstructionType::MessageDeleted => write!(f, "MessageDeleted"),
+            CoreInstructionType::ReactionsChanged => write!(f, "ReactionsChanged"),
+            CoreInstructionType::MessageActionFailed => write!(f, "MessageActionFailed")
         }
     }
 }
@@ -71,7 +287,16 @@ impl Display for PluginInstructionType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             PluginInstructionType::AuthAccount => write!(f, "AuthAccount"),
-            PluginInstructionType::Keepalive => write!(f, "KeepAlive")
+            PluginInstructionType::Keepalive => write!(f, "KeepAlive"),
+            PluginInstructionType::InitAck => write!(f, "InitAck"),
+            PluginInstructionType::InitRejected => write!(f, "InitRejected"),
+            PluginInstructionType::Shutdown => write!(f, "Shutdown"),
+            PluginInstructionType::ListConversations => write!(f, "ListConversations"),
+            PluginInstructionType::SendMessage => write!(f, "SendMessage"),
+            PluginInstructionType::FetchHistory => write!(f, "FetchHistory"),
+            PluginInstructionType::EditMessage => write!(f, "EditMessage"),
+            PluginInstructionType::DeleteMessage => write!(f, "DeleteMessage"),
+            PluginInstructionType::ReactToMessage => write!(f, "ReactToMessage")
         }
     }
 }
@@ -85,8 +310,9 @@ impl<P: Serialize + Debug> PartialEq for SerializableCoreInstr<P> {
         }
         let payloads_equal = serialized_payload_1.unwrap() == serialized_payload_2.unwrap();
         let ins_equal = self.instruction_type == other.instruction_type;
+        let ids_equal = self.request_id == other.request_id;
 
-        ins_equal && payloads_equal
+        ins_equal && ids_equal && payloads_equal
     }
 }
 
@@ -99,26 +325,39 @@ impl<P: Serialize + Debug> PartialEq for SerializablePluginInstr<P> {
         }
         let payloads_equal = serialized_payload_1.unwrap() == serialized_payload_2.unwrap();
         let ins_equal = self.instruction_type == other.instruction_type;
+        let ids_equal = self.request_id == other.request_id;
 
-        ins_equal && payloads_equal
+        ins_equal && ids_equal && payloads_equal
     }
 }
 
-impl From<DeserializableCoreInstr> for SerializableCoreInstr<Box<RawValue>> {
+impl From<DeserializableCoreInstr> for SerializableCoreInstr<Payload> {
     fn from(value: DeserializableCoreInstr) -> Self {
-        SerializableCoreInstr { instruction_type: value.instruction_type, payload: value.payload }
+        SerializableCoreInstr {
+            instruction_type: value.instruction_type,
+            request_id: value.request_id,
+            payload: value.payload
+        }
     }
 }
 
-impl From<DeserializablePluginInstr> for SerializablePluginInstr<Box<RawValue>> {
+impl From<DeserializablePluginInstr> for SerializablePluginInstr<Payload> {
     fn from(value: DeserializablePluginInstr) -> Self {
-        SerializablePluginInstr { instruction_type: value.instruction_type, payload: value.payload }
+        SerializablePluginInstr {
+            instruction_type: value.instruction_type,
+            request_id: value.request_id,
+            payload: value.payload
+        }
     }
 }
 
 impl Clone for DeserializableCoreInstr {
     fn clone(&self) -> Self {
-        DeserializableCoreInstr { instruction_type: self.instruction_type.clone(), payload: self.payload.clone() }
+        DeserializableCoreInstr {
+            instruction_type: self.instruction_type.clone(),
+            request_id: self.request_id,
+            payload: self.payload.clone()
+        }
     }
 }
 
@@ -127,7 +366,90 @@ impl Clone for CoreInstructionType {
         match self {
             CoreInstructionType::Init => CoreInstructionType::Init,
             CoreInstructionType::KeepaliveResponse => CoreInstructionType::KeepaliveResponse,
-            CoreInstructionType::AuthAccountResponse => CoreInstructionType::AuthAccountResponse
+            CoreInstructionType::AuthAccountResponse => CoreInstructionType::AuthAccountResponse,
+            CoreInstructionType::Log => CoreInstructionType::Log,
+            CoreInstructionType::ConversationList => CoreInstructionType::ConversationList,
+            CoreInstructionType::MessageReceived => CoreInstructionType::MessageReceived,
+            CoreInstructionType::MessageSendResult => CoreInstructionType::MessageSendResult,
+            CoreInstructionType::HistoryPage => CoreInstructionType::HistoryPage,
+            CoreInstructionType::MessageEdited => CoreInstructionType::MessageEdited,
+            CoreInstructionType::MessageDeleted => CoreInstructionType::MessageDeleted,
+            CoreInstructionType::ReactionsChanged => CoreInstructionType::ReactionsChanged,
+            CoreInstructionType::MessageActionFailed => CoreInstructionType::MessageActionFailed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use claims::{assert_ok, assert_err};
+
+    use crate::api::schema::log::{LogInstruction, LogLevel};
+
+    fn create_log() -> LogInstruction {
+        LogInstruction {
+            level: LogLevel::Info,
+            target: "test_plugin".to_string(),
+            message: "A message\nwith a newline".to_string(),
+            fields: [("protocol".to_string(), "test".to_string())].into(),
         }
     }
+
+    #[rstest]
+    #[case(Encoding::Json)]
+    #[case(Encoding::MessagePack)]
+    #[case(Encoding::Cbor)]
+    #[test_log::test]
+    fn test_core_instr_round_trip(#[case] encoding: Encoding) {
+        let instr = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::Log,
+            request_id: Some(3),
+            payload: create_log(),
+        };
+        let data = assert_ok!(instr.encode(encoding));
+        let decoded = assert_ok!(DeserializableCoreInstr::decode(&data, encoding));
+        assert_eq!(CoreInstructionType::Log, decoded.instruction_type);
+        assert_eq!(Some(3), decoded.request_id);
+        assert_eq!(encoding, decoded.payload.encoding());
+        assert_eq!(create_log(), assert_ok!(decoded.payload.decode::<LogInstruction>()));
+    }
+
+    #[rstest]
+    #[case(Encoding::Json)]
+    #[case(Encoding::MessagePack)]
+    #[case(Encoding::Cbor)]
+    #[test_log::test]
+    fn test_plugin_instr_round_trip(#[case] encoding: Encoding) {
+        let instr = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::AuthAccount,
+            request_id: 7,
+            payload: create_log(),
+        };
+        let data = assert_ok!(instr.encode(encoding));
+        let decoded = assert_ok!(DeserializablePluginInstr::decode(&data, encoding));
+        assert_eq!(PluginInstructionType::AuthAccount, decoded.instruction_type);
+        assert_eq!(7, decoded.request_id);
+        assert_eq!(create_log(), assert_ok!(decoded.payload.decode::<LogInstruction>()));
+    }
+
+    /// Binary payloads are shown and re-serialized as JSON, so they can be
+    /// logged or passed on to a connection that uses JSON.
+    #[rstest]
+    #[case(Encoding::MessagePack)]
+    #[case(Encoding::Cbor)]
+    #[test_log::test]
+    fn test_binary_payload_as_json(#[case] encoding: Encoding) {
+        let payload = assert_ok!(Payload::encode(&create_log(), encoding));
+        let expected = assert_ok!(serde_json::to_value(create_log()));
+        assert_eq!(expected, assert_ok!(serde_json::from_str::<serde_json::Value>(&payload.to_string())));
+        assert_eq!(expected, assert_ok!(serde_json::to_value(&payload)));
+    }
+
+    #[test_log::test]
+    fn test_payload_decoded_as_wrong_type() {
+        let payload = assert_ok!(Payload::encode(&create_log(), Encoding::MessagePack));
+        assert_err!(payload.decode::<RequestId>());
+    }
 }
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+/// The severity of a log record. Mirrors [log::Level].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A log record sent from the plugin so it can appear in the core's log.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LogInstruction {
+    pub level: LogLevel,
+    /// The target of the record within the plugin, usually its module path.
+    pub target: String,
+    pub message: String,
+    /// Structured key/value pairs attached to the record.
+    pub fields: BTreeMap<String, String>,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+    use log::debug;
+
+    // Serialization + Deserialization tests
+    // For all of the types, these tests serialize and deserialize them to
+    // ensure it behaves as expected
+    // To see the serialized structs as json when you run the tests, run it
+    // as `cargo test -- --nocapture`
+    #[test]
+    fn test_log_instruction_serialization() {
+        let original = LogInstruction {
+            level: LogLevel::Warn,
+            target: "test".to_string(),
+            message: "test".to_string(),
+            fields: BTreeMap::from([("key".to_string(), "value".to_string())]),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized LogInstruction = {}", serialized);
+
+        let deserialized: LogInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+}
@@ -0,0 +1,481 @@
+use serde::{Serialize, Deserialize};
+
+use super::conversation::Participant;
+
+/// How far a message the account sent has gotten.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DeliveryState {
+    /// The plugin has it, but the service doesn't yet.
+    Pending,
+    /// The service accepted it.
+    Sent,
+    /// It reached the other participants.
+    Delivered,
+    /// It won't be sent. The result says why.
+    Failed,
+}
+
+impl DeliveryState {
+    /// Whether the message can't change state again.
+    pub fn is_final(&self) -> bool {
+        matches!(self, DeliveryState::Delivered | DeliveryState::Failed)
+    }
+
+    /// Whether a message in this state can move to the given one.
+    /// Messages only move forward, and can fail until they're delivered.
+    pub fn can_become(&self, next: DeliveryState) -> bool {
+        matches!((self, next),
+            (DeliveryState::Pending, DeliveryState::Sent | DeliveryState::Delivered | DeliveryState::Failed)
+            | (DeliveryState::Sent, DeliveryState::Delivered | DeliveryState::Failed))
+    }
+}
+
+/// A chat message in a conversation.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Message {
+    /// The service's ID for the message, only unique within its conversation.
+    pub id: String,
+    pub conversation_id: String,
+    pub author: Participant,
+    pub body: String,
+    /// When the author sent it, in milliseconds since the Unix epoch.
+    pub sent_at_ms: u64,
+}
+
+/// Asks the plugin to send a message from an account.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SendMessageInstruction {
+    /// The [AccountIdentity](super::auth::AccountIdentity) ID of the account to send it from.
+    pub account_id: String,
+    pub conversation_id: String,
+    /// Chosen by the core, since the service's ID isn't known until it's sent.
+    /// Every [MessageSendResultInstruction] for the message carries it.
+    pub local_id: String,
+    pub body: String,
+}
+
+/// A message that arrived in one of an account's conversations.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageReceivedInstruction {
+    pub account_id: String,
+    pub message: Message,
+}
+
+/// Tells the core how far a message sent with [SendMessageInstruction] has gotten.
+/// The first answers the request. Later ones, such as when the message is
+/// delivered, can be sent whenever the plugin finds out.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageSendResultInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub local_id: String,
+    /// The service's ID for the message, once it's been sent.
+    pub message_id: Option<String>,
+    pub state: DeliveryState,
+    /// When the message reached this state, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// Why the message failed, for [DeliveryState::Failed].
+    pub error: Option<String>,
+}
+
+/// Where a page of history starts, not counting the anchor itself.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum HistoryAnchor {
+    /// The newest message in the conversation. Only makes sense with [HistoryDirection::Before].
+    Latest,
+    /// The message with the given ID.
+    MessageId(String),
+    /// The given time, in milliseconds since the Unix epoch.
+    Timestamp(u64),
+    /// Where the previous page left off, from its [HistoryPageInstruction::next_cursor].
+    Cursor(String),
+}
+
+/// Which way from the anchor to fetch messages.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HistoryDirection {
+    /// Older messages, such as when the GUI scrolls back.
+    Before,
+    /// Newer messages.
+    After,
+}
+
+/// Asks the plugin for a page of a conversation's messages.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FetchHistoryInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub anchor: HistoryAnchor,
+    pub direction: HistoryDirection,
+    /// The most messages to send back. The plugin can send fewer, such as when
+    /// the service's pages are smaller.
+    pub limit: u32,
+}
+
+/// The plugin's answer to [FetchHistoryInstruction].
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct HistoryPageInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    /// Oldest first, whichever direction they were fetched in.
+    pub messages: Vec<Message>,
+    /// Passed back as a [HistoryAnchor::Cursor], in the same direction, to fetch the
+    /// next page. None once there are no more messages in that direction.
+    pub next_cursor: Option<String>,
+}
+
+/// Asks the plugin to change the body of a message the account sent.
+/// Only sent if the plugin supports [MessageCapabilities::edit](super::protocol::MessageCapabilities::edit).
+/// The plugin should answer with a [MessageEditedInstruction] once the service has it,
+/// or a [MessageActionFailedInstruction] if it can't.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct EditMessageInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+    pub body: String,
+}
+
+/// Asks the plugin to delete a message.
+/// Only sent if the plugin supports [MessageCapabilities::delete](super::protocol::MessageCapabilities::delete).
+/// The plugin should answer with a [MessageDeletedInstruction] once the service has it,
+/// or a [MessageActionFailedInstruction] if it can't.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeleteMessageInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+}
+
+/// Whether a reaction is being added or taken back.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReactionAction {
+    Add,
+    Remove,
+}
+
+/// Asks the plugin to add or remove the account's reaction to a message.
+/// Only sent if the plugin supports [MessageCapabilities::react](super::protocol::MessageCapabilities::react).
+/// The plugin should answer with a [ReactionsChangedInstruction] once the service has it,
+/// or a [MessageActionFailedInstruction] if it can't.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReactToMessageInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+    /// An emoji, or the service's name for a custom one.
+    pub reaction: String,
+    pub action: ReactionAction,
+}
+
+/// A message's body changed, whoever changed it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageEditedInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+    pub body: String,
+    /// When it was edited, in milliseconds since the Unix epoch.
+    pub edited_at_ms: u64,
+}
+
+/// A message was deleted, whoever deleted it.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageDeletedInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+}
+
+/// One reaction to a message, and who reacted with it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Reaction {
+    pub reaction: String,
+    /// The [Participant] IDs of everyone who reacted with it.
+    pub participant_ids: Vec<String>,
+}
+
+/// The reactions to a message changed, whoever changed them.
+/// It has all of the message's reactions, not just what changed, so the
+/// core doesn't fall out of step if it misses one.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ReactionsChangedInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+    pub reactions: Vec<Reaction>,
+}
+
+/// Answers an [EditMessageInstruction], [DeleteMessageInstruction] or
+/// [ReactToMessageInstruction] that the plugin couldn't carry out, such as
+/// when the service refused it. Sent with the request's ID.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MessageActionFailedInstruction {
+    pub account_id: String,
+    pub conversation_id: String,
+    pub message_id: String,
+    /// Why it failed, for the GUI to show.
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+    use log::debug;
+
+    // Serialization + Deserialization tests
+    // For all of the types, these tests serialize and deserialize them to
+    // ensure it behaves as expected
+    // To see the serialized structs as json when you run the tests, run it
+    // as `cargo test -- --nocapture`
+    #[test]
+    fn test_send_message_instruction_serialization() {
+        let original = SendMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            local_id: "local-1".to_string(),
+            body: "Hello\nthere".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized SendMessageInstruction = {}", serialized);
+
+        let deserialized: SendMessageInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_message_received_instruction_serialization() {
+        let original = MessageReceivedInstruction {
+            account_id: "user".to_string(),
+            message: Message {
+                id: "42".to_string(),
+                conversation_id: "1".to_string(),
+                author: Participant { id: "friend".to_string(), display_name: "Friend".to_string() },
+                body: "Hi!".to_string(),
+                sent_at_ms: 1_700_000_000_000,
+            },
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized MessageReceivedInstruction = {}", serialized);
+
+        let deserialized: MessageReceivedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_message_send_result_instruction_serialization() {
+        for (state, message_id, error) in [
+            (DeliveryState::Pending, None, None),
+            (DeliveryState::Sent, Some("42".to_string()), None),
+            (DeliveryState::Delivered, Some("42".to_string()), None),
+            (DeliveryState::Failed, None, Some("Rate limited".to_string())),
+        ] {
+            let original = MessageSendResultInstruction {
+                account_id: "user".to_string(),
+                conversation_id: "1".to_string(),
+                local_id: "local-1".to_string(),
+                message_id,
+                state,
+                timestamp_ms: 1_700_000_000_000,
+                error,
+            };
+            let serialized = serde_json::to_string(&original).unwrap();
+
+            debug!("serialized MessageSendResultInstruction = {}", serialized);
+
+            let deserialized: MessageSendResultInstruction = serde_json::from_str(&serialized).unwrap();
+
+            assert_eq!(original, deserialized);
+        }
+    }
+
+    #[test]
+    fn test_fetch_history_instruction_serialization() {
+        for anchor in [
+            HistoryAnchor::Latest,
+            HistoryAnchor::MessageId("42".to_string()),
+            HistoryAnchor::Timestamp(1_700_000_000_000),
+            HistoryAnchor::Cursor("page-2".to_string()),
+        ] {
+            let original = FetchHistoryInstruction {
+                account_id: "user".to_string(),
+                conversation_id: "1".to_string(),
+                anchor,
+                direction: HistoryDirection::Before,
+                limit: 50,
+            };
+            let serialized = serde_json::to_string(&original).unwrap();
+
+            debug!("serialized FetchHistoryInstruction = {}", serialized);
+
+            let deserialized: FetchHistoryInstruction = serde_json::from_str(&serialized).unwrap();
+
+            assert_eq!(original, deserialized);
+        }
+    }
+
+    #[test]
+    fn test_history_page_instruction_serialization() {
+        let original = HistoryPageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            messages: (0..3).map(|i| Message {
+                id: i.to_string(),
+                conversation_id: "1".to_string(),
+                author: Participant { id: "friend".to_string(), display_name: "Friend".to_string() },
+                body: format!("Message {}", i),
+                sent_at_ms: 1_700_000_000_000 + i,
+            }).collect(),
+            next_cursor: Some("page-2".to_string()),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized HistoryPageInstruction = {}", serialized);
+
+        let deserialized: HistoryPageInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_edit_message_instruction_serialization() {
+        let original = EditMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+            body: "Fixed a typo".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized EditMessageInstruction = {}", serialized);
+
+        let deserialized: EditMessageInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_delete_message_instruction_serialization() {
+        let original = DeleteMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized DeleteMessageInstruction = {}", serialized);
+
+        let deserialized: DeleteMessageInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_react_to_message_instruction_serialization() {
+        for action in [ReactionAction::Add, ReactionAction::Remove] {
+            let original = ReactToMessageInstruction {
+                account_id: "user".to_string(),
+                conversation_id: "1".to_string(),
+                message_id: "42".to_string(),
+                reaction: "👍".to_string(),
+                action,
+            };
+            let serialized = serde_json::to_string(&original).unwrap();
+
+            debug!("serialized ReactToMessageInstruction = {}", serialized);
+
+            let deserialized: ReactToMessageInstruction = serde_json::from_str(&serialized).unwrap();
+
+            assert_eq!(original, deserialized);
+        }
+    }
+
+    #[test]
+    fn test_message_edited_instruction_serialization() {
+        let original = MessageEditedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+            body: "Fixed a typo".to_string(),
+            edited_at_ms: 1_700_000_000_000,
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized MessageEditedInstruction = {}", serialized);
+
+        let deserialized: MessageEditedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_message_deleted_instruction_serialization() {
+        let original = MessageDeletedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized MessageDeletedInstruction = {}", serialized);
+
+        let deserialized: MessageDeletedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_reactions_changed_instruction_serialization() {
+        let original = ReactionsChangedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+            reactions: vec![
+                Reaction { reaction: "👍".to_string(), participant_ids: vec!["user".to_string(), "friend".to_string()] },
+                Reaction { reaction: "party_parrot".to_string(), participant_ids: vec!["friend".to_string()] },
+            ],
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized ReactionsChangedInstruction = {}", serialized);
+
+        let deserialized: ReactionsChangedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_message_action_failed_instruction_serialization() {
+        let original = MessageActionFailedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "1".to_string(),
+            message_id: "42".to_string(),
+            error: "Messages can only be edited for 15 minutes".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized MessageActionFailedInstruction = {}", serialized);
+
+        let deserialized: MessageActionFailedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_delivery_state_only_moves_forward() {
+        assert!(DeliveryState::Pending.can_become(DeliveryState::Sent));
+        assert!(DeliveryState::Sent.can_become(DeliveryState::Delivered));
+        assert!(DeliveryState::Sent.can_become(DeliveryState::Failed));
+        assert!(!DeliveryState::Sent.can_become(DeliveryState::Pending));
+        assert!(!DeliveryState::Sent.can_become(DeliveryState::Sent));
+        assert!(!DeliveryState::Delivered.can_become(DeliveryState::Failed));
+        assert!(!DeliveryState::Failed.can_become(DeliveryState::Sent));
+        assert!(DeliveryState::Failed.is_final());
+        assert!(!DeliveryState::Pending.is_final());
+    }
+}
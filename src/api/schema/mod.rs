@@ -1,4 +1,8 @@
 pub mod auth;
+pub mod conversation;
 pub mod instructions;
 pub mod keepalive;
-pub mod protocol;
\ No newline at end of file
+pub mod log;
+pub mod message;
+pub mod protocol;
+pub mod shutdown;
\ No newline at end of file
@@ -1,19 +1,175 @@
+use std::fmt::{Debug, Display};
+
 use serde::{Serialize, Deserialize};
-use crate::api::schema::auth::*;
+use crate::api::schema::{auth::*, instructions::PluginInstructionType};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// The version of the API that this core implements.
+pub const CORE_API_VERSION: Version = Version { major: 0, minor: 1, patch: 0 };
+
+/// Versions are ordered by major, then minor, then patch.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct Version {
     pub major: i32,
     pub minor: i32,
     pub patch: i32,
 }
 
+impl Version {
+    /**
+     * Whether a plugin written for this API version can be used by a core
+     * that implements the given API version.
+     * The major versions must match, and the plugin can't need anything newer
+     * than the core's minor version.
+     */
+    pub fn is_compatible_with(&self, core_api_version: &Version) -> bool {
+        self.major == core_api_version.major && self.minor <= core_api_version.minor
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// How instructions are separated from each other on a connection.
+/// Connections start out newline delimited, and the plugin and core agree on
+/// what to use afterwards during the Init handshake.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Framing {
+    /// Each instruction is followed by a newline, so it can't contain raw newlines.
+    #[default]
+    Newline,
+    /// Each instruction is preceded by its length in bytes, as a big endian u32.
+    /// Frames longer than the agreed maximum frame size are refused.
+    LengthPrefixed,
+}
+
+impl Framing {
+    /**
+     * Picks the framing to use with a plugin.
+     * The core supports all of them, so it's the plugin's most preferred one.
+     * 
+     * # Arguments
+     * ## offered
+     * The framings the plugin supports, from [InitDataInstruction::framing].
+     */
+    pub fn negotiate(offered: &[Framing]) -> Framing {
+        offered.first().copied().unwrap_or_default()
+    }
+}
+
+/// How instructions are encoded on a connection.
+/// Connections start out with JSON, and the plugin and core agree on what to
+/// use afterwards during the Init handshake, along with the [Framing].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /**
+     * Picks the encoding to use with a plugin.
+     * Only JSON can be newline delimited, so binary encodings need [Framing::LengthPrefixed].
+     * Otherwise, it's the plugin's most preferred one, since the core supports all of them.
+     * 
+     * # Arguments
+     * ## offered
+     * The encodings the plugin supports, from [InitDataInstruction::encodings].
+     * ## framing
+     * The framing that was picked for the connection.
+     */
+    pub fn negotiate(offered: &[Encoding], framing: Framing) -> Encoding {
+        match framing {
+            Framing::Newline => Encoding::Json,
+            Framing::LengthPrefixed => offered.first().copied().unwrap_or_default(),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Json => write!(f, "JSON"),
+            Encoding::MessagePack => write!(f, "MessagePack"),
+            Encoding::Cbor => write!(f, "CBOR"),
+        }
+    }
+}
+
+/// The largest frame that's sent or received with [Framing::LengthPrefixed],
+/// unless the core says otherwise in its [InitAckInstruction].
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * 1024 * 1024;
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+/// The environment variable that the core passes a plugin its auth token in.
+pub const AUTH_TOKEN_ENV: &str = "POLYCHAT_AUTH_TOKEN";
+
+/// What the plugin's first argument starts with when it inherits its end of
+/// a socket pair, instead of being passed a socket name. It's followed by the
+/// file descriptor, as in `fd:3`.
+pub const INHERITED_FD_PREFIX: &str = "fd:";
+
+/// The first frame a plugin sends after connecting, before its Init.
+/// It's newline delimited JSON, like the Init. Connections that don't start
+/// with the auth token the plugin was started with are closed, so that no other
+/// local process can connect in the plugin's place.
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct AuthFrame {
+    /// From the [AUTH_TOKEN_ENV] environment variable.
+    pub token: String,
+}
+
+/// Kept out of logs.
+impl Debug for AuthFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AuthFrame {{ token: .. }}")
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct ProtocolData {
     /// The well known name of the service that this plugin is designed for.
     pub protocol_service_name: String,
     /// All of the supported ways to authenticate an account
     pub auth_methods: Vec<AuthMethod>,
+    /// What can be done to messages that were already sent.
+    /// Plugins that don't send these support none of it.
+    #[serde(default)]
+    pub capabilities: MessageCapabilities,
+}
+
+/// Which changes to sent messages a plugin supports. The core doesn't send
+/// a plugin instructions for the ones it doesn't. Any that are left out aren't supported.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(default)]
+pub struct MessageCapabilities {
+    /// Whether it handles [PluginInstructionType::EditMessage], and sends MessageEdited.
+    /// Like the others, it answers with MessageActionFailed if it can't do it.
+    pub edit: bool,
+    /// Whether it handles [PluginInstructionType::DeleteMessage], and sends MessageDeleted.
+    pub delete: bool,
+    /// Whether it handles [PluginInstructionType::ReactToMessage], and sends ReactionsChanged.
+    pub react: bool,
+}
+
+impl MessageCapabilities {
+    /// Whether the plugin can be sent the given instruction type.
+    /// Only the ones these capabilities are about can be unsupported.
+    pub fn supports(&self, instruction_type: PluginInstructionType) -> bool {
+        match instruction_type {
+            PluginInstructionType::EditMessage => self.edit,
+            PluginInstructionType::DeleteMessage => self.delete,
+            PluginInstructionType::ReactToMessage => self.react,
+            _ => true
+        }
+    }
 }
 
 /// Data sent from the plugin to the core once it's initialized
@@ -29,6 +185,42 @@ pub struct InitDataInstruction {
     pub plugin_version: Version,
     /// All of the important info about the protocol
     pub protocol_data: ProtocolData,
+    /// The framings the plugin supports, most preferred first.
+    /// Plugins that don't send any only support [Framing::Newline].
+    /// The plugin shouldn't send anything else until it receives the core's
+    /// answer, since that's when it switches.
+    #[serde(default)]
+    pub framing: Vec<Framing>,
+    /// The encodings the plugin supports, most preferred first.
+    /// Plugins that don't send any only support [Encoding::Json].
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+}
+
+/// Sent from the core to the plugin when it accepts the plugin's Init.
+/// This is sent as newline delimited JSON, and everything after it in either
+/// direction uses the framing and encoding it names.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct InitAckInstruction {
+    pub core_api_version: Version,
+    /// Picked from the plugin's [InitDataInstruction::framing].
+    #[serde(default)]
+    pub framing: Framing,
+    /// The largest frame, in bytes, that either side can send with [Framing::LengthPrefixed].
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: u32,
+    /// Picked from the plugin's [InitDataInstruction::encodings].
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// Sent from the core to the plugin when it can't use the plugin.
+/// The core will not send it any other instructions, so the plugin should exit.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct InitRejectedInstruction {
+    pub core_api_version: Version,
+    /// Why the plugin was rejected, to show to its developer.
+    pub reason: String,
 }
 
 #[cfg(test)]
@@ -62,9 +254,11 @@ mod tests {
                             }
                         ]
                     }
-                ]
-            }
-
+                ],
+                capabilities: MessageCapabilities { edit: true, delete: false, react: true },
+            },
+            framing: vec![Framing::LengthPrefixed, Framing::Newline],
+            encodings: vec![Encoding::MessagePack, Encoding::Json],
         };
         let serialized = serde_json::to_string(&original).unwrap();
 
@@ -75,4 +269,79 @@ mod tests {
 
         assert_eq!(original, deserialized);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_init_rejected_instruction_serialization() {
+        let original = InitRejectedInstruction {
+            core_api_version: Version { major: 1, minor: 2, patch: 3 },
+            reason: "test".to_string(),
+        };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized InitRejectedInstruction = {}", serialized);
+
+        let deserialized: InitRejectedInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+
+    #[test]
+    fn test_init_ack_defaults_to_newline_framing() {
+        // What a core from before framing could be negotiated sends.
+        let serialized = r#"{"core_api_version":{"major":0,"minor":1,"patch":0}}"#;
+        let deserialized: InitAckInstruction = serde_json::from_str(serialized).unwrap();
+
+        assert_eq!(Framing::Newline, deserialized.framing);
+        assert_eq!(DEFAULT_MAX_FRAME_SIZE, deserialized.max_frame_size);
+        assert_eq!(Encoding::Json, deserialized.encoding);
+    }
+
+    #[test]
+    fn test_framing_negotiation() {
+        assert_eq!(Framing::Newline, Framing::negotiate(&[]));
+        assert_eq!(Framing::Newline, Framing::negotiate(&[Framing::Newline, Framing::LengthPrefixed]));
+        assert_eq!(Framing::LengthPrefixed, Framing::negotiate(&[Framing::LengthPrefixed, Framing::Newline]));
+    }
+
+    #[test]
+    fn test_encoding_negotiation() {
+        assert_eq!(Encoding::Json, Encoding::negotiate(&[], Framing::LengthPrefixed));
+        assert_eq!(Encoding::Cbor, Encoding::negotiate(&[Encoding::Cbor, Encoding::Json], Framing::LengthPrefixed));
+        // Binary encodings can contain newlines.
+        assert_eq!(Encoding::Json, Encoding::negotiate(&[Encoding::MessagePack, Encoding::Json], Framing::Newline));
+    }
+
+    #[test]
+    fn test_api_version_compatibility() {
+        let core = Version { major: 1, minor: 2, patch: 3 };
+        assert!(Version { major: 1, minor: 2, patch: 9 }.is_compatible_with(&core));
+        assert!(Version { major: 1, minor: 0, patch: 0 }.is_compatible_with(&core));
+        assert!(!Version { major: 1, minor: 3, patch: 0 }.is_compatible_with(&core));
+        assert!(!Version { major: 0, minor: 2, patch: 3 }.is_compatible_with(&core));
+        assert!(!Version { major: 2, minor: 0, patch: 0 }.is_compatible_with(&core));
+    }
+
+    #[test]
+    fn test_protocol_data_defaults() {
+        let json = r#"{"protocol_service_name":"test","auth_methods":[]}"#;
+        let deserialized: ProtocolData = serde_json::from_str(json).unwrap();
+        assert_eq!(MessageCapabilities { edit: false, delete: false, react: false }, deserialized.capabilities);
+    }
+
+    #[test]
+    fn test_partial_message_capabilities() {
+        let json = r#"{"protocol_service_name":"test","auth_methods":[],"capabilities":{"edit":true}}"#;
+        let deserialized: ProtocolData = serde_json::from_str(json).unwrap();
+        assert_eq!(MessageCapabilities { edit: true, delete: false, react: false }, deserialized.capabilities);
+    }
+
+    #[test]
+    fn test_message_capabilities_support() {
+        let capabilities = MessageCapabilities { edit: true, delete: false, react: false };
+        assert!(capabilities.supports(PluginInstructionType::EditMessage));
+        assert!(!capabilities.supports(PluginInstructionType::DeleteMessage));
+        assert!(!capabilities.supports(PluginInstructionType::ReactToMessage));
+        // Instructions that aren't about capabilities are always supported.
+        assert!(MessageCapabilities::default().supports(PluginInstructionType::SendMessage));
+    }
+}
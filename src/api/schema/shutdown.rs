@@ -0,0 +1,49 @@
+use serde::{Serialize, Deserialize};
+
+/// Why the core is shutting a plugin down.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ShutdownReason {
+    /// The core is done with the plugin, such as when the core is exiting.
+    Requested,
+    /// The plugin will be started again right after it exits.
+    Restarting,
+    /// A newer version of the plugin is running.
+    Superseded,
+    /// The plugin did not finish loading.
+    FailedToLoad,
+    /// The plugin sent messages that were too large or malformed, so it was disconnected.
+    ProtocolViolation,
+}
+
+/// Asks the plugin to clean up, such as logging out of services and
+/// flushing state, then exit.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ShutdownInstruction {
+    pub reason: ShutdownReason,
+    /// How long the plugin has to exit before it is terminated, in milliseconds.
+    pub deadline_ms: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+    use log::debug;
+
+    // Serialization + Deserialization tests
+    // For all of the types, these tests serialize and deserialize them to
+    // ensure it behaves as expected
+    // To see the serialized structs as json when you run the tests, run it
+    // as `cargo test -- --nocapture`
+    #[test]
+    fn test_shutdown_instruction_serialization() {
+        let original = ShutdownInstruction { reason: ShutdownReason::Superseded, deadline_ms: 5000 };
+        let serialized = serde_json::to_string(&original).unwrap();
+
+        debug!("serialized shutdown = {}", serialized);
+
+        let deserialized: ShutdownInstruction = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(original, deserialized);
+    }
+}
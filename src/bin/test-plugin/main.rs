@@ -1,11 +1,162 @@
-use tokio;
 extern crate polychat_ipc;
-use polychat_ipc::polychat_plugin_sdk_rust::entrypoint;
-//use log::info;
+use std::{env, fmt::Debug, time::{SystemTime, UNIX_EPOCH}};
+
+use polychat_ipc::{
+    polychat_plugin_sdk_rust::{entrypoint, socket::CoreInstructionSender},
+    api::{
+        plugin_instruction_handler::PluginInstructionHandler,
+        schema::{
+            auth::AuthAccountInstruction,
+            conversation::ListConversationsInstruction,
+            instructions::{CoreInstructionType, SerializableCoreInstr, RequestId},
+            keepalive::KeepaliveInstruction,
+            message::{
+                SendMessageInstruction, MessageSendResultInstruction, DeliveryState, FetchHistoryInstruction,
+                EditMessageInstruction, MessageEditedInstruction, DeleteMessageInstruction, MessageDeletedInstruction,
+                ReactToMessageInstruction, MessageActionFailedInstruction
+            },
+            protocol::{
+                InitDataInstruction, InitAckInstruction, InitRejectedInstruction, ProtocolData, MessageCapabilities,
+                Version, Framing, Encoding
+            },
+            shutdown::ShutdownInstruction
+        }
+    }
+};
+use log::{debug, error};
+use serde::Serialize;
+
+/// Set by tests to make the plugin report a different plugin version, formatted as major.minor.patch.
+const VERSION_VAR: &str = "TEST_PLUGIN_VERSION";
+/// Set by tests to make the plugin report a different API version, formatted as major.minor.patch.
+const API_VERSION_VAR: &str = "TEST_PLUGIN_API_VERSION";
+/// Set by tests to make the plugin declare message capabilities, formatted as a comma separated
+/// list of `edit`, `delete` and `react`.
+const CAPABILITIES_VAR: &str = "TEST_PLUGIN_CAPABILITIES";
 
 #[tokio::main]
 async fn main() {
     println!("Test Example plugin starting.");
-    entrypoint::run_plugin().await;
+    let example = Version { major: 0, minor: 1, patch: 0 };
+    entrypoint::run_plugin_with(InitDataInstruction {
+        api_version: env::var(API_VERSION_VAR).map(|v| parse_version(&v)).unwrap_or(example),
+        plugin_version: env::var(VERSION_VAR).map(|v| parse_version(&v)).unwrap_or(example),
+        protocol_data: ProtocolData {
+            protocol_service_name: "example_protocol".to_string(),
+            auth_methods: vec![],
+            capabilities: env::var(CAPABILITIES_VAR).map(|c| parse_capabilities(&c)).unwrap_or_default(),
+        },
+        framing: vec![Framing::LengthPrefixed, Framing::Newline],
+        encodings: vec![Encoding::MessagePack, Encoding::Cbor, Encoding::Json],
+    }, TestPlugin::new).await;
     println!("Test Example plugin finished running.");
-}
\ No newline at end of file
+}
+
+/// Answers the core as if there were a service behind the plugin that did
+/// everything it was asked to right away.
+struct TestPlugin {
+    sender: CoreInstructionSender,
+}
+
+impl TestPlugin {
+    fn new(sender: CoreInstructionSender) -> Self {
+        TestPlugin { sender }
+    }
+
+    /// Answers the core's request with the given ID. The handler can't wait
+    /// for it to be sent, so it's sent from a task of its own.
+    fn answer<P: Serialize + Debug + Send + Sync + 'static>(&self, instruction_type: CoreInstructionType,
+        request_id: RequestId, payload: P)
+    {
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let response = SerializableCoreInstr { instruction_type, request_id: Some(request_id), payload };
+            if let Err(e) = sender.send_core_instruction(&response).await {
+                error!("Error while trying to send {}: {}", response.instruction_type, e);
+            }
+        });
+    }
+}
+
+impl PluginInstructionHandler for TestPlugin {
+    fn on_keepalive(&self, _request_id: RequestId, _data: KeepaliveInstruction) {}
+
+    fn on_auth_account(&self, _request_id: RequestId, _data: AuthAccountInstruction) {
+        debug!("Ignoring AuthAccount");
+    }
+
+    fn on_init_ack(&self, _request_id: RequestId, _data: InitAckInstruction) {}
+
+    fn on_init_rejected(&self, _request_id: RequestId, _data: InitRejectedInstruction) {}
+
+    fn on_shutdown(&self, _request_id: RequestId, _data: ShutdownInstruction) {}
+
+    fn on_list_conversations(&self, _request_id: RequestId, _data: ListConversationsInstruction) {
+        debug!("Ignoring ListConversations");
+    }
+
+    fn on_send_message(&self, request_id: RequestId, data: SendMessageInstruction) {
+        // Every message is sent right away.
+        self.answer(CoreInstructionType::MessageSendResult, request_id, MessageSendResultInstruction {
+            account_id: data.account_id,
+            conversation_id: data.conversation_id,
+            message_id: Some(format!("example-{}", data.local_id)),
+            local_id: data.local_id,
+            state: DeliveryState::Sent,
+            timestamp_ms: now_ms(),
+            error: None,
+        });
+    }
+
+    fn on_fetch_history(&self, _request_id: RequestId, _data: FetchHistoryInstruction) {
+        debug!("Ignoring FetchHistory");
+    }
+
+    // These are only sent if the plugin's Init declares the matching capability.
+    fn on_edit_message(&self, request_id: RequestId, data: EditMessageInstruction) {
+        self.answer(CoreInstructionType::MessageEdited, request_id, MessageEditedInstruction {
+            account_id: data.account_id,
+            conversation_id: data.conversation_id,
+            message_id: data.message_id,
+            body: data.body,
+            edited_at_ms: now_ms(),
+        });
+    }
+
+    fn on_delete_message(&self, request_id: RequestId, data: DeleteMessageInstruction) {
+        self.answer(CoreInstructionType::MessageDeleted, request_id, MessageDeletedInstruction {
+            account_id: data.account_id,
+            conversation_id: data.conversation_id,
+            message_id: data.message_id,
+        });
+    }
+
+    fn on_react_to_message(&self, request_id: RequestId, data: ReactToMessageInstruction) {
+        // It doesn't know who the account is in the conversation, so it can't list its reactions.
+        self.answer(CoreInstructionType::MessageActionFailed, request_id, MessageActionFailedInstruction {
+            account_id: data.account_id,
+            conversation_id: data.conversation_id,
+            message_id: data.message_id,
+            error: "The test plugin doesn't keep track of reactions".to_string(),
+        });
+    }
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+fn parse_version(version: &str) -> Version {
+    let parts: Vec<i32> = version.split('.').map(|part| part.parse().expect("Invalid version")).collect();
+    Version { major: parts[0], minor: parts[1], patch: parts[2] }
+}
+
+fn parse_capabilities(capabilities: &str) -> MessageCapabilities {
+    let declared: Vec<&str> = capabilities.split(',').map(str::trim).collect();
+    MessageCapabilities {
+        edit: declared.contains(&"edit"),
+        delete: declared.contains(&"delete"),
+        react: declared.contains(&"react"),
+    }
+}
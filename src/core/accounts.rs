@@ -0,0 +1,193 @@
+use std::{collections::HashMap, fmt::Display, path::{Path, PathBuf}};
+
+use log::{debug, info};
+
+use crate::api::schema::auth::{AccountIdentity, AuthAccountResponse, AuthResult};
+
+/// Where an account is in logging in, as far as the core knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountState {
+    /// The plugin is logging in to it.
+    Connecting,
+    /// Logged in, and usable.
+    Authenticated,
+    /// Was logged in, but has to log in again before it can be used.
+    SessionExpired,
+}
+
+impl Display for AccountState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountState::Connecting => write!(f, "Connecting"),
+            AccountState::Authenticated => write!(f, "Authenticated"),
+            AccountState::SessionExpired => write!(f, "SessionExpired"),
+        }
+    }
+}
+
+/// An account that a plugin is logging in to, or has logged in to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub identity: AccountIdentity,
+    pub state: AccountState,
+    /// From the most recent [AuthAccountResponse] for the account.
+    pub details: String,
+}
+
+/// Keeps track of the accounts each plugin is logging in to or has logged in
+/// to, from the [AuthAccountResponse]s they send. Plugins are identified by
+/// the path they were loaded from, as in the
+/// [ProcessManager](crate::process_management::process_manager::ProcessManager).
+#[derive(Debug, Default)]
+pub struct AccountRegistry {
+    /// In the order they were first seen.
+    accounts: HashMap<PathBuf, Vec<Account>>,
+}
+
+impl AccountRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Updates the account a plugin sent an [AuthAccountResponse] for.
+     *
+     * An account that was logged in and then fails to log in again is kept as
+     * [AccountState::SessionExpired]. One that fails without ever having
+     * logged in is forgotten.
+     *
+     * # Arguments
+     * ## plugin
+     * The path of the plugin that sent the response.
+     *
+     * # Returns
+     * The account's new state, or None if it isn't tracked anymore.
+     */
+    pub fn handle_auth_response(&mut self, plugin: &Path, response: AuthAccountResponse) -> Option<AccountState> {
+        let accounts = self.accounts.entry(plugin.to_path_buf()).or_default();
+        let index = accounts.iter().position(|a| a.identity.id == response.account.id);
+        let previous = index.map(|i| accounts[i].state);
+        let state = match (response.result, previous) {
+            (AuthResult::Connecting, _) => AccountState::Connecting,
+            (AuthResult::Success, _) => AccountState::Authenticated,
+            (AuthResult::SessionExpired, _) => AccountState::SessionExpired,
+            (AuthResult::FailRejected | AuthResult::FailConnectionError,
+                Some(AccountState::Authenticated | AccountState::SessionExpired)) => AccountState::SessionExpired,
+            (AuthResult::FailRejected | AuthResult::FailConnectionError, _) => {
+                if let Some(i) = index {
+                    accounts.remove(i);
+                }
+                debug!("Account {} of {} failed to log in: {}", response.account.id, plugin.display(), response.details);
+                return None;
+            }
+        };
+        if previous != Some(state) {
+            info!("Account {} of {} is now {}", response.account.id, plugin.display(), state);
+        }
+        let account = Account { identity: response.account, state, details: response.details };
+        match index {
+            Some(i) => accounts[i] = account,
+            None => accounts.push(account)
+        }
+        Some(state)
+    }
+
+    /// Returns the accounts of the plugin at the given path, in the order they were first seen.
+    pub fn get_accounts(&self, plugin: &Path) -> &[Account] {
+        self.accounts.get(plugin).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Returns the account with the given ID that the plugin at the given path sent.
+    pub fn get_account(&self, plugin: &Path, id: &str) -> Option<&Account> {
+        self.get_accounts(plugin).iter().find(|a| a.identity.id == id)
+    }
+
+    /// Returns every plugin's accounts that are in the given state, along with the plugin's path.
+    pub fn get_accounts_in_state(&self, state: AccountState) -> Vec<(&Path, &Account)> {
+        self.accounts.iter()
+            .flat_map(|(plugin, accounts)| accounts.iter().map(move |a| (plugin.as_path(), a)))
+            .filter(|(_, a)| a.state == state)
+            .collect()
+    }
+
+    /**
+     * Forgets the accounts of the plugin at the given path, such as when it's unloaded.
+     *
+     * # Returns
+     * The accounts that were forgotten.
+     */
+    pub fn remove_plugin(&mut self, plugin: &Path) -> Vec<Account> {
+        self.accounts.remove(plugin).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_none, assert_some};
+    use test_log::test;
+
+    #[test]
+    fn test_account_logs_in() {
+        let mut registry = AccountRegistry::new();
+        let plugin = Path::new("plugin");
+        assert_eq!(Some(AccountState::Connecting), registry.handle_auth_response(plugin, create_response("a", AuthResult::Connecting)));
+        assert_eq!(Some(AccountState::Authenticated), registry.handle_auth_response(plugin, create_response("a", AuthResult::Success)));
+
+        let account = assert_some!(registry.get_account(plugin, "a"));
+        assert_eq!(AccountState::Authenticated, account.state);
+        assert_eq!("Account a", account.identity.display_name);
+        assert_eq!(1, registry.get_accounts(plugin).len());
+        assert!(registry.get_accounts(Path::new("other")).is_empty());
+    }
+
+    #[test]
+    fn test_failed_login_is_forgotten() {
+        let mut registry = AccountRegistry::new();
+        let plugin = Path::new("plugin");
+        registry.handle_auth_response(plugin, create_response("a", AuthResult::Connecting));
+        assert_none!(registry.handle_auth_response(plugin, create_response("a", AuthResult::FailRejected)));
+        assert_none!(registry.get_account(plugin, "a"));
+    }
+
+    #[test]
+    fn test_session_expires() {
+        let mut registry = AccountRegistry::new();
+        let plugin = Path::new("plugin");
+        registry.handle_auth_response(plugin, create_response("a", AuthResult::Success));
+        registry.handle_auth_response(plugin, create_response("b", AuthResult::Success));
+        assert_eq!(Some(AccountState::SessionExpired), registry.handle_auth_response(plugin, create_response("a", AuthResult::FailConnectionError)));
+        assert_eq!(Some(AccountState::SessionExpired), registry.handle_auth_response(plugin, create_response("b", AuthResult::SessionExpired)));
+
+        let expired = registry.get_accounts_in_state(AccountState::SessionExpired);
+        assert_eq!(2, expired.len());
+        assert!(registry.get_accounts_in_state(AccountState::Authenticated).is_empty());
+        // Logging in again makes it usable.
+        assert_eq!(Some(AccountState::Authenticated), registry.handle_auth_response(plugin, create_response("a", AuthResult::Success)));
+    }
+
+    #[test]
+    fn test_accounts_are_per_plugin() {
+        let mut registry = AccountRegistry::new();
+        registry.handle_auth_response(Path::new("one"), create_response("a", AuthResult::Success));
+        registry.handle_auth_response(Path::new("two"), create_response("a", AuthResult::Connecting));
+
+        assert_eq!(AccountState::Authenticated, assert_some!(registry.get_account(Path::new("one"), "a")).state);
+        assert_eq!(AccountState::Connecting, assert_some!(registry.get_account(Path::new("two"), "a")).state);
+        assert_eq!(1, registry.remove_plugin(Path::new("one")).len());
+        assert_none!(registry.get_account(Path::new("one"), "a"));
+        assert_some!(registry.get_account(Path::new("two"), "a"));
+    }
+
+    fn create_response(id: &str, result: AuthResult) -> AuthAccountResponse {
+        AuthAccountResponse {
+            account: AccountIdentity {
+                id: id.to_string(),
+                display_name: format!("Account {}", id),
+                protocol: "test_protocol".to_string(),
+            },
+            result,
+            details: String::new(),
+        }
+    }
+}
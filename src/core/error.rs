@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::api::schema::instructions::PluginInstructionType;
+
+#[derive(Error, Debug)]
+pub enum CoreError {
+    /// The plugin's [MessageCapabilities](crate::api::schema::protocol::MessageCapabilities)
+    /// say it doesn't handle the instruction, or it hasn't sent them yet.
+    #[error("Plugin '{plugin}' does not support {instruction_type}")]
+    Unsupported {
+        plugin: PathBuf,
+        instruction_type: PluginInstructionType,
+    },
+    /// The plugin answered with a MessageActionFailed, such as when the service refused it.
+    #[error("Plugin '{plugin}' could not carry out {instruction_type} for message {message_id}: {reason}")]
+    ActionFailed {
+        plugin: PathBuf,
+        instruction_type: PluginInstructionType,
+        message_id: String,
+        reason: String,
+    },
+}
@@ -0,0 +1,260 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use log::debug;
+
+use crate::api::schema::message::{
+    Message, MessageReceivedInstruction, MessageEditedInstruction, MessageDeletedInstruction, Reaction,
+    ReactionsChangedInstruction
+};
+
+/// Identifies a conversation by the plugin and account it belongs to,
+/// since its ID is only unique among the account's conversations.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ConversationKey {
+    plugin: PathBuf,
+    account_id: String,
+    conversation_id: String,
+}
+
+impl ConversationKey {
+    fn new(plugin: &Path, account_id: &str, conversation_id: &str) -> Self {
+        ConversationKey {
+            plugin: plugin.to_path_buf(),
+            account_id: account_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+        }
+    }
+}
+
+/// A message that arrived in one of an account's conversations, as it is now.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    /// Its body is the latest one, if it was edited.
+    pub message: Message,
+    /// When it was last edited, in milliseconds since the Unix epoch.
+    pub edited_at_ms: Option<u64>,
+    pub reactions: Vec<Reaction>,
+}
+
+/// Keeps the messages that arrived in each account's conversations, from the
+/// [MessageReceivedInstruction]s plugins send, and applies the edits, deletions
+/// and reactions they send for them. Plugins are identified by the path they
+/// were loaded from, as in the [AccountRegistry](super::accounts::AccountRegistry).
+#[derive(Debug, Default)]
+pub struct MessageStore {
+    /// Oldest first.
+    conversations: HashMap<ConversationKey, Vec<StoredMessage>>,
+}
+
+impl MessageStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Adds a message that arrived in one of an account's conversations.
+     * A message with the same ID as one that's already kept replaces it,
+     * such as when a plugin sends it again after reconnecting.
+     *
+     * # Arguments
+     * ## plugin
+     * The path of the plugin that sent it.
+     */
+    pub fn handle_received(&mut self, plugin: &Path, received: MessageReceivedInstruction) {
+        let message = received.message;
+        let key = ConversationKey::new(plugin, &received.account_id, &message.conversation_id);
+        let messages = self.conversations.entry(key).or_default();
+        if let Some(i) = messages.iter().position(|m| m.message.id == message.id) {
+            debug!("Message {} from {} arrived again", message.id, plugin.display());
+            messages.remove(i);
+        }
+        // Equal times keep the order they arrived in.
+        let index = messages.partition_point(|m| m.message.sent_at_ms <= message.sent_at_ms);
+        messages.insert(index, StoredMessage { message, edited_at_ms: None, reactions: vec![] });
+    }
+
+    /**
+     * Changes the body of a message, whoever edited it.
+     *
+     * # Returns
+     * Whether the message is kept. Edits to messages that aren't, such as ones
+     * that arrived before the core started, are ignored.
+     */
+    pub fn handle_edited(&mut self, plugin: &Path, edited: MessageEditedInstruction) -> bool {
+        match self.get_message_mut(plugin, &edited.account_id, &edited.conversation_id, &edited.message_id) {
+            Some(stored) => {
+                stored.message.body = edited.body;
+                stored.edited_at_ms = Some(edited.edited_at_ms);
+                true
+            },
+            None => {
+                debug!("Ignoring edit to unknown message {} from {}", edited.message_id, plugin.display());
+                false
+            }
+        }
+    }
+
+    /**
+     * Forgets a message, whoever deleted it.
+     *
+     * # Returns
+     * The message, if it was kept.
+     */
+    pub fn handle_deleted(&mut self, plugin: &Path, deleted: MessageDeletedInstruction) -> Option<StoredMessage> {
+        let key = ConversationKey::new(plugin, &deleted.account_id, &deleted.conversation_id);
+        let messages = self.conversations.get_mut(&key)?;
+        let index = messages.iter().position(|m| m.message.id == deleted.message_id)?;
+        Some(messages.remove(index))
+    }
+
+    /**
+     * Replaces the reactions to a message with the ones the plugin sent,
+     * whoever changed them.
+     *
+     * # Returns
+     * Whether the message is kept. Like edits, changes to others are ignored.
+     */
+    pub fn handle_reactions_changed(&mut self, plugin: &Path, changed: ReactionsChangedInstruction) -> bool {
+        match self.get_message_mut(plugin, &changed.account_id, &changed.conversation_id, &changed.message_id) {
+            Some(stored) => {
+                stored.reactions = changed.reactions;
+                true
+            },
+            None => {
+                debug!("Ignoring reactions to unknown message {} from {}", changed.message_id, plugin.display());
+                false
+            }
+        }
+    }
+
+    /// Returns the messages kept for one of an account's conversations, oldest first.
+    pub fn get_messages(&self, plugin: &Path, account_id: &str, conversation_id: &str) -> &[StoredMessage] {
+        self.conversations.get(&ConversationKey::new(plugin, account_id, conversation_id))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns the message with the given ID in one of an account's conversations.
+    pub fn get_message(&self, plugin: &Path, account_id: &str, conversation_id: &str, message_id: &str)
+        -> Option<&StoredMessage>
+    {
+        self.get_messages(plugin, account_id, conversation_id).iter().find(|m| m.message.id == message_id)
+    }
+
+    fn get_message_mut(&mut self, plugin: &Path, account_id: &str, conversation_id: &str, message_id: &str)
+        -> Option<&mut StoredMessage>
+    {
+        self.conversations.get_mut(&ConversationKey::new(plugin, account_id, conversation_id))?
+            .iter_mut()
+            .find(|m| m.message.id == message_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::schema::conversation::Participant;
+    use claims::{assert_none, assert_some};
+    use test_log::test;
+
+    #[test]
+    fn test_messages_are_kept_in_order() {
+        let mut store = MessageStore::new();
+        let plugin = Path::new("plugin");
+        store.handle_received(plugin, create_received("2", 200));
+        store.handle_received(plugin, create_received("1", 100));
+        store.handle_received(plugin, create_received("3", 200));
+
+        let ids: Vec<&str> = store.get_messages(plugin, "user", "conversation").iter().map(|m| m.message.id.as_str()).collect();
+        assert_eq!(vec!["1", "2", "3"], ids);
+        assert!(store.get_messages(plugin, "other", "conversation").is_empty());
+        assert!(store.get_messages(Path::new("other"), "user", "conversation").is_empty());
+    }
+
+    #[test]
+    fn test_message_arriving_again_replaces_it() {
+        let mut store = MessageStore::new();
+        let plugin = Path::new("plugin");
+        store.handle_received(plugin, create_received("1", 100));
+        let mut again = create_received("1", 100);
+        again.message.body = "Hello again".to_string();
+        store.handle_received(plugin, again);
+
+        assert_eq!(1, store.get_messages(plugin, "user", "conversation").len());
+        assert_eq!("Hello again", assert_some!(store.get_message(plugin, "user", "conversation", "1")).message.body);
+        assert_none!(store.get_message(plugin, "user", "conversation", "2"));
+    }
+
+    #[test]
+    fn test_message_is_edited_and_reacted_to() {
+        let mut store = MessageStore::new();
+        let plugin = Path::new("plugin");
+        store.handle_received(plugin, create_received("1", 100));
+        assert!(store.handle_edited(plugin, MessageEditedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "1".to_string(),
+            body: "Hello, edited".to_string(),
+            edited_at_ms: 150,
+        }));
+        let reactions = vec![Reaction { reaction: "👍".to_string(), participant_ids: vec!["user".to_string()] }];
+        assert!(store.handle_reactions_changed(plugin, ReactionsChangedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "1".to_string(),
+            reactions: reactions.clone(),
+        }));
+
+        let stored = assert_some!(store.get_message(plugin, "user", "conversation", "1"));
+        assert_eq!("Hello, edited", stored.message.body);
+        assert_eq!(Some(150), stored.edited_at_ms);
+        assert_eq!(reactions, stored.reactions);
+    }
+
+    #[test]
+    fn test_message_is_deleted() {
+        let mut store = MessageStore::new();
+        let plugin = Path::new("plugin");
+        store.handle_received(plugin, create_received("1", 100));
+        let deleted = MessageDeletedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "1".to_string(),
+        };
+        assert_some!(store.handle_deleted(plugin, deleted));
+        assert!(store.get_messages(plugin, "user", "conversation").is_empty());
+    }
+
+    #[test]
+    fn test_changes_to_unknown_messages_are_ignored() {
+        let mut store = MessageStore::new();
+        let plugin = Path::new("plugin");
+        store.handle_received(plugin, create_received("1", 100));
+        assert!(!store.handle_edited(Path::new("other"), MessageEditedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "1".to_string(),
+            body: "Not mine".to_string(),
+            edited_at_ms: 150,
+        }));
+        assert_none!(store.handle_deleted(plugin, MessageDeletedInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "2".to_string(),
+        }));
+        assert_eq!("Hello", assert_some!(store.get_message(plugin, "user", "conversation", "1")).message.body);
+    }
+
+    fn create_received(id: &str, sent_at_ms: u64) -> MessageReceivedInstruction {
+        MessageReceivedInstruction {
+            account_id: "user".to_string(),
+            message: Message {
+                id: id.to_string(),
+                conversation_id: "conversation".to_string(),
+                author: Participant { id: "friend".to_string(), display_name: "Friend".to_string() },
+                body: "Hello".to_string(),
+                sent_at_ms,
+            },
+        }
+    }
+}
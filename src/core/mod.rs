@@ -1,11 +1,39 @@
+pub mod accounts;
+pub mod error;
+pub mod messages;
+pub mod outbox;
 pub mod socket_handler;
+pub mod transport;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use log::{debug, warn};
+use serde::Serialize;
+use std::{fmt::Debug, path::Path};
 
-use crate::process_management::process_manager::ProcessManager;
+use crate::{
+    api::schema::{
+        auth::AuthAccountResponse,
+        instructions::{CoreInstructionType, DeserializableCoreInstr, PluginInstructionType, SerializablePluginInstr},
+        message::{
+            MessageSendResultInstruction, MessageReceivedInstruction, SendMessageInstruction,
+            FetchHistoryInstruction, HistoryPageInstruction,
+            EditMessageInstruction, DeleteMessageInstruction, ReactToMessageInstruction, MessageEditedInstruction,
+            MessageDeletedInstruction, ReactionsChangedInstruction, MessageActionFailedInstruction
+        }
+    },
+    process_management::{
+        process_manager::{ProcessManager, ProcessEvent}, process::{Process, ProcessHandle}, error::ProcessManagerError
+    }
+};
+use self::{accounts::AccountRegistry, error::CoreError, messages::MessageStore, outbox::Outbox};
 
 pub struct Core {
-    proc_manager: ProcessManager
+    proc_manager: ProcessManager,
+    accounts: AccountRegistry,
+    outbox: Outbox,
+    messages: MessageStore,
+    /// For the local IDs of messages the core sends.
+    next_local_id: u64,
 }
 
 impl Core {
@@ -19,8 +47,346 @@ impl Core {
     pub fn new() -> Result<Core> {
         let man = ProcessManager::from_dir_str("polychat")?;
 
-        Ok(Core {
-            proc_manager: man
-        })
+        Ok(Core::with_process_manager(man))
+    }
+
+    /// Creates a Core that uses the plugins loaded by the given ProcessManager.
+    pub fn with_process_manager(proc_manager: ProcessManager) -> Core {
+        Core {
+            proc_manager,
+            accounts: AccountRegistry::new(),
+            outbox: Outbox::new(),
+            messages: MessageStore::new(),
+            next_local_id: 1,
+        }
+    }
+
+    /// Returns the ProcessManager that owns all of the loaded plugins.
+    pub fn get_process_manager(&mut self) -> &mut ProcessManager {
+        &mut self.proc_manager
+    }
+
+    /// Returns the accounts that plugins are logging in to, or have logged in to.
+    pub fn get_accounts(&self) -> &AccountRegistry {
+        &self.accounts
+    }
+
+    /// Returns the messages the core asked plugins to send, and how far they've gotten.
+    pub fn get_outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    /// Returns the messages that arrived in accounts' conversations.
+    pub fn get_messages(&self) -> &MessageStore {
+        &self.messages
+    }
+
+    /**
+     * Asks a plugin to send a message from one of its accounts. Its delivery
+     * state is kept in the [Outbox], and updated as the plugin sends results for it.
+     * 
+     * # Arguments
+     * ## plugin
+     * The path of the plugin the account belongs to.
+     * 
+     * # Returns
+     * The message's local ID on success. If the plugin isn't loaded, the error is a
+     * [ProcessManagerError::NotLoaded]. If it couldn't be sent to the plugin, the
+     * message is marked as failed.
+     */
+    pub async fn send_message(&mut self, plugin: &Path, account_id: &str, conversation_id: &str, body: &str)
+        -> Result<String>
+    {
+        let handle = self.get_handle(plugin)?;
+        let local_id = self.next_local_id.to_string();
+        self.next_local_id += 1;
+        let instr = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::SendMessage,
+            request_id: handle.next_request_id(),
+            payload: SendMessageInstruction {
+                account_id: account_id.to_string(),
+                conversation_id: conversation_id.to_string(),
+                local_id: local_id.clone(),
+                body: body.to_string(),
+            },
+        };
+        self.outbox.track(plugin, &instr.payload);
+        if let Err(e) = handle.send_instruction(&instr).await {
+            self.outbox.fail(plugin, &local_id, e.to_string());
+            return Err(e);
+        }
+        Ok(local_id)
+    }
+
+    /**
+     * Asks a plugin for a page of a conversation's messages, and waits for it.
+     * To keep scrolling, fetch again with the page's `next_cursor` as a
+     * [HistoryAnchor::Cursor](crate::api::schema::message::HistoryAnchor::Cursor).
+     * 
+     * # Returns
+     * The page on success. If the plugin isn't loaded, the error is a [ProcessManagerError::NotLoaded].
+     * If the plugin doesn't answer in time, it's a [PluginError::RequestTimedOut](crate::process_management::error::PluginError::RequestTimedOut).
+     */
+    pub async fn fetch_history(&self, plugin: &Path, fetch: FetchHistoryInstruction) -> Result<HistoryPageInstruction> {
+        let handle = self.get_handle(plugin)?;
+        let response = handle.request(PluginInstructionType::FetchHistory, fetch).await?;
+        if response.instruction_type != CoreInstructionType::HistoryPage {
+            return Err(anyhow!("{} answered FetchHistory with {}", plugin.display(), response.instruction_type));
+        }
+        response.payload.decode()
+    }
+
+    /**
+     * Asks a plugin to change the body of a message, and waits for it to be
+     * done. The plugin sends a MessageEdited once it is, like it does for
+     * edits made elsewhere, and the edit is applied to the [MessageStore].
+     * 
+     * # Returns
+     * An [Error](std::error::Error) if it wasn't done. If the plugin isn't loaded, the error
+     * is a [ProcessManagerError::NotLoaded], and if it doesn't support editing messages, it's a
+     * [CoreError::Unsupported]. If the plugin says it couldn't, it's a [CoreError::ActionFailed],
+     * and if it doesn't answer in time, it's a
+     * [PluginError::RequestTimedOut](crate::process_management::error::PluginError::RequestTimedOut).
+     */
+    pub async fn edit_message(&mut self, plugin: &Path, edit: EditMessageInstruction) -> Result<()> {
+        let message_id = edit.message_id.clone();
+        self.request_if_supported(plugin, PluginInstructionType::EditMessage, edit, &message_id, CoreInstructionType::MessageEdited)
+            .await
+    }
+
+    /// Asks a plugin to delete a message, and waits for the MessageDeleted it sends
+    /// once it's done. Fails like [Core::edit_message].
+    pub async fn delete_message(&mut self, plugin: &Path, delete: DeleteMessageInstruction) -> Result<()> {
+        let message_id = delete.message_id.clone();
+        self.request_if_supported(plugin, PluginInstructionType::DeleteMessage, delete, &message_id, CoreInstructionType::MessageDeleted)
+            .await
+    }
+
+    /// Asks a plugin to add or remove a reaction to a message, and waits for the
+    /// ReactionsChanged it sends once it's done. Fails like [Core::edit_message].
+    pub async fn react_to_message(&mut self, plugin: &Path, react: ReactToMessageInstruction) -> Result<()> {
+        let message_id = react.message_id.clone();
+        self.request_if_supported(plugin, PluginInstructionType::ReactToMessage, react, &message_id, CoreInstructionType::ReactionsChanged)
+            .await
+    }
+
+    /// Sends a request to a plugin, unless its Init says it doesn't support it,
+    /// and handles the answer if it's the expected one.
+    async fn request_if_supported<P: Serialize + Debug>(&mut self, plugin: &Path, instruction_type: PluginInstructionType,
+        payload: P, message_id: &str, expected: CoreInstructionType) -> Result<()>
+    {
+        let process = self.get_process(plugin)?;
+        let supported = process.get_init_data()
+            .is_some_and(|init| init.protocol_data.capabilities.supports(instruction_type));
+        if !supported {
+            return Err(CoreError::Unsupported { plugin: plugin.to_path_buf(), instruction_type }.into());
+        }
+        let response = process.get_handle().request(instruction_type, payload).await?;
+        if response.instruction_type == CoreInstructionType::MessageActionFailed {
+            let failed = response.payload.decode::<MessageActionFailedInstruction>()?;
+            return Err(CoreError::ActionFailed {
+                plugin: plugin.to_path_buf(),
+                instruction_type,
+                message_id: message_id.to_string(),
+                reason: failed.error,
+            }.into());
+        }
+        if response.instruction_type != expected {
+            return Err(anyhow!("{} answered {} with {}", plugin.display(), instruction_type, response.instruction_type));
+        }
+        self.handle_instruction(plugin, &response)
+    }
+
+    fn get_handle(&self, plugin: &Path) -> Result<ProcessHandle> {
+        Ok(self.get_process(plugin)?.get_handle())
+    }
+
+    fn get_process(&self, plugin: &Path) -> Result<&Process> {
+        match self.proc_manager.get_processes().iter().find(|p| p.get_path() == plugin) {
+            Some(process) => Ok(process),
+            None => Err(ProcessManagerError::NotLoaded(plugin.to_path_buf()).into())
+        }
+    }
+
+    /**
+     * Waits for the next event from the loaded plugins, like [ProcessManager::next_event],
+     * after updating what the core keeps track of from it. The accounts of a plugin
+     * that is unloaded are forgotten, since it has to log in to them again once it's
+     * restarted. This must be called regularly for plugins' instructions to be handled.
+     * 
+     * # Returns
+     * The event. Like [ProcessManager::next_event], it's never None.
+     */
+    pub async fn next_event(&mut self) -> Option<ProcessEvent> {
+        let event = self.proc_manager.next_event().await?;
+        match &event {
+            ProcessEvent::Instruction { process_path, instruction } => {
+                // Plugins that keep sending invalid instructions are disconnected by their process.
+                if let Err(e) = self.handle_instruction(process_path, instruction) {
+                    warn!("Invalid data for instruction type {} from {}: {}", instruction.instruction_type, process_path.display(), e);
+                }
+            },
+            ProcessEvent::Unloaded(path) => {
+                let forgotten = self.accounts.remove_plugin(path);
+                if !forgotten.is_empty() {
+                    debug!("Forgot {} accounts of {}, since it was unloaded", forgotten.len(), path.display());
+                }
+            },
+            ProcessEvent::Error(_) => {}
+        }
+        Some(event)
+    }
+
+    /**
+     * Updates what the core keeps track of from an instruction a plugin sent.
+     * Instructions that don't change anything it keeps track of are ignored.
+     * 
+     * # Arguments
+     * ## plugin
+     * The path of the plugin that sent the instruction.
+     * 
+     * # Returns
+     * An [Error](std::error::Error) if the instruction's payload is invalid for its type.
+     */
+    pub fn handle_instruction(&mut self, plugin: &Path, instr: &DeserializableCoreInstr) -> Result<()> {
+        match &instr.instruction_type {
+            CoreInstructionType::AuthAccountResponse => {
+                let response = instr.payload.decode::<AuthAccountResponse>()?;
+                self.accounts.handle_auth_response(plugin, response);
+            },
+            CoreInstructionType::MessageSendResult => {
+                let result = instr.payload.decode::<MessageSendResultInstruction>()?;
+                self.outbox.handle_send_result(plugin, result);
+            },
+            CoreInstructionType::MessageReceived => {
+                let received = instr.payload.decode::<MessageReceivedInstruction>()?;
+                self.messages.handle_received(plugin, received);
+            },
+            CoreInstructionType::MessageEdited => {
+                let edited = instr.payload.decode::<MessageEditedInstruction>()?;
+                self.messages.handle_edited(plugin, edited);
+            },
+            CoreInstructionType::MessageDeleted => {
+                let deleted = instr.payload.decode::<MessageDeletedInstruction>()?;
+                self.messages.handle_deleted(plugin, deleted);
+            },
+            CoreInstructionType::ReactionsChanged => {
+                let changed = instr.payload.decode::<ReactionsChangedInstruction>()?;
+                self.messages.handle_reactions_changed(plugin, changed);
+            },
+            CoreInstructionType::MessageActionFailed => {
+                // Only arrives here if the request it answers already timed out.
+                let failed = instr.payload.decode::<MessageActionFailedInstruction>()?;
+                warn!("{} failed to change message {} too late: {}", plugin.display(), failed.message_id, failed.error);
+            },
+            other => debug!("Core doesn't keep track of anything from {}", other)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        api::schema::{
+            auth::{AccountIdentity, AuthResult},
+            instructions::SerializableCoreInstr,
+            message::{HistoryAnchor, HistoryDirection, Message},
+            conversation::Participant,
+            protocol::Encoding
+        },
+        core::accounts::AccountState
+    };
+    use claims::{assert_err, assert_ok, assert_some};
+    use test_log::test;
+
+    #[test]
+    fn test_auth_response_updates_accounts() {
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        let plugin = Path::new("plugin");
+        let response = AuthAccountResponse {
+            account: AccountIdentity {
+                id: "user".to_string(),
+                display_name: "User".to_string(),
+                protocol: "test_protocol".to_string(),
+            },
+            result: AuthResult::Success,
+            details: String::new(),
+        };
+        assert_ok!(core.handle_instruction(plugin, &create_instruction(CoreInstructionType::AuthAccountResponse, &response)));
+
+        let account = assert_some!(core.get_accounts().get_account(plugin, "user"));
+        assert_eq!(AccountState::Authenticated, account.state);
+        assert_eq!("test_protocol", account.identity.protocol);
+    }
+
+    #[test]
+    fn test_received_message_is_kept() {
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        let plugin = Path::new("plugin");
+        let received = MessageReceivedInstruction {
+            account_id: "user".to_string(),
+            message: Message {
+                id: "42".to_string(),
+                conversation_id: "conversation".to_string(),
+                author: Participant { id: "friend".to_string(), display_name: "Friend".to_string() },
+                body: "Hi!".to_string(),
+                sent_at_ms: 1_700_000_000_000,
+            },
+        };
+        assert_ok!(core.handle_instruction(plugin, &create_instruction(CoreInstructionType::MessageReceived, &received)));
+
+        let message = assert_some!(core.get_messages().get_message(plugin, "user", "conversation", "42"));
+        assert_eq!("Hi!", message.message.body);
+    }
+
+    #[test(tokio::test)]
+    async fn test_send_message_to_unloaded_plugin() {
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        let err = assert_err!(core.send_message(Path::new("plugin"), "user", "conversation", "Hello").await);
+        assert!(matches!(err.downcast_ref::<ProcessManagerError>(), Some(ProcessManagerError::NotLoaded(_))), "Unexpected error {}", err);
+        assert!(core.get_outbox().get_undelivered().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_fetch_history_from_unloaded_plugin() {
+        let core = Core::with_process_manager(ProcessManager::new());
+        let fetch = FetchHistoryInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            anchor: HistoryAnchor::Latest,
+            direction: HistoryDirection::Before,
+            limit: 50,
+        };
+        let err = assert_err!(core.fetch_history(Path::new("plugin"), fetch).await);
+        assert!(matches!(err.downcast_ref::<ProcessManagerError>(), Some(ProcessManagerError::NotLoaded(_))), "Unexpected error {}", err);
+    }
+
+    #[test(tokio::test)]
+    async fn test_edit_message_on_unloaded_plugin() {
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        let edit = EditMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "42".to_string(),
+            body: "Hello again".to_string(),
+        };
+        let err = assert_err!(core.edit_message(Path::new("plugin"), edit).await);
+        assert!(matches!(err.downcast_ref::<ProcessManagerError>(), Some(ProcessManagerError::NotLoaded(_))), "Unexpected error {}", err);
+    }
+
+    #[test]
+    fn test_invalid_auth_response() {
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        let instr = create_instruction(CoreInstructionType::AuthAccountResponse, &"not a response");
+        assert_err!(core.handle_instruction(Path::new("plugin"), &instr));
+    }
+
+    fn create_instruction<P: serde::Serialize + std::fmt::Debug>(instruction_type: CoreInstructionType, payload: &P)
+        -> DeserializableCoreInstr
+    {
+        let instr = SerializableCoreInstr { instruction_type, request_id: None, payload };
+        assert_ok!(DeserializableCoreInstr::decode(&assert_ok!(instr.encode(Encoding::Json)), Encoding::Json))
     }
 }
@@ -0,0 +1,256 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use log::{debug, info, warn};
+
+use crate::api::schema::message::{DeliveryState, MessageSendResultInstruction, SendMessageInstruction};
+
+/// A message the core asked a plugin to send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingMessage {
+    /// The path of the plugin that's sending it.
+    pub plugin: PathBuf,
+    pub account_id: String,
+    pub conversation_id: String,
+    pub local_id: String,
+    pub body: String,
+    /// The service's ID for the message, once it's been sent.
+    pub message_id: Option<String>,
+    pub state: DeliveryState,
+    /// Why the message failed, for [DeliveryState::Failed].
+    pub error: Option<String>,
+}
+
+/// Identifies an outgoing message by the plugin sending it, since its local
+/// ID is only unique among the messages sent through that plugin.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct MessageKey {
+    plugin: PathBuf,
+    local_id: String,
+}
+
+impl MessageKey {
+    fn new(plugin: &Path, local_id: &str) -> Self {
+        MessageKey { plugin: plugin.to_path_buf(), local_id: local_id.to_string() }
+    }
+}
+
+/// Keeps track of how far each message the core asked plugins to send has
+/// gotten, from the [MessageSendResultInstruction]s they send back.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    messages: HashMap<MessageKey, OutgoingMessage>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Starts keeping track of a message that's being sent by the plugin at the given path.
+     * It's [DeliveryState::Pending] until the plugin says otherwise.
+     *
+     * # Returns
+     * Whether it's kept track of. A message with the same local ID that's still
+     * on its way isn't replaced, while one that was delivered or failed is.
+     */
+    pub fn track(&mut self, plugin: &Path, instr: &SendMessageInstruction) -> bool {
+        let key = MessageKey::new(plugin, &instr.local_id);
+        if let Some(existing) = self.messages.get(&key).filter(|m| !m.state.is_final()) {
+            warn!("Not tracking message {} from {} again, since it's still {:?}",
+                instr.local_id, plugin.display(), existing.state);
+            return false;
+        }
+        self.messages.insert(key, OutgoingMessage {
+            plugin: plugin.to_path_buf(),
+            account_id: instr.account_id.clone(),
+            conversation_id: instr.conversation_id.clone(),
+            local_id: instr.local_id.clone(),
+            body: instr.body.clone(),
+            message_id: None,
+            state: DeliveryState::Pending,
+            error: None,
+        });
+        true
+    }
+
+    /**
+     * Updates a message from a result the plugin at the given path sent for it.
+     *
+     * Results for messages that weren't sent through the plugin, or that would
+     * move a message backwards, such as one that arrived late, are ignored.
+     *
+     * # Returns
+     * The message's state after the result, or None if the message isn't known.
+     */
+    pub fn handle_send_result(&mut self, plugin: &Path, result: MessageSendResultInstruction) -> Option<DeliveryState> {
+        let message = match self.messages.get_mut(&MessageKey::new(plugin, &result.local_id)) {
+            Some(message) => message,
+            None => {
+                warn!("{} sent a result for message {}, which it wasn't asked to send", plugin.display(), result.local_id);
+                return None;
+            }
+        };
+        if !message.state.can_become(result.state) {
+            debug!("Ignoring {:?} for message {}, which is already {:?}", result.state, message.local_id, message.state);
+            return Some(message.state);
+        }
+        match &result.error {
+            Some(error) => info!("Message {} is now {:?}: {}", message.local_id, result.state, error),
+            None => debug!("Message {} is now {:?}", message.local_id, result.state)
+        }
+        message.state = result.state;
+        message.message_id = result.message_id.or(message.message_id.take());
+        message.error = result.error;
+        Some(message.state)
+    }
+
+    /// Marks a message as failed without hearing from the plugin, such as when it couldn't be sent to it.
+    pub fn fail(&mut self, plugin: &Path, local_id: &str, error: String) {
+        let key = MessageKey::new(plugin, local_id);
+        if let Some(message) = self.messages.get_mut(&key).filter(|m| m.state.can_become(DeliveryState::Failed)) {
+            message.state = DeliveryState::Failed;
+            message.error = Some(error);
+        }
+    }
+
+    /// Returns the message with the given local ID that's being sent by the plugin at the given path.
+    pub fn get_message(&self, plugin: &Path, local_id: &str) -> Option<&OutgoingMessage> {
+        self.messages.get(&MessageKey::new(plugin, local_id))
+    }
+
+    /// Returns the messages that aren't [DeliveryState::Delivered] or [DeliveryState::Failed] yet.
+    pub fn get_undelivered(&self) -> Vec<&OutgoingMessage> {
+        self.messages.values().filter(|m| !m.state.is_final()).collect()
+    }
+
+    /**
+     * Stops keeping track of a message, such as once the GUI has shown that it
+     * was delivered or failed.
+     *
+     * # Returns
+     * The message, if it was being kept track of.
+     */
+    pub fn remove(&mut self, plugin: &Path, local_id: &str) -> Option<OutgoingMessage> {
+        self.messages.remove(&MessageKey::new(plugin, local_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use claims::{assert_none, assert_some};
+    use test_log::test;
+
+    #[test]
+    fn test_message_is_delivered() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        outbox.track(plugin, &create_message("1"));
+        assert_eq!(DeliveryState::Pending, assert_some!(outbox.get_message(plugin, "1")).state);
+
+        let sent = create_result("1", DeliveryState::Sent, Some("42"), None);
+        assert_eq!(Some(DeliveryState::Sent), outbox.handle_send_result(plugin, sent));
+        let delivered = create_result("1", DeliveryState::Delivered, None, None);
+        assert_eq!(Some(DeliveryState::Delivered), outbox.handle_send_result(plugin, delivered));
+
+        let message = assert_some!(outbox.get_message(plugin, "1"));
+        // Kept from when it was sent.
+        assert_eq!(Some("42".to_string()), message.message_id);
+        assert!(outbox.get_undelivered().is_empty());
+    }
+
+    #[test]
+    fn test_state_only_moves_forward() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        outbox.track(plugin, &create_message("1"));
+        outbox.handle_send_result(plugin, create_result("1", DeliveryState::Delivered, Some("42"), None));
+
+        let late = create_result("1", DeliveryState::Sent, Some("42"), None);
+        assert_eq!(Some(DeliveryState::Delivered), outbox.handle_send_result(plugin, late));
+        let failed = create_result("1", DeliveryState::Failed, None, Some("Too late"));
+        assert_eq!(Some(DeliveryState::Delivered), outbox.handle_send_result(plugin, failed));
+        assert_none!(assert_some!(outbox.get_message(plugin, "1")).error.as_ref());
+    }
+
+    #[test]
+    fn test_message_fails() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        outbox.track(plugin, &create_message("1"));
+        outbox.track(plugin, &create_message("2"));
+        let failed = create_result("1", DeliveryState::Failed, None, Some("Rate limited"));
+        assert_eq!(Some(DeliveryState::Failed), outbox.handle_send_result(plugin, failed));
+        outbox.fail(plugin, "2", "Not connected".to_string());
+
+        assert_eq!(Some(&"Rate limited".to_string()), assert_some!(outbox.get_message(plugin, "1")).error.as_ref());
+        assert_eq!(DeliveryState::Failed, assert_some!(outbox.get_message(plugin, "2")).state);
+        assert!(outbox.get_undelivered().is_empty());
+        assert_some!(outbox.remove(plugin, "1"));
+        assert_none!(outbox.get_message(plugin, "1"));
+    }
+
+    #[test]
+    fn test_result_from_other_plugin_is_ignored() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        outbox.track(plugin, &create_message("1"));
+        let sent = create_result("1", DeliveryState::Sent, Some("42"), None);
+        assert_none!(outbox.handle_send_result(Path::new("other"), sent));
+        assert_none!(outbox.handle_send_result(plugin, create_result("2", DeliveryState::Sent, None, None)));
+        assert_eq!(DeliveryState::Pending, assert_some!(outbox.get_message(plugin, "1")).state);
+    }
+
+    #[test]
+    fn test_same_local_id_from_other_plugin_is_kept_apart() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        let other = Path::new("other");
+        assert!(outbox.track(plugin, &create_message("1")));
+        assert!(outbox.track(other, &create_message("1")));
+        outbox.handle_send_result(other, create_result("1", DeliveryState::Sent, Some("42"), None));
+
+        assert_eq!(DeliveryState::Pending, assert_some!(outbox.get_message(plugin, "1")).state);
+        assert_eq!(DeliveryState::Sent, assert_some!(outbox.get_message(other, "1")).state);
+        assert_eq!(2, outbox.get_undelivered().len());
+    }
+
+    #[test]
+    fn test_undelivered_message_is_not_replaced() {
+        let mut outbox = Outbox::new();
+        let plugin = Path::new("plugin");
+        assert!(outbox.track(plugin, &create_message("1")));
+        let mut again = create_message("1");
+        again.body = "Hello again".to_string();
+        assert!(!outbox.track(plugin, &again));
+        assert_eq!("Hello", assert_some!(outbox.get_message(plugin, "1")).body);
+
+        outbox.fail(plugin, "1", "Not connected".to_string());
+        assert!(outbox.track(plugin, &again));
+        assert_eq!(DeliveryState::Pending, assert_some!(outbox.get_message(plugin, "1")).state);
+    }
+
+    fn create_message(local_id: &str) -> SendMessageInstruction {
+        SendMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            local_id: local_id.to_string(),
+            body: "Hello".to_string(),
+        }
+    }
+
+    fn create_result(local_id: &str, state: DeliveryState, message_id: Option<&str>, error: Option<&str>)
+        -> MessageSendResultInstruction
+    {
+        MessageSendResultInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            local_id: local_id.to_string(),
+            message_id: message_id.map(str::to_string),
+            state,
+            timestamp_ms: 0,
+            error: error.map(str::to_string),
+        }
+    }
+}
@@ -1,26 +1,51 @@
 use crate::{
     api::schema::{
         instructions::{SerializablePluginInstr, DeserializableCoreInstr},
+        protocol::{AuthFrame, Encoding, Framing, DEFAULT_MAX_FRAME_SIZE}
     },
-    utils::socket::*
+    utils::{auth::AuthToken, socket::*}
 };
 
 use log::{debug, error, warn, trace};
 use interprocess::local_socket::{
     NameTypeSupport, 
-    tokio::{LocalSocketListener, LocalSocketStream, OwnedReadHalf, OwnedWriteHalf}
+    tokio::{LocalSocketListener, LocalSocketStream}
 };
 use serde::Serialize;
-use std::{path::Path, fs, fmt::Debug};
+use std::{path::Path, fs, fmt::Debug, time::Duration};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use tokio::{task::JoinSet, time::timeout};
 
 use anyhow::Result;
 
+/// How long a new connection has to send its [AuthFrame].
+const AUTH_TIMEOUT: Duration = Duration::from_secs(2);
+/// The largest [AuthFrame] that's read, in bytes.
+const MAX_AUTH_FRAME_SIZE: u32 = 1024;
+
 #[derive(Debug)]
 pub struct SocketHandler {
     socket_name: String,
     listener: LocalSocketListener,
-    read: Option<OwnedReadHalf>,
-    write: Option<OwnedWriteHalf>
+}
+
+/// The read half of a connection accepted by a [SocketHandler].
+/// It's read from independently of the [SocketWriter], so waiting for an
+/// instruction never holds up sending one.
+#[derive(Debug)]
+pub struct SocketReader {
+    socket_name: String,
+    read: FrameReader,
+    encoding: Encoding,
+}
+
+/// The write half of a connection accepted by a [SocketHandler].
+#[derive(Debug)]
+pub struct SocketWriter {
+    socket_name: String,
+    write: FrameWriter,
+    encoding: Encoding,
 }
 
 
@@ -37,11 +62,23 @@ impl SocketHandler {
      * otherwise, an [Error](std::error::Error) is returned containing the error.
      * 
      * # Platform-Dependent Behavior
-     * - Windows/Linux - Creates a namespaced socket (@[`socket_name`](#socket_name).sock)
-     * - BSD/Mac/\*NIX - Creates a filepath socket at /tmp/[`socket_name`](#socket_name).sock
+     * - Windows - Creates a namespaced socket (@[`socket_name`](#socket_name).sock)
+     * - Linux/BSD/Mac/\*NIX - Creates a filepath socket at [`socket_name`](#socket_name).sock in the
+     *   [default_socket_dir], which only the current user can access
      **/
     pub fn new<S>(socket_name: S) -> Result<Self> where S: Into<String> + std::fmt::Display {
-        let name = get_socket_name(socket_name);
+        Self::new_in(&default_socket_dir(), socket_name)
+    }
+
+    /**
+     * Creates a new SocketHandler like [SocketHandler::new], but with filepath
+     * sockets created in the given directory. It's created if it doesn't exist
+     * yet, and must only be accessible by the current user. The socket file
+     * itself is only readable and writable by the current user.
+     **/
+    pub fn new_in<S>(dir: &Path, socket_name: S) -> Result<Self> where S: Into<String> + std::fmt::Display {
+        prepare_socket_dir(dir)?;
+        let name = get_socket_name_in(dir, socket_name);
 
         debug!("Attempting to start server at {}", name);
         let listener = match LocalSocketListener::bind(name.clone()) {
@@ -51,46 +88,66 @@ impl SocketHandler {
                 return Err(e.into());
             }
         };
+        #[cfg(unix)]
+        if Path::new(&name).exists() {
+            fs::set_permissions(&name, fs::Permissions::from_mode(0o600))?;
+        }
         debug!("Server started at {}", name);
         Ok(SocketHandler{
             listener,
             socket_name: name,
-            read: None,
-            write: None
         })
     }
 
     /**
-     * Reads an instruction from the socket connection, fails when an unrecongized
-     * instruction is received
+     * Waits for a new connection, and hands both halves of it to the caller,
+     * so that each can be used from a different task without locking the other.
+     * The connection isn't kept by this, so it's closed once both halves are dropped.
      * 
-     * # Return
-     * Upon a successful read and parse from the socket, a [CoreInstruction]
-     * is returned.  Otherwise an [Error](std::error::Error) is returned.
+     * # Returns
+     * A [Result] with the [SocketReader] and [SocketWriter] on success, and [Error](std::error::Error) on failure
      **/
-    pub async fn get_instruction(&mut self) -> Result<DeserializableCoreInstr> {
-        self.update_owned_split().await?;
-        let data = self.get_data().await?;
-        debug!("Converting '{}' to CoreInstruction", &data);
-        convert_str_to_struct::<DeserializableCoreInstr>(&data)
+    pub async fn accept(&mut self) -> Result<(SocketReader, SocketWriter)> {
+        let (read, write) = self.get_connection().await?.into_split();
+        Ok(split_connection(&self.socket_name, read, write))
     }
 
     /**
-     * If the read or write part of the connection are not associated with this object, a new connection is
-     * gathered, and then the read/write parts are assigned to this object
+     * Waits for a connection that presents the given token in its first frame,
+     * and hands both halves of it to the caller, like [SocketHandler::accept].
+     * 
+     * Connections that present anything else, or nothing within [AUTH_TIMEOUT],
+     * are closed. Each connection is authenticated while more are accepted, so
+     * another local process can't connect in the plugin's place, or keep the
+     * plugin from connecting by opening connections that never send anything.
+     * Once one is authenticated, the rest are closed.
      * 
      * # Returns
-     * A [Result] is returned, void if successful, [Error](std::error::Error) if unsuccessful
+     * A [Result] with the [SocketReader] and [SocketWriter] on success, and
+     * [Error](std::error::Error) if a connection could not be accepted.
      **/
-    async fn update_owned_split(&mut self) -> Result<()> {
-        trace!("Checking if read/write needs updating");
-        if self.read.is_none() || self.write.is_none() {
-            debug!("Updating read/write associations");
-            let (read, write) = self.get_connection().await?.into_split();
-            self.read = Some(read);
-            self.write = Some(write);
+    pub async fn accept_authenticated(&mut self, token: &AuthToken) -> Result<(SocketReader, SocketWriter)> {
+        let mut pending = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = self.accept() => {
+                    let (mut reader, writer) = accepted?;
+                    let token = token.clone();
+                    pending.spawn(async move {
+                        match reader.read_auth_token(&token, AUTH_TIMEOUT).await {
+                            true => Some((reader, writer)),
+                            false => None
+                        }
+                    });
+                },
+                Some(authenticated) = pending.join_next() => match authenticated {
+                    Ok(Some(halves)) => return Ok(halves),
+                    Ok(None) => warn!("Closing a connection on socket {} that didn't present its auth token",
+                        self.socket_name),
+                    Err(e) => warn!("Could not authenticate a connection on socket {}: {}", self.socket_name, e)
+                }
+            }
         }
-        Ok(())
     }
 
     /**
@@ -108,12 +165,115 @@ impl SocketHandler {
             },
             Err(e) => {
                 warn!("Could not accept a socket connection: {}", e);
-                return Err(e.into());
+                Err(e.into())
             }
         }
     }
     /**
-     * Sends a [PluginInstruction] over the socket for the plugin process to handle.
+     * Returns a reference to the name/id of the pipe/socket.
+     * Remember that Windows uses pipes, and unix-like OSs use sockets.
+     */
+    pub fn get_socket_name(&self) -> &String {
+        &self.socket_name
+    }
+}
+
+/// Wraps both halves of a new connection, which start out with JSON encoding.
+pub(crate) fn split_connection<R, W>(socket_name: &str, read: R, write: W) -> (SocketReader, SocketWriter)
+    where R: Into<ReadHalf>, W: Into<WriteHalf>
+{
+    let reader = SocketReader {
+        socket_name: socket_name.to_string(),
+        read: FrameReader::new(read),
+        encoding: Encoding::Json,
+    };
+    let writer = SocketWriter {
+        socket_name: socket_name.to_string(),
+        write: FrameWriter::new(write),
+        encoding: Encoding::Json,
+    };
+    (reader, writer)
+}
+
+impl SocketReader {
+    /**
+     * Reads the [AuthFrame] that has to come first on a new connection.
+     * 
+     * # Returns
+     * Whether it held the given token, and arrived within the given time.
+     **/
+    pub(crate) async fn read_auth_token(&mut self, token: &AuthToken, wait: Duration) -> bool {
+        self.set_max_frame_size(MAX_AUTH_FRAME_SIZE);
+        let presented = match timeout(wait, self.get_data()).await {
+            Ok(Ok(data)) => decode_value::<AuthFrame>(data, Encoding::Json).ok(),
+            Ok(Err(e)) => {
+                debug!("Could not read the auth token from a connection: {}", e);
+                None
+            },
+            Err(_) => None
+        };
+        match presented {
+            Some(frame) if token.matches(&frame.token) => {
+                debug!("Connection on socket {} presented its auth token", self.socket_name);
+                self.set_max_frame_size(DEFAULT_MAX_FRAME_SIZE);
+                true
+            },
+            _ => false
+        }
+    }
+
+    /**
+     * Reads an instruction from the connection, fails when an unrecongized
+     * instruction is received
+     * 
+     * # Return
+     * Upon a successful read and parse from the socket, a [CoreInstruction]
+     * is returned.  Otherwise an [Error](std::error::Error) is returned.
+     **/
+    pub async fn get_instruction(&mut self) -> Result<DeserializableCoreInstr> {
+        let encoding = self.encoding;
+        let data = self.get_data().await?;
+        debug!("Converting {} bytes of {} to CoreInstruction", data.len(), encoding);
+        DeserializableCoreInstr::decode(data, encoding)
+    }
+
+    /**
+     * Waits for the next frame from the connection.
+     * 
+     * # Returns
+     * A [Result] containing the frame on success, encoded with [SocketReader::get_encoding].
+     * If the plugin closed the connection, the error is [SocketError::Closed].
+     **/
+    pub async fn get_data(&mut self) -> Result<&[u8]> {
+        trace!("Fetching data from socket {}", self.socket_name);
+        self.read.next_frame().await
+    }
+
+    /// Reads the instructions after this one with the framing agreed on during the Init handshake.
+    pub fn set_framing(&mut self, framing: Framing, max_frame_size: u32) {
+        self.read.set_framing(framing, max_frame_size);
+    }
+
+    /// Refuses instructions after this one that are larger than the given size, in bytes,
+    /// whatever the framing. Reading them fails with [SocketError::FrameTooLarge].
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.read.set_max_frame_size(max_frame_size);
+    }
+
+    /// Decodes the instructions after this one with the encoding agreed on during the Init handshake.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        debug!("Decoding {} from socket {} from now on", encoding, self.socket_name);
+        self.encoding = encoding;
+    }
+
+    pub fn get_encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+impl SocketWriter {
+    /**
+     * Sends a [PluginInstruction] over the connection for the plugin process to handle.
      * 
      * # Parameters
      * - inst ([PluginInstruction]): The instruction to be sent
@@ -122,41 +282,30 @@ impl SocketHandler {
      * A [Result], void on success, [Error](std::error::Error) on failure
      **/
     pub async fn send_plugin_instruction<P: Serialize + Debug>(&mut self, inst: &SerializablePluginInstr<P>) -> Result<()> {
-        self.update_owned_split().await?;
-        debug!("Converting PluginInstr to String for IPC");
-        let payload = match convert_struct_to_str(inst) {
-            Ok(s) => {
-                debug!("Successfully converted to string {}", s);
-                s
+        debug!("Encoding PluginInstr as {} for IPC", self.encoding);
+        let payload = match inst.encode(self.encoding) {
+            Ok(data) => {
+                debug!("Successfully encoded {} bytes", data.len());
+                data
             },
             Err(e) => {
-                warn!("Could not convert instruction to a String!");
-                return Err(e.into());
+                warn!("Could not encode instruction as {}!", self.encoding);
+                return Err(e);
             }
         };
-        
-        return send_str_over_ipc(&payload, self.write.as_mut().unwrap()).await;
+        trace!("Sending to socket {}", self.socket_name);
+        self.write.send_frame(&payload).await
     }
 
-    /**
-     * Reads data from the socket connection, and returns what it found
-     * 
-     * # Returns
-     * A [Result] containing the received data in a [String] or a [Error](std::error::Error) on failure
-     **/
-    async fn get_data(&mut self) -> Result<String> {
-        self.update_owned_split().await?;
-        debug!("Fetching data from socket");
-        let reader = self.read.as_mut().unwrap();
-        return receive_line(reader).await;
+    /// Sends the instructions after this one with the framing agreed on during the Init handshake.
+    pub fn set_framing(&mut self, framing: Framing, max_frame_size: u32) {
+        self.write.set_framing(framing, max_frame_size);
     }
 
-    /**
-     * Returns a reference to the name/id of the pipe/socket.
-     * Remember that Windows uses pipes, and unix-like OSs use sockets.
-     */
-    pub fn get_socket_name(&self) -> &String {
-        &self.socket_name
+    /// Encodes the instructions after this one with the encoding agreed on during the Init handshake.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        debug!("Encoding {} to socket {} from now on", encoding, self.socket_name);
+        self.encoding = encoding;
     }
 }
 
@@ -174,7 +323,7 @@ impl Drop for SocketHandler {
                             debug!("Socket successfully removed");
                         },
                         Err(e) => {
-                            error!("Could not clean up socket: {}", e.to_string());
+                            error!("Could not clean up socket: {}", e);
                         }
                     }
                 }
@@ -190,14 +339,17 @@ mod test{
     use claims::assert_ok;
 
     #[tokio::test]
-    #[ignore = "Single Threaded test"]
     async fn create_socket_succeeds() {
-        assert_ok!(SocketHandler::new("polychat"));
+        assert_ok!(SocketHandler::new("polychat_create_socket_test"));
     }
 
+    #[cfg(unix)]
     #[tokio::test]
-    #[ignore = "Single Threaded test"]
     async fn socket_cleans_up_after_itself() {
-        assert_ok!(SocketHandler::new("polychat"));
+        let handler = assert_ok!(SocketHandler::new("polychat_socket_cleanup_test"));
+        let path = std::path::PathBuf::from(handler.get_socket_name());
+        assert!(path.exists());
+        drop(handler);
+        assert!(!path.exists());
     }
 }
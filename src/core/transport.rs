@@ -0,0 +1,224 @@
+use crate::{
+    core::socket_handler::{SocketHandler, SocketReader, SocketWriter},
+    utils::auth::AuthToken
+};
+#[cfg(unix)]
+use crate::{
+    api::schema::protocol::INHERITED_FD_PREFIX,
+    core::socket_handler::split_connection,
+    utils::socket::SocketError
+};
+
+#[cfg(unix)]
+use log::debug;
+#[cfg(unix)]
+use std::os::unix::{io::{AsRawFd, OwnedFd, RawFd}, net::UnixStream};
+use std::time::Duration;
+use tokio::process::Command;
+
+use anyhow::Result;
+
+/// Which way the [ProcessManager](crate::process_management::process_manager::ProcessManager)
+/// connects to the plugins it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransportKind {
+    /// A [SocketHandler] that the plugin is passed the name of, and connects to.
+    #[cfg_attr(not(unix), default)]
+    NamedSocket,
+    /// A [SocketPair] that the plugin inherits one end of. There's no name,
+    /// so nothing that can collide, be connected to by another process, or be
+    /// left behind if the core crashes. It doesn't use the
+    /// [socket directory](crate::process_management::process_manager::ProcessManager::set_socket_dir).
+    #[cfg(unix)]
+    #[default]
+    SocketPair,
+}
+
+/// How a plugin process reaches the core.
+#[derive(Debug)]
+pub enum PluginTransport {
+    Named(Box<SocketHandler>),
+    #[cfg(unix)]
+    Inherited(SocketPair),
+}
+
+/// A connected pair of Unix sockets. The core keeps one end, and the plugin
+/// inherits the other as an open file descriptor, which it's told the number
+/// of in its first argument.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct SocketPair {
+    name: String,
+    /// Taken once the connection is accepted.
+    core: Option<UnixStream>,
+    /// Closed once the plugin has started, so that the connection closes when it exits.
+    plugin: Option<OwnedFd>,
+}
+
+impl PluginTransport {
+    /**
+     * Returns what the plugin is passed as its first argument, so it can connect.
+     *
+     * # Returns
+     * The socket name for [PluginTransport::Named], and [INHERITED_FD_PREFIX](crate::api::schema::protocol::INHERITED_FD_PREFIX)
+     * followed by the plugin's file descriptor for [PluginTransport::Inherited].
+     */
+    pub fn get_plugin_arg(&self) -> String {
+        match self {
+            PluginTransport::Named(socket) => socket.get_socket_name().clone(),
+            #[cfg(unix)]
+            PluginTransport::Inherited(pair) => pair.get_plugin_arg(),
+        }
+    }
+
+    /// Lets the process the command starts inherit anything it needs to connect.
+    pub(crate) fn prepare_command(&self, command: &mut Command) {
+        match self {
+            PluginTransport::Named(_) => {},
+            #[cfg(unix)]
+            PluginTransport::Inherited(pair) => pair.prepare_command(command),
+        }
+    }
+
+    /// Called once the plugin has started, and inherited what it needed.
+    pub(crate) fn plugin_started(&mut self) {
+        match self {
+            PluginTransport::Named(_) => {},
+            #[cfg(unix)]
+            PluginTransport::Inherited(pair) => pair.close_plugin_end(),
+        }
+    }
+
+    /**
+     * Waits for the plugin to connect and present the given token, and hands
+     * both halves of the connection to the caller.
+     * See [SocketHandler::accept_authenticated] and [SocketPair::accept_authenticated].
+     * 
+     * # Arguments
+     * ## wait
+     * How long the plugin has to present the token over a [SocketPair], which
+     * is connected before the plugin has even started. Connections to a
+     * [SocketHandler] are only made once it has, so they're each given a short
+     * time of their own instead.
+     */
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub async fn accept_authenticated(&mut self, token: &AuthToken, wait: Duration)
+        -> Result<(SocketReader, SocketWriter)>
+    {
+        match self {
+            PluginTransport::Named(socket) => socket.accept_authenticated(token).await,
+            #[cfg(unix)]
+            PluginTransport::Inherited(pair) => pair.accept_authenticated(token, wait).await,
+        }
+    }
+}
+
+impl From<SocketHandler> for PluginTransport {
+    fn from(socket: SocketHandler) -> Self {
+        PluginTransport::Named(Box::new(socket))
+    }
+}
+
+#[cfg(unix)]
+impl From<SocketPair> for PluginTransport {
+    fn from(pair: SocketPair) -> Self {
+        PluginTransport::Inherited(pair)
+    }
+}
+
+#[cfg(unix)]
+impl SocketPair {
+    /**
+     * Creates a connected pair of sockets. Neither end is inherited by
+     * processes started after this, unless [SocketPair::prepare_command] is
+     * used on the one that should.
+     *
+     * # Returns
+     * A [Result] with the SocketPair on success, and [Error](std::error::Error) on failure
+     */
+    pub fn new() -> Result<SocketPair> {
+        let (core, plugin) = UnixStream::pair()?;
+        core.set_nonblocking(true)?;
+        let name = format!("socket pair {}-{}", core.as_raw_fd(), plugin.as_raw_fd());
+        debug!("Created {}", name);
+        Ok(SocketPair { name, core: Some(core), plugin: Some(plugin.into()) })
+    }
+
+    /// Returns the plugin's end, or None once it's been closed.
+    pub fn get_plugin_fd(&self) -> Option<RawFd> {
+        self.plugin.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    /// See [PluginTransport::get_plugin_arg].
+    pub fn get_plugin_arg(&self) -> String {
+        match self.get_plugin_fd() {
+            Some(fd) => format!("{}{}", INHERITED_FD_PREFIX, fd),
+            None => String::new()
+        }
+    }
+
+    /// Lets the process the command starts inherit the plugin's end, with the same number.
+    pub fn prepare_command(&self, command: &mut Command) {
+        if let Some(fd) = self.get_plugin_fd() {
+            // SAFETY: Only clears the close-on-exec flag on the child's copy of
+            // the file descriptor, which is async-signal-safe.
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    /**
+     * Closes the core's copy of the plugin's end. It should be called once the
+     * plugin has started, since otherwise the connection isn't closed when the
+     * plugin exits.
+     */
+    pub fn close_plugin_end(&mut self) {
+        self.plugin = None;
+    }
+
+    /**
+     * Hands both halves of the core's end to the caller, like [SocketHandler::accept].
+     *
+     * # Returns
+     * A [Result] with the [SocketReader] and [SocketWriter] on success. If they
+     * were already handed over, the error is [SocketError::Closed].
+     */
+    pub fn accept(&mut self) -> Result<(SocketReader, SocketWriter)> {
+        let core = self.core.take().ok_or(SocketError::Closed)?;
+        let (read, write) = tokio::net::UnixStream::from_std(core)?.into_split();
+        Ok(split_connection(&self.name, read, write))
+    }
+
+    /**
+     * Hands both halves of the core's end to the caller once the plugin
+     * presents the given token in its first frame, like [SocketHandler::accept_authenticated].
+     *
+     * Nothing else can connect in the plugin's place, so the token is only
+     * checked so that plugins connect the same way whatever the transport.
+     *
+     * # Arguments
+     * ## wait
+     * How long the plugin has to present the token. The pair is connected
+     * before the plugin starts, so this should allow for it starting up.
+     *
+     * # Returns
+     * A [Result] with the [SocketReader] and [SocketWriter] on success. If the
+     * plugin didn't present the token in time, the error is [SocketError::Unauthenticated].
+     */
+    pub async fn accept_authenticated(&mut self, token: &AuthToken, wait: Duration)
+        -> Result<(SocketReader, SocketWriter)>
+    {
+        let (mut reader, writer) = self.accept()?;
+        if reader.read_auth_token(token, wait).await {
+            Ok((reader, writer))
+        } else {
+            Err(SocketError::Unauthenticated.into())
+        }
+    }
+}
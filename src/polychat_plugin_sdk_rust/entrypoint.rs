@@ -1,38 +1,283 @@
-use std::env;
+use std::{env, sync::Arc};
 
-use crate::api::schema::{instructions::{CoreInstructionType, SerializableCoreInstr}, protocol::{InitDataInstruction, Version, ProtocolData}};
-use log::error;
-use super::socket::SocketCommunicator;
+use crate::{
+    api::schema::{
+        instructions::{CoreInstructionType, SerializableCoreInstr, PluginInstructionType, DeserializablePluginInstr},
+        protocol::{
+            InitDataInstruction, Version, ProtocolData, MessageCapabilities, InitAckInstruction, InitRejectedInstruction,
+            Framing, Encoding, AUTH_TOKEN_ENV
+        },
+        shutdown::ShutdownInstruction
+    },
+    api::plugin_instruction_handler::{PluginInstructionHandler, call_core_handler},
+    utils::socket::SocketError
+};
+use log::{error, warn, debug, info, LevelFilter};
+use super::{socket::{SocketCommunicator, CoreInstructionSender}, logger::PluginLogger};
+
+/// The plugin's handler, shared with [call_core_handler].
+type SharedHandler = Arc<dyn PluginInstructionHandler + Send + Sync>;
+
+// A blocking function that determines the socket name, or the inherited socket,
+// from command line args, then connects to it and sends example Init data.
+// Once the core accepts it, and unless the plugin set its own logger, log
+// records are sent to the core.
+// The handler is created with a sender for answering the core once it's
+// accepted, and is given the instructions the core sends, other than keepalives.
+// Runs until the core closes the connection.
+pub async fn run_plugin<H, F>(create_handler: F)
+    where H: PluginInstructionHandler + Send + Sync + 'static, F: FnOnce(CoreInstructionSender) -> H
+{
+    let protocol_service_name = "example_protocol";
+    run_plugin_with(InitDataInstruction {
+        api_version: Version {major: 0, minor: 1, patch: 0},
+        plugin_version: Version {major: 0, minor: 1, patch: 0},
+        protocol_data: ProtocolData {
+            protocol_service_name: protocol_service_name.to_string(),
+            auth_methods: vec![],
+            capabilities: MessageCapabilities::default(),
+        },
+        framing: vec![Framing::LengthPrefixed, Framing::Newline],
+        encodings: vec![Encoding::MessagePack, Encoding::Cbor, Encoding::Json],
+    }, create_handler).await;
+}
 
-// A blocking function that determines the socket name from command line args,
-// then opens the socket.
-pub async fn run_plugin() {
+// Same as run_plugin, but sends the given Init data.
+pub async fn run_plugin_with<H, F>(init: InitDataInstruction, create_handler: F)
+    where H: PluginInstructionHandler + Send + Sync + 'static, F: FnOnce(CoreInstructionSender) -> H
+{
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Incorrect number of args while running plugin. Got {}, expected 2.", args.len());
+    // Any args after the socket name or inherited socket are the plugin's own, from its manifest.
+    if args.len() < 2 {
+        panic!("Incorrect number of args while running plugin. Got {}, expected at least 2.", args.len());
     }
     let socket_id = args[1].clone();
 
-    let ipc_connection = SocketCommunicator::new(&socket_id).await;
+    let ipc_connection = SocketCommunicator::connect(&socket_id).await;
     match ipc_connection {
         Ok(mut connection) => {
-            // TODO: Make it so the plugin passes this in instead of using example data.
-            let instr_payload = InitDataInstruction {
-                api_version: Version {major: 0, minor: 1, patch: 0},
-                plugin_version: Version {major: 0, minor: 1, patch: 0},
-                protocol_data: ProtocolData { protocol_service_name: "example_protocol".to_string(), auth_methods: vec![] },
-            };
+            match env::var(AUTH_TOKEN_ENV) {
+                Ok(token) => {
+                    if let Err(e) = connection.authenticate(&token).await {
+                        error!("Error while trying to send the auth token: {}", e);
+                    }
+                },
+                Err(_) => warn!("{} isn't set, so the core might not accept this connection", AUTH_TOKEN_ENV)
+            }
+            let protocol_service_name = init.protocol_data.protocol_service_name.clone();
             let init_instr = SerializableCoreInstr {
                 instruction_type: CoreInstructionType::Init,
-                payload: instr_payload,
+                request_id: None,
+                payload: init,
             };
             let send_result = connection.send_core_instruction(&init_instr).await;
             if send_result.is_err() {
                 error!("Error while trying to send core instruction: {:?}", send_result.err())
             }
+            // Nothing else can be sent until then, since the framing might change.
+            if !wait_for_init_ack(&mut connection).await {
+                return;
+            }
+            if PluginLogger::init(connection.get_sender(), LevelFilter::Info).is_ok() {
+                info!(protocol = protocol_service_name.as_str(); "The core accepted this plugin");
+            }
+            let handler: SharedHandler = Arc::new(create_handler(connection.get_sender()));
+            handle_instructions(&mut connection, &handler).await;
         },
         Err(e) => {
             panic!("Error while opening IPC connection. Error: {}", e);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Waits for the core's answer to Init, and switches to the framing it picked.
+/// Returns whether the plugin was accepted. An InitAck that can't be read
+/// counts as not being accepted, since the framing the core picked is unknown.
+async fn wait_for_init_ack(connection: &mut SocketCommunicator) -> bool {
+    loop {
+        match connection.recv_plugin_instruction().await {
+            Ok(instr) if instr.instruction_type == PluginInstructionType::InitAck => {
+                match instr.payload.decode::<InitAckInstruction>() {
+                    Ok(ack) => {
+                        debug!("The core accepted this plugin. It uses API version {}, {:?} framing, and {}",
+                            ack.core_api_version, ack.framing, ack.encoding);
+                        connection.set_framing(ack.framing, ack.max_frame_size).await;
+                        connection.set_encoding(ack.encoding).await;
+                        return true;
+                    },
+                    Err(e) => {
+                        error!("Invalid data for instruction type InitAck, so the core can't be talked to: {}", e);
+                        return false;
+                    }
+                }
+            },
+            Ok(instr) if should_exit(&instr) => return false,
+            Ok(instr) => debug!("Ignoring {} received before InitAck", instr.instruction_type),
+            Err(e) => {
+                if let Some(SocketError::Closed) = e.downcast_ref::<SocketError>() {
+                    debug!("Core closed the connection");
+                    return false;
+                }
+                warn!("Error receiving plugin instruction: {}", e);
+            }
+        }
+    }
+}
+
+/// Receives instructions from the core until the connection closes, the
+/// core rejects the plugin, or the core asks it to shut down. The handler
+/// is told about the Shutdown before this returns.
+async fn handle_instructions(connection: &mut SocketCommunicator, handler: &SharedHandler) {
+    loop {
+        match connection.recv_plugin_instruction().await {
+            Ok(instr) if should_exit(&instr) => {
+                dispatch(handler, &instr);
+                return;
+            },
+            Ok(instr) => handle_instruction(connection, handler, instr).await,
+            Err(e) => {
+                if let Some(SocketError::Closed) = e.downcast_ref::<SocketError>() {
+                    debug!("Core closed the connection");
+                    return;
+                }
+                warn!("Error receiving plugin instruction: {}", e);
+            }
+        }
+    }
+}
+
+/// Whether the instruction means the plugin should exit, which is when the
+/// core rejects it, or asks it to shut down.
+fn should_exit(instr: &DeserializablePluginInstr) -> bool {
+    match instr.instruction_type {
+        PluginInstructionType::InitRejected => {
+            match instr.payload.decode::<InitRejectedInstruction>() {
+                Ok(rejection) => error!("The core rejected this plugin: {}", rejection.reason),
+                Err(e) => error!("The core rejected this plugin, with invalid data: {}", e)
+            }
+            true
+        },
+        PluginInstructionType::Shutdown => {
+            match instr.payload.decode::<ShutdownInstruction>() {
+                Ok(shutdown) => info!("The core asked this plugin to shut down ({:?})", shutdown.reason),
+                Err(e) => warn!("The core asked this plugin to shut down, with invalid data: {}", e)
+            }
+            true
+        },
+        _ => false
+    }
+}
+
+async fn handle_instruction(connection: &mut SocketCommunicator, handler: &SharedHandler,
+    instr: DeserializablePluginInstr)
+{
+    match instr.instruction_type {
+        PluginInstructionType::Keepalive => {
+            // The response carries the same ID as the ping, so just echo the payload.
+            let response = SerializableCoreInstr {
+                instruction_type: CoreInstructionType::KeepaliveResponse,
+                request_id: Some(instr.request_id),
+                payload: instr.payload,
+            };
+            if let Err(e) = connection.send_core_instruction(&response).await {
+                error!("Error while trying to respond to keepalive: {}", e);
+            }
+        },
+        _ => dispatch(handler, &instr)
+    }
+}
+
+/// Passes an instruction on to the plugin's handler.
+fn dispatch(handler: &SharedHandler, instr: &DeserializablePluginInstr) {
+    if let Err(e) = call_core_handler(instr, handler.clone()) {
+        warn!("Could not pass {} on to the plugin: {}", instr.instruction_type, e);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::api::schema::{
+        auth::AuthAccountInstruction,
+        conversation::ListConversationsInstruction,
+        keepalive::KeepaliveInstruction,
+        instructions::RequestId,
+        message::{
+            SendMessageInstruction, FetchHistoryInstruction, EditMessageInstruction, DeleteMessageInstruction,
+            ReactToMessageInstruction
+        }
+    };
+    use std::{io::Write, os::unix::{io::IntoRawFd, net::UnixStream}, sync::Mutex};
+    use claims::assert_ok;
+    use test_log::test;
+
+    /// Keeps the types of the instructions it's given, in order.
+    #[derive(Default)]
+    struct RecordingHandler {
+        received: Mutex<Vec<PluginInstructionType>>,
+    }
+
+    impl RecordingHandler {
+        fn record(&self, instruction_type: PluginInstructionType) {
+            self.received.lock().unwrap().push(instruction_type);
+        }
+    }
+
+    impl PluginInstructionHandler for RecordingHandler {
+        fn on_keepalive(&self, _: RequestId, _: KeepaliveInstruction) { self.record(PluginInstructionType::Keepalive) }
+        fn on_auth_account(&self, _: RequestId, _: AuthAccountInstruction) { self.record(PluginInstructionType::AuthAccount) }
+        fn on_init_ack(&self, _: RequestId, _: InitAckInstruction) { self.record(PluginInstructionType::InitAck) }
+        fn on_init_rejected(&self, _: RequestId, _: InitRejectedInstruction) { self.record(PluginInstructionType::InitRejected) }
+        fn on_shutdown(&self, _: RequestId, _: ShutdownInstruction) { self.record(PluginInstructionType::Shutdown) }
+        fn on_list_conversations(&self, _: RequestId, _: ListConversationsInstruction) {
+            self.record(PluginInstructionType::ListConversations)
+        }
+        fn on_send_message(&self, _: RequestId, _: SendMessageInstruction) { self.record(PluginInstructionType::SendMessage) }
+        fn on_fetch_history(&self, _: RequestId, _: FetchHistoryInstruction) { self.record(PluginInstructionType::FetchHistory) }
+        fn on_edit_message(&self, _: RequestId, _: EditMessageInstruction) { self.record(PluginInstructionType::EditMessage) }
+        fn on_delete_message(&self, _: RequestId, _: DeleteMessageInstruction) { self.record(PluginInstructionType::DeleteMessage) }
+        fn on_react_to_message(&self, _: RequestId, _: ReactToMessageInstruction) {
+            self.record(PluginInstructionType::ReactToMessage)
+        }
+    }
+
+    /// Connects a [SocketCommunicator] to a socket the test writes to in the core's place.
+    fn create_connection() -> (SocketCommunicator, UnixStream) {
+        let (plugin, core) = assert_ok!(UnixStream::pair());
+        // The descriptor was just created, and is only owned by the communicator.
+        let connection = assert_ok!(unsafe { SocketCommunicator::from_fd(plugin.into_raw_fd()) });
+        (connection, core)
+    }
+
+    #[test(tokio::test)]
+    async fn test_init_ack_is_accepted() {
+        let (mut connection, mut core) = create_connection();
+        let ack = r#"{"instruction_type":"InitAck","request_id":1,"payload":{"core_api_version":{"major":0,"minor":1,"patch":0}}}"#;
+        assert_ok!(core.write_all(format!("{}\n", ack).as_bytes()));
+        assert!(wait_for_init_ack(&mut connection).await);
+    }
+
+    #[test(tokio::test)]
+    async fn test_instructions_are_passed_to_handler() {
+        let (mut connection, mut core) = create_connection();
+        let send = r#"{"instruction_type":"SendMessage","request_id":2,"payload":{"account_id":"user","conversation_id":"conversation","local_id":"1","body":"Hello"}}"#;
+        let keepalive = r#"{"instruction_type":"Keepalive","request_id":3,"payload":{"id":0}}"#;
+        let shutdown = r#"{"instruction_type":"Shutdown","request_id":4,"payload":{"reason":"Requested","deadline_ms":1000}}"#;
+        assert_ok!(core.write_all(format!("{}\n{}\n{}\n", send, keepalive, shutdown).as_bytes()));
+
+        let recording = Arc::new(RecordingHandler::default());
+        let handler: SharedHandler = recording.clone();
+        handle_instructions(&mut connection, &handler).await;
+        // Keepalives are answered without it.
+        assert_eq!(vec![PluginInstructionType::SendMessage, PluginInstructionType::Shutdown],
+            *recording.received.lock().unwrap());
+    }
+
+    #[test(tokio::test)]
+    async fn test_undecodable_init_ack_is_a_failure() {
+        let (mut connection, mut core) = create_connection();
+        let ack = r#"{"instruction_type":"InitAck","request_id":1,"payload":{"framing":"Smoke signals"}}"#;
+        assert_ok!(core.write_all(format!("{}\n", ack).as_bytes()));
+        assert!(!wait_for_init_ack(&mut connection).await);
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::BTreeMap, sync::{Arc, atomic::{AtomicU64, Ordering}}};
+
+use log::{Log, Metadata, Record, LevelFilter, kv::{Key, Value, VisitSource}};
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::api::schema::{
+    instructions::{CoreInstructionType, SerializableCoreInstr},
+    log::{LogInstruction, LogLevel}
+};
+use super::socket::CoreInstructionSender;
+
+use anyhow::Result;
+
+/// Records from these targets are not forwarded, since they are logged while
+/// a record is being sent, and forwarding them would never stop.
+const UNFORWARDED_TARGETS: [&str; 5] = [
+    "polychat_ipc::utils::socket",
+    "polychat_ipc::polychat_plugin_sdk_rust::socket",
+    "tokio",
+    "mio",
+    "interprocess",
+];
+
+/// How many records can wait to be sent before new ones are dropped, so a
+/// plugin that logs faster than the core reads doesn't run out of memory.
+const RECORD_QUEUE_SIZE: usize = 1024;
+
+/// A [Log] implementation that sends every record to the core as a Log
+/// instruction, so that plugin logs appear in the core's log.
+#[derive(Debug)]
+pub struct PluginLogger {
+    level: LevelFilter,
+    records: Sender<LogInstruction>,
+    /// How many records were dropped since the core was last told about it.
+    dropped: Arc<AtomicU64>,
+}
+
+impl PluginLogger {
+    /**
+     * Creates a logger that forwards records at or above `level`, and starts
+     * the task that sends them. Must be called from within a tokio runtime.
+     * 
+     * Records logged while [RECORD_QUEUE_SIZE] others are waiting to be sent
+     * are dropped, and the core is sent a warning saying how many were.
+     */
+    pub fn new(sender: CoreInstructionSender, level: LevelFilter) -> PluginLogger {
+        let (records, mut records_rx) = mpsc::channel::<LogInstruction>(RECORD_QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thrd_dropped = dropped.clone();
+        tokio::spawn(async move {
+            while let Some(record) = records_rx.recv().await {
+                let count = thrd_dropped.swap(0, Ordering::Relaxed);
+                let warning = (count > 0).then(|| dropped_warning(count));
+                for record in warning.into_iter().chain([record]) {
+                    let instr = SerializableCoreInstr {
+                        instruction_type: CoreInstructionType::Log,
+                        request_id: None,
+                        payload: record,
+                    };
+                    // There's nowhere left to log the failure to.
+                    if sender.send_core_instruction(&instr).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        PluginLogger { level, records, dropped }
+    }
+
+    /// Installs a new PluginLogger as the global logger.
+    /// Fails if a global logger was already set.
+    pub fn init(sender: CoreInstructionSender, level: LevelFilter) -> Result<()> {
+        log::set_boxed_logger(Box::new(PluginLogger::new(sender, level)))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl Log for PluginLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && !UNFORWARDED_TARGETS.iter().any(|t| metadata.target().starts_with(t))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = FieldCollector(BTreeMap::new());
+        // Collecting into a map cannot fail.
+        let _ = record.key_values().visit(&mut fields);
+        let queued = self.records.try_send(LogInstruction {
+            level: record.level().into(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            fields: fields.0,
+        });
+        if let Err(mpsc::error::TrySendError::Full(_)) = queued {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Tells the core that records were dropped, since it can't tell otherwise.
+fn dropped_warning(count: u64) -> LogInstruction {
+    LogInstruction {
+        level: LogLevel::Warn,
+        target: module_path!().to_string(),
+        message: format!("Dropped {} log records, since they were logged faster than they could be sent", count),
+        fields: BTreeMap::new(),
+    }
+}
+
+struct FieldCollector(BTreeMap<String, String>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        self.0.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
@@ -1,2 +1,3 @@
 pub mod socket;
-pub mod entrypoint;
\ No newline at end of file
+pub mod entrypoint;
+pub mod logger;
@@ -1,14 +1,18 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 
 use interprocess::local_socket::{
     tokio::{
-        LocalSocketStream, OwnedReadHalf, OwnedWriteHalf,
+        LocalSocketStream,
     }
 };
 use serde::Serialize;
+use tokio::sync::Mutex;
 
 use crate::{
-    api::schema::instructions::{SerializableCoreInstr, DeserializablePluginInstr},
+    api::schema::{
+        instructions::{SerializableCoreInstr, DeserializablePluginInstr},
+        protocol::{AuthFrame, Encoding, Framing}
+    },
     utils::socket::*
 };
 
@@ -16,41 +20,109 @@ use anyhow::Result;
 
 #[derive(Debug)]
 pub struct SocketCommunicator {
-    reader: OwnedReadHalf,
-    writer: OwnedWriteHalf
+    reader: FrameReader,
+    encoding: Encoding,
+    sender: CoreInstructionSender
+}
+
+/// A cloneable handle for sending core instructions over a
+/// [SocketCommunicator]'s connection from other tasks.
+#[derive(Debug, Clone)]
+pub struct CoreInstructionSender {
+    writer: Arc<Mutex<CoreInstructionWriter>>
+}
+
+/// The write half of the connection, and how instructions are encoded for it.
+/// They're changed together, so nothing is sent with one but not the other.
+#[derive(Debug)]
+struct CoreInstructionWriter {
+    write: FrameWriter,
+    encoding: Encoding,
 }
 
 /// The component that handles connecting to the IPC socket or pipe, as well as
 /// serializing and deserializing the instructions sent each way.
 impl SocketCommunicator {
-    pub async fn new(name: &String) -> Result<SocketCommunicator> {
-        let stream = match LocalSocketStream::connect(get_socket_name(name)).await {
+    /// Connects to the socket or pipe with the name the core passed to the plugin.
+    pub async fn new(name: &str) -> Result<SocketCommunicator> {
+        let stream = match LocalSocketStream::connect(name).await {
             Ok(s) => s,
             Err(e) => {
                 return Err(e.into());
             }
         };
         let (reader, writer) = stream.into_split();
-        Ok(SocketCommunicator { 
-            reader,
-            writer
-        })
+        Ok(SocketCommunicator::from_halves(reader, writer))
+    }
+
+    /**
+     * Connects to the core however its argument to the plugin says to: the end of a socket
+     * pair the plugin inherited, if it starts with [INHERITED_FD_PREFIX](crate::api::schema::protocol::INHERITED_FD_PREFIX),
+     * and otherwise the named socket or pipe.
+     */
+    pub async fn connect(arg: &str) -> Result<SocketCommunicator> {
+        #[cfg(unix)]
+        if let Some(fd) = arg.strip_prefix(crate::api::schema::protocol::INHERITED_FD_PREFIX) {
+            let fd: std::os::unix::io::RawFd = fd.parse()?;
+            // SAFETY: The core passed the plugin this file descriptor for it to own.
+            return unsafe { SocketCommunicator::from_fd(fd) };
+        }
+        SocketCommunicator::new(arg).await
+    }
+
+    /**
+     * Uses the end of a socket pair that the plugin inherited from the core.
+     * It isn't inherited by any processes the plugin starts.
+     * 
+     * # Safety
+     * The file descriptor must be an open Unix stream socket that nothing else owns,
+     * since it's closed once this is dropped.
+     */
+    #[cfg(unix)]
+    pub unsafe fn from_fd(fd: std::os::unix::io::RawFd) -> Result<SocketCommunicator> {
+        use std::os::unix::io::FromRawFd;
+
+        let stream = std::os::unix::net::UnixStream::from_raw_fd(fd);
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        stream.set_nonblocking(true)?;
+        let (reader, writer) = tokio::net::UnixStream::from_std(stream)?.into_split();
+        Ok(SocketCommunicator::from_halves(reader, writer))
+    }
+
+    fn from_halves<R, W>(reader: R, writer: W) -> SocketCommunicator where R: Into<ReadHalf>, W: Into<WriteHalf> {
+        SocketCommunicator {
+            reader: FrameReader::new(reader),
+            encoding: Encoding::Json,
+            sender: CoreInstructionSender {
+                writer: Arc::new(Mutex::new(CoreInstructionWriter { write: FrameWriter::new(writer), encoding: Encoding::Json }))
+            }
+        }
+    }
+
+    /**
+     * Presents the auth token that the core started the plugin with.
+     * This has to be the first thing sent, or the core closes the connection.
+     */
+    pub async fn authenticate(&mut self, token: &str) -> Result<()> {
+        let frame = encode_value(&AuthFrame { token: token.to_string() }, Encoding::Json)?;
+        self.sender.writer.lock().await.write.send_frame(&frame).await
     }
 
     pub async fn send_core_instruction<P: Serialize + Debug>(&mut self, msg: &SerializableCoreInstr<P>) -> Result<()>{
-        let payload = convert_struct_to_str(&msg)?;
-        Ok(send_str_over_ipc(&payload, &mut self.writer).await?)
+        self.sender.send_core_instruction(msg).await
     }
 
     pub async fn recv_plugin_instruction(&mut self) -> Result<DeserializablePluginInstr> {
-        let data  = match receive_line(&mut self.reader).await {
+        let data  = match self.reader.next_frame().await {
             Ok(s) => s,
             Err(e) => {
-                return Err(e.into());
+                return Err(e);
             }
         };
         
-        return match convert_str_to_struct::<DeserializablePluginInstr>(&data) {
+        match DeserializablePluginInstr::decode(data, self.encoding) {
             Ok(plugin_instr) => {
                 Ok(plugin_instr)
             },
@@ -59,4 +131,35 @@ impl SocketCommunicator {
             }
         }
     }
+
+    /**
+     * Switches both directions to the framing the core picked in its InitAck.
+     * Everything sent or received after the InitAck uses it.
+     */
+    pub async fn set_framing(&mut self, framing: Framing, max_frame_size: u32) {
+        self.reader.set_framing(framing, max_frame_size);
+        self.sender.writer.lock().await.write.set_framing(framing, max_frame_size);
+    }
+
+    /**
+     * Switches both directions to the encoding the core picked in its InitAck.
+     * Everything sent or received after the InitAck uses it.
+     */
+    pub async fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+        self.sender.writer.lock().await.encoding = encoding;
+    }
+
+    /// Returns a handle that can send core instructions while this waits to receive.
+    pub fn get_sender(&self) -> CoreInstructionSender {
+        self.sender.clone()
+    }
+}
+
+impl CoreInstructionSender {
+    pub async fn send_core_instruction<P: Serialize + Debug>(&self, msg: &SerializableCoreInstr<P>) -> Result<()>{
+        let mut writer = self.writer.lock().await;
+        let payload = msg.encode(writer.encoding)?;
+        writer.write.send_frame(&payload).await
+    }
 }
@@ -1,7 +1,14 @@
-use std::path::PathBuf;
+use std::{fmt::Display, path::{Path, PathBuf}, process::ExitStatus, time::Duration};
 
 use thiserror::Error;
 
+use super::process::InstanceId;
+
+use crate::api::schema::{
+    instructions::{PluginInstructionType, RequestId},
+    protocol::Version
+};
+
 #[derive(Error, Debug)]
 pub enum ProcessManagerError {
     #[error("Path '{0}' does not exist")]
@@ -12,4 +19,163 @@ pub enum ProcessManagerError {
     NonDirectory(PathBuf),
     #[error("Path is not set")]
     NoPath,
-}
\ No newline at end of file
+    #[error("No process is loaded from '{0}'")]
+    NotLoaded(PathBuf),
+    #[error("Plugin directory '{0}' has no manifest")]
+    MissingManifest(PathBuf),
+    #[error("Could not read manifest '{path}': {source}")]
+    UnreadableManifest {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Manifest '{path}' is malformed: {source}")]
+    MalformedManifest {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Manifest '{path}' is invalid: {reason}")]
+    InvalidManifest {
+        path: PathBuf,
+        reason: String,
+    },
+    #[error("Plugin '{path}' supports API versions {min_api_version} to {max_api_version}, none of which are compatible with the core's {core_api_version}")]
+    IncompatibleManifest {
+        path: PathBuf,
+        min_api_version: Version,
+        max_api_version: Version,
+        core_api_version: Version,
+    },
+}
+
+/// A problem with a running plugin that the core should know about.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum PluginError {
+    #[error("Plugin '{process_path}' did not respond to request {request_id} ({instruction_type}) in time")]
+    RequestTimedOut {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        request_id: RequestId,
+        instruction_type: PluginInstructionType,
+    },
+    #[error("Plugin '{process_path}' is hung after missing {missed} keepalives")]
+    Unresponsive {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        missed: u32,
+    },
+    #[error("Plugin '{process_path}' crashed ({reason})")]
+    Crashed {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        reason: ExitReason,
+        /// The last lines the plugin wrote to stderr, oldest first.
+        stderr_tail: Vec<String>,
+    },
+    #[error("Plugin '{process_path}' was not restarted after crashing {restarts} times")]
+    RestartLimitReached {
+        process_path: PathBuf,
+        restarts: u32,
+    },
+    #[error("Plugin '{process_path}' did not send Init within {timeout:?}, so it failed to load")]
+    InitTimedOut {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        timeout: Duration,
+    },
+    #[error("Plugin '{process_path}' was rejected, since it uses API version {api_version} and the core uses {core_api_version}")]
+    IncompatibleApi {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        api_version: Version,
+        core_api_version: Version,
+    },
+    #[error("Plugin '{process_path}' ({version}) was shut down, since '{kept_path}' provides {protocol_service_name} at version {kept_version}")]
+    Superseded {
+        process_path: PathBuf,
+        version: Version,
+        protocol_service_name: String,
+        kept_path: PathBuf,
+        kept_version: Version,
+    },
+    #[error("Plugin '{process_path}' was disconnected, since it sent a message of at least {size} bytes, over the limit of {max_message_size}")]
+    MessageTooLarge {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        size: usize,
+        max_message_size: u32,
+    },
+    #[error("Plugin '{process_path}' was disconnected after sending {count} malformed messages")]
+    MalformedMessages {
+        process_path: PathBuf,
+        instance_id: InstanceId,
+        count: u32,
+    },
+}
+
+impl PluginError {
+    /// The run of the plugin that raised it. None for the errors the
+    /// [ProcessManager](super::process_manager::ProcessManager) raises
+    /// about the plugin as a whole.
+    pub fn get_instance_id(&self) -> Option<InstanceId> {
+        match self {
+            PluginError::RequestTimedOut { instance_id, .. }
+            | PluginError::Unresponsive { instance_id, .. }
+            | PluginError::Crashed { instance_id, .. }
+            | PluginError::InitTimedOut { instance_id, .. }
+            | PluginError::IncompatibleApi { instance_id, .. }
+            | PluginError::MessageTooLarge { instance_id, .. }
+            | PluginError::MalformedMessages { instance_id, .. } => Some(*instance_id),
+            PluginError::RestartLimitReached { .. } | PluginError::Superseded { .. } => None,
+        }
+    }
+
+    /// The path of the plugin it's about.
+    pub fn get_process_path(&self) -> &Path {
+        match self {
+            PluginError::RequestTimedOut { process_path, .. }
+            | PluginError::Unresponsive { process_path, .. }
+            | PluginError::Crashed { process_path, .. }
+            | PluginError::RestartLimitReached { process_path, .. }
+            | PluginError::InitTimedOut { process_path, .. }
+            | PluginError::IncompatibleApi { process_path, .. }
+            | PluginError::Superseded { process_path, .. }
+            | PluginError::MessageTooLarge { process_path, .. }
+            | PluginError::MalformedMessages { process_path, .. } => process_path,
+        }
+    }
+}
+
+/// Why a plugin process exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Code(i32),
+    /// Killed by a signal. Only on unix-like OSs.
+    Signal(i32),
+    Unknown,
+}
+
+impl From<ExitStatus> for ExitReason {
+    fn from(status: ExitStatus) -> Self {
+        if let Some(code) = status.code() {
+            return ExitReason::Code(code);
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitReason::Signal(signal);
+            }
+        }
+        ExitReason::Unknown
+    }
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "exit code {}", code),
+            ExitReason::Signal(signal) => write!(f, "signal {}", signal),
+            ExitReason::Unknown => write!(f, "unknown exit status"),
+        }
+    }
+}
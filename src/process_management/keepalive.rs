@@ -0,0 +1,234 @@
+use crate::{
+    api::schema::{
+        instructions::{CoreInstructionType, PluginInstructionType},
+        keepalive::KeepaliveInstruction
+    },
+    process_management::{
+        process::ProcessHandle,
+        error::PluginError,
+        request_tracker::PluginErrorSender
+    }
+};
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant}
+};
+use log::{debug, warn, trace};
+use anyhow::{Result, anyhow};
+use tokio::{task::JoinHandle, time::{interval, MissedTickBehavior}};
+
+/// How often plugins are pinged, and how many pings they can miss.
+#[derive(Debug, Clone)]
+pub struct KeepaliveConfig {
+    pub interval: Duration,
+    /// The number of consecutive pings a plugin can miss before it is
+    /// considered hung.
+    pub max_missed: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval: Duration::from_secs(10),
+            max_missed: 3,
+        }
+    }
+}
+
+/// What the supervisor knows about the responsiveness of a single plugin.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeepaliveStats {
+    /// The round-trip time of the most recent ping that was responded to.
+    pub last_rtt: Option<Duration>,
+    /// The number of pings missed since the last one that was responded to.
+    pub missed: u32,
+    /// Set once `missed` reaches the configured maximum, and cleared by the
+    /// next response.
+    pub hung: bool,
+}
+
+#[derive(Debug)]
+struct Watched {
+    handle: ProcessHandle,
+    stats: KeepaliveStats,
+    next_ping_id: u64,
+    ping_in_flight: bool,
+}
+
+type WatchedProcesses = Arc<Mutex<HashMap<PathBuf, Watched>>>;
+
+/// A background task that periodically sends a [KeepaliveInstruction] to
+/// every watched process, and raises [PluginError::Unresponsive] for any
+/// that stop responding.
+#[derive(Debug)]
+pub struct KeepaliveSupervisor {
+    watched: WatchedProcesses,
+    task: JoinHandle<()>,
+}
+
+impl KeepaliveSupervisor {
+    /// Starts the supervisor. Must be called from within a tokio runtime.
+    pub fn start(config: KeepaliveConfig, errors: PluginErrorSender) -> KeepaliveSupervisor {
+        let watched: WatchedProcesses = Arc::new(Mutex::new(HashMap::new()));
+        let thrd_watched = watched.clone();
+        KeepaliveSupervisor {
+            watched,
+            task: tokio::spawn(async move {
+                supervise(thrd_watched, config, errors).await;
+            }),
+        }
+    }
+
+    /// Starts pinging a process. Replaces any process that was watched under the same path.
+    pub fn watch(&self, handle: ProcessHandle) {
+        debug!("Watching process {} for keepalive responses", handle.get_path().display());
+        let watched = Watched { handle, stats: KeepaliveStats::default(), next_ping_id: 0, ping_in_flight: false };
+        self.watched.lock().unwrap().insert(watched.handle.get_path().to_path_buf(), watched);
+    }
+
+    pub fn unwatch(&self, path: &Path) {
+        self.watched.lock().unwrap().remove(path);
+    }
+
+    pub fn get_stats(&self, path: &Path) -> Option<KeepaliveStats> {
+        self.watched.lock().unwrap().get(path).map(|w| w.stats.clone())
+    }
+}
+
+impl Drop for KeepaliveSupervisor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn supervise(watched: WatchedProcesses, config: KeepaliveConfig, errors: PluginErrorSender) {
+    let mut ticker = interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let pings: Vec<(ProcessHandle, u64)> = {
+            let mut lock = watched.lock().unwrap();
+            lock.values_mut()
+                // A ping still waiting on its deadline will be counted when it expires.
+                .filter(|w| !w.ping_in_flight)
+                .map(|w| {
+                    w.ping_in_flight = true;
+                    w.next_ping_id += 1;
+                    (w.handle.clone(), w.next_ping_id)
+                })
+                .collect()
+        };
+
+        for (handle, ping_id) in pings {
+            let watched = watched.clone();
+            let errors = errors.clone();
+            let max_missed = config.max_missed;
+            tokio::spawn(async move {
+                let sent_at = Instant::now();
+                let result = ping(&handle, ping_id).await;
+                record_ping(&watched, &handle, result.map(|_| sent_at.elapsed()), max_missed, &errors);
+            });
+        }
+    }
+}
+
+async fn ping(handle: &ProcessHandle, ping_id: u64) -> Result<()> {
+    trace!("Sending keepalive {} to {}", ping_id, handle.get_path().display());
+    let response = handle.request(PluginInstructionType::Keepalive, KeepaliveInstruction { id: ping_id }).await?;
+    if response.instruction_type != CoreInstructionType::KeepaliveResponse {
+        return Err(anyhow!("Expected KeepaliveResponse, got {}", response.instruction_type));
+    }
+    let payload = response.payload.decode::<KeepaliveInstruction>()?;
+    if payload.id != ping_id {
+        return Err(anyhow!("Expected keepalive ID {}, got {}", ping_id, payload.id));
+    }
+    Ok(())
+}
+
+fn record_ping(watched: &WatchedProcesses, handle: &ProcessHandle, result: Result<Duration>,
+    max_missed: u32, errors: &PluginErrorSender)
+{
+    let path = handle.get_path();
+    let mut lock = watched.lock().unwrap();
+    let w = match lock.get_mut(path) {
+        // A restarted plugin is watched under the same path, but its pings start over.
+        Some(w) if w.handle.get_instance_id() == handle.get_instance_id() => w,
+        _ => return, // No longer watched
+    };
+    w.ping_in_flight = false;
+
+    match result {
+        Ok(rtt) => {
+            trace!("Keepalive response from {} after {:?}", path.display(), rtt);
+            if w.stats.hung {
+                debug!("Process {} is responding again", path.display());
+            }
+            w.stats = KeepaliveStats { last_rtt: Some(rtt), missed: 0, hung: false };
+        },
+        Err(e) => {
+            w.stats.missed += 1;
+            warn!("Process {} missed keepalive ({} in a row): {}", path.display(), w.stats.missed, e);
+            if w.stats.missed == max_missed {
+                w.stats.hung = true;
+                let err = PluginError::Unresponsive {
+                    process_path: path.to_path_buf(),
+                    instance_id: handle.get_instance_id(),
+                    missed: w.stats.missed,
+                };
+                warn!("{}", err);
+                let _ = errors.send(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        core::socket_handler::SocketHandler,
+        process_management::{process::{Process, ProcessConfig}, request_tracker::RequestTimeouts}
+    };
+    use claims::{assert_ok, assert_some};
+    use test_log::test;
+    use tokio::sync::mpsc;
+
+    #[cfg(target_os = "windows")]
+    const TEST_PROGRAM: &str = "calc.exe";
+    #[cfg(not(target_os = "windows"))]
+    const TEST_PROGRAM: &str = "yes";
+
+    /// The test program never connects to the socket, so it can never respond.
+    #[test(tokio::test)]
+    async fn test_unresponsive_process_is_hung() {
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let config = ProcessConfig {
+            request_timeouts: RequestTimeouts { default: Duration::from_millis(20), per_type: HashMap::new() },
+            ..Default::default()
+        };
+        let socket = assert_ok!(SocketHandler::new("polychat-keepalive-hung-test"));
+        let proc = assert_ok!(Process::with_config(TEST_PROGRAM, socket, config, errors_tx.clone()));
+        let keepalive_config = KeepaliveConfig { interval: Duration::from_millis(10), max_missed: 2 };
+        let supervisor = KeepaliveSupervisor::start(keepalive_config, errors_tx);
+        supervisor.watch(proc.get_handle());
+
+        loop {
+            match assert_some!(errors_rx.recv().await) {
+                PluginError::RequestTimedOut { instruction_type, .. } => {
+                    assert_eq!(PluginInstructionType::Keepalive, instruction_type);
+                },
+                PluginError::Unresponsive { missed, .. } => {
+                    assert_eq!(2, missed);
+                    break;
+                },
+                other => panic!("Unexpected error {}", other),
+            }
+        }
+        let stats = assert_some!(supervisor.get_stats(proc.get_path()));
+        assert!(stats.hung);
+        assert_eq!(None, stats.last_rtt);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{fmt::Display, path::PathBuf, sync::{Arc, Mutex}};
+
+use log::debug;
+use tokio::sync::watch;
+
+/// Where a plugin process is in its lifecycle.
+/// A process mostly moves through these in the order they are declared, but
+/// can recover from [ProcessState::Degraded]. See [ProcessState::can_move_to].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// Started, but not yet connected to its socket.
+    Spawned,
+    /// Connected to its socket, but has not sent an accepted Init.
+    Connected,
+    /// Its Init was accepted, and it's ready for instructions.
+    Initialized,
+    /// A request to it timed out under the
+    /// [TimeoutPolicy::MarkDegraded](super::request_tracker::TimeoutPolicy::MarkDegraded) policy,
+    /// and it hasn't responded to one since.
+    Degraded,
+    /// Exited on its own.
+    Crashed,
+    /// Stopped by the core, or exited after being rejected or disconnected.
+    Stopped,
+}
+
+impl ProcessState {
+    /// Whether the process has exited, so its state can't change again.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, ProcessState::Crashed | ProcessState::Stopped)
+    }
+
+    /**
+     * Whether a process in this state can move to the given one:
+     * - Spawned, Connected and Initialized can only move forward.
+     * - Degraded can only be entered once connected, and recovers to
+     *   Connected or Initialized.
+     * - Any state that hasn't exited can exit, and exiting is final.
+     */
+    pub fn can_move_to(&self, next: ProcessState) -> bool {
+        use ProcessState::*;
+        match (self, next) {
+            (Crashed | Stopped, _) => false,
+            (_, Crashed | Stopped) => true,
+            (Spawned, Connected | Initialized) => true,
+            (Connected, Initialized | Degraded) => true,
+            (Initialized, Degraded) => true,
+            (Degraded, Connected | Initialized) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for ProcessState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessState::Spawned => write!(f, "Spawned"),
+            ProcessState::Connected => write!(f, "Connected"),
+            ProcessState::Initialized => write!(f, "Initialized"),
+            ProcessState::Degraded => write!(f, "Degraded"),
+            ProcessState::Crashed => write!(f, "Crashed"),
+            ProcessState::Stopped => write!(f, "Stopped"),
+        }
+    }
+}
+
+/// The current state of a single process, shared by every task that
+/// drives its transitions.
+#[derive(Debug, Clone)]
+pub(crate) struct StateTracker {
+    process_path: PathBuf,
+    state: Arc<watch::Sender<ProcessState>>,
+    /// The state to go back to when recovering from [ProcessState::Degraded].
+    before_degraded: Arc<Mutex<ProcessState>>,
+}
+
+impl StateTracker {
+    pub fn new(process_path: PathBuf) -> Self {
+        let (state, _) = watch::channel(ProcessState::Spawned);
+        StateTracker {
+            process_path,
+            state: Arc::new(state),
+            before_degraded: Arc::new(Mutex::new(ProcessState::Connected)),
+        }
+    }
+
+    /**
+     * Moves the process to a new state, if [ProcessState::can_move_to] allows it.
+     *
+     * # Returns
+     * Whether the state changed.
+     */
+    pub fn advance(&self, new_state: ProcessState) -> bool {
+        self.state.send_if_modified(|state| {
+            if !state.can_move_to(new_state) {
+                return false;
+            }
+            debug!("Process {} is now {} (was {})", self.process_path.display(), new_state, state);
+            if new_state == ProcessState::Degraded {
+                *self.before_degraded.lock().unwrap() = *state;
+            }
+            *state = new_state;
+            true
+        })
+    }
+
+    /**
+     * Moves a [ProcessState::Degraded] process back to the state it was in
+     * before, such as after it responded to a request.
+     *
+     * # Returns
+     * Whether the state changed.
+     */
+    pub fn recover(&self) -> bool {
+        if self.get() != ProcessState::Degraded {
+            return false;
+        }
+        let previous = *self.before_degraded.lock().unwrap();
+        self.state.send_if_modified(|state| {
+            // It may have exited or been initialized in the meantime.
+            if *state != ProcessState::Degraded {
+                return false;
+            }
+            debug!("Process {} recovered, and is {} again", self.process_path.display(), previous);
+            *state = previous;
+            true
+        })
+    }
+
+    pub fn get(&self) -> ProcessState {
+        *self.state.borrow()
+    }
+
+    /// Whether the process was initialized, even if it's degraded since.
+    pub fn is_initialized(&self) -> bool {
+        match self.get() {
+            ProcessState::Initialized => true,
+            ProcessState::Degraded => *self.before_degraded.lock().unwrap() == ProcessState::Initialized,
+            _ => false
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ProcessState> {
+        self.state.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_state_only_moves_forward() {
+        let state = StateTracker::new(PathBuf::from("test"));
+        assert_eq!(ProcessState::Spawned, state.get());
+        // Nothing can time out before it connects.
+        assert!(!state.advance(ProcessState::Degraded));
+        assert!(state.advance(ProcessState::Initialized));
+        assert!(!state.advance(ProcessState::Connected));
+        assert!(!state.advance(ProcessState::Initialized));
+        assert_eq!(ProcessState::Initialized, state.get());
+        assert!(state.advance(ProcessState::Degraded));
+        assert!(state.advance(ProcessState::Crashed));
+        // Nothing comes after exiting.
+        assert!(!state.advance(ProcessState::Stopped));
+        assert!(!state.recover());
+        assert_eq!(ProcessState::Crashed, state.get());
+    }
+
+    #[test]
+    fn test_degraded_recovers_to_previous_state() {
+        let state = StateTracker::new(PathBuf::from("test"));
+        assert!(!state.recover());
+        assert!(state.advance(ProcessState::Connected));
+        assert!(state.advance(ProcessState::Degraded));
+        assert!(state.recover());
+        assert_eq!(ProcessState::Connected, state.get());
+
+        // Being initialized while degraded also counts as recovering.
+        assert!(state.advance(ProcessState::Degraded));
+        assert!(state.advance(ProcessState::Initialized));
+        assert!(state.advance(ProcessState::Degraded));
+        assert!(state.recover());
+        assert_eq!(ProcessState::Initialized, state.get());
+        assert!(!state.recover());
+    }
+
+    #[test(tokio::test)]
+    async fn test_subscribers_see_changes() {
+        let state = StateTracker::new(PathBuf::from("test"));
+        let mut rx = state.subscribe();
+        state.advance(ProcessState::Connected);
+        assert!(rx.changed().await.is_ok());
+        assert_eq!(ProcessState::Connected, *rx.borrow());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf}
+};
+
+use log::{debug, error};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    api::schema::protocol::Version,
+    process_management::error::ProcessManagerError
+};
+
+/// The name of the manifest file that every plugin directory must contain.
+pub const MANIFEST_FILE_NAME: &str = "plugin.json";
+
+/// The range of core API versions that a plugin supports, inclusive.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ApiVersionRange {
+    pub min: Version,
+    pub max: Version,
+}
+
+impl ApiVersionRange {
+    pub fn contains(&self, version: &Version) -> bool {
+        self.min <= *version && *version <= self.max
+    }
+
+    /**
+     * Whether a plugin written for any API version in this range can be used
+     * by a core that implements the given API version.
+     * See [Version::is_compatible_with].
+     */
+    pub fn is_compatible_with(&self, core_api_version: &Version) -> bool {
+        // The oldest version in the range with the core's major version needs the least from it.
+        let oldest = match self.min.major == core_api_version.major {
+            true => self.min,
+            false => Version { major: core_api_version.major, minor: 0, patch: 0 },
+        };
+        self.contains(&oldest) && oldest.is_compatible_with(core_api_version)
+    }
+}
+
+/// Describes a plugin and how to start it.
+/// Read from the [MANIFEST_FILE_NAME] file in the plugin's directory.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PluginManifest {
+    /// A human readable name for the plugin.
+    pub name: String,
+    /// Uniquely identifies the plugin, regardless of its version.
+    pub id: String,
+    pub version: Version,
+    /// The plugin's executable, relative to the plugin directory in the file.
+    /// Once loaded with [PluginManifest::from_dir], this is the full path.
+    pub executable: PathBuf,
+    pub api_version: ApiVersionRange,
+    /// Passed to the executable after the socket name.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Added to the environment the executable inherits from the core.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+impl PluginManifest {
+    /**
+     * Reads and validates the manifest in a plugin directory.
+     *
+     * # Arguments
+     * ## dir
+     * The plugin directory, which contains the [MANIFEST_FILE_NAME] file.
+     *
+     * # Returns
+     * The manifest, with [PluginManifest::executable] resolved against the plugin directory.
+     *
+     * A [ProcessManagerError] describing why the manifest could not be used on failure.
+     */
+    pub fn from_dir(dir: &Path) -> Result<PluginManifest, ProcessManagerError> {
+        let path = dir.join(MANIFEST_FILE_NAME);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let err = ProcessManagerError::MissingManifest(dir.to_path_buf());
+                error!("{}", err);
+                return Err(err);
+            },
+            Err(source) => {
+                let err = ProcessManagerError::UnreadableManifest { path, source };
+                error!("{}", err);
+                return Err(err);
+            }
+        };
+        let mut manifest: PluginManifest = match serde_json::from_str(&contents) {
+            Ok(manifest) => manifest,
+            Err(source) => {
+                let err = ProcessManagerError::MalformedManifest { path, source };
+                error!("{}", err);
+                return Err(err);
+            }
+        };
+        if let Err(reason) = manifest.validate(dir) {
+            let err = ProcessManagerError::InvalidManifest { path, reason };
+            error!("{}", err);
+            return Err(err);
+        }
+        manifest.executable = dir.join(&manifest.executable);
+        debug!("Loaded manifest for {} {} from {}", manifest.id, manifest.version, path.display());
+        Ok(manifest)
+    }
+
+    /// Checks the parts of the manifest that can't be checked while parsing it.
+    fn validate(&self, dir: &Path) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name is empty".to_string());
+        }
+        if self.id.trim().is_empty() {
+            return Err("id is empty".to_string());
+        }
+        if self.api_version.min > self.api_version.max {
+            return Err(format!(
+                "api_version min {} is greater than max {}", self.api_version.min, self.api_version.max
+            ));
+        }
+        // Plugins can only start executables from their own directory.
+        let stays_in_dir = self.executable.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if self.executable.as_os_str().is_empty() || !stays_in_dir {
+            return Err(format!("executable '{}' is not a path within the plugin directory", self.executable.display()));
+        }
+        if !dir.join(&self.executable).is_file() {
+            return Err(format!("executable '{}' does not exist", self.executable.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process_management::error::ProcessManagerError;
+    use claims::{assert_err, assert_ok};
+    use serde_json::json;
+    use testdir::testdir;
+    use test_log::test;
+
+    fn write_plugin_dir(dir: &Path, manifest: serde_json::Value) {
+        assert_ok!(std::fs::write(dir.join("plugin"), ""));
+        assert_ok!(std::fs::write(dir.join(MANIFEST_FILE_NAME), manifest.to_string()));
+    }
+
+    fn valid_manifest() -> serde_json::Value {
+        json!({
+            "name": "Test Plugin",
+            "id": "test-plugin",
+            "version": { "major": 1, "minor": 2, "patch": 3 },
+            "executable": "plugin",
+            "api_version": {
+                "min": { "major": 0, "minor": 1, "patch": 0 },
+                "max": { "major": 0, "minor": 3, "patch": 0 }
+            }
+        })
+    }
+
+    #[test]
+    fn test_valid_manifest() {
+        let dir = testdir!();
+        let mut manifest = valid_manifest();
+        manifest["args"] = json!(["--verbose"]);
+        manifest["env"] = json!({ "TEST_VAR": "1" });
+        write_plugin_dir(&dir, manifest);
+
+        let manifest = assert_ok!(PluginManifest::from_dir(&dir));
+        assert_eq!("test-plugin", manifest.id);
+        assert_eq!(Version { major: 1, minor: 2, patch: 3 }, manifest.version);
+        assert_eq!(dir.join("plugin"), manifest.executable);
+        assert_eq!(vec!["--verbose".to_string()], manifest.args);
+        assert_eq!(Some(&"1".to_string()), manifest.env.get("TEST_VAR"));
+        assert!(manifest.api_version.contains(&Version { major: 0, minor: 2, patch: 7 }));
+        assert!(!manifest.api_version.contains(&Version { major: 0, minor: 3, patch: 1 }));
+    }
+
+    #[test]
+    fn test_api_version_range_compatibility() {
+        let range = ApiVersionRange {
+            min: Version { major: 0, minor: 2, patch: 0 },
+            max: Version { major: 1, minor: 4, patch: 0 },
+        };
+        assert!(range.is_compatible_with(&Version { major: 0, minor: 2, patch: 0 }));
+        assert!(range.is_compatible_with(&Version { major: 0, minor: 9, patch: 0 }));
+        assert!(range.is_compatible_with(&Version { major: 1, minor: 0, patch: 0 }));
+        // Needs more than the core has.
+        assert!(!range.is_compatible_with(&Version { major: 0, minor: 1, patch: 0 }));
+        // Outside of the range.
+        assert!(!range.is_compatible_with(&Version { major: 2, minor: 0, patch: 0 }));
+    }
+
+    #[test]
+    fn test_missing_manifest() {
+        let dir = testdir!();
+        let err = assert_err!(PluginManifest::from_dir(&dir));
+        assert!(matches!(err, ProcessManagerError::MissingManifest(_)), "Unexpected error {}", err);
+    }
+
+    #[test]
+    fn test_malformed_manifest() {
+        let dir = testdir!();
+        let mut manifest = valid_manifest();
+        manifest.as_object_mut().unwrap().remove("version");
+        write_plugin_dir(&dir, manifest);
+
+        let err = assert_err!(PluginManifest::from_dir(&dir));
+        assert!(matches!(err, ProcessManagerError::MalformedManifest { .. }), "Unexpected error {}", err);
+    }
+
+    #[test]
+    fn test_invalid_manifests() {
+        let mut backwards_range = valid_manifest();
+        backwards_range["api_version"]["min"]["major"] = json!(1);
+        let mut escaping_executable = valid_manifest();
+        escaping_executable["executable"] = json!("../plugin");
+        let mut missing_executable = valid_manifest();
+        missing_executable["executable"] = json!("not_there");
+        let mut empty_id = valid_manifest();
+        empty_id["id"] = json!(" ");
+
+        for manifest in [backwards_range, escaping_executable, missing_executable, empty_id] {
+            let dir = testdir!();
+            write_plugin_dir(&dir, manifest);
+            let err = assert_err!(PluginManifest::from_dir(&dir));
+            assert!(matches!(err, ProcessManagerError::InvalidManifest { .. }), "Unexpected error {}", err);
+        }
+    }
+}
@@ -1,3 +1,8 @@
 pub mod process;
+pub mod lifecycle;
 pub mod process_manager;
-mod error;
\ No newline at end of file
+pub mod manifest;
+pub mod request_tracker;
+pub mod keepalive;
+pub mod output;
+pub mod error;
@@ -0,0 +1,182 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex}
+};
+use log::{log, warn, Level, LevelFilter};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+use crate::api::schema::log::LogInstruction;
+
+/// The log target that plugin output is logged under.
+pub const PLUGIN_OUTPUT_TARGET: &str = "polychat_plugin";
+
+/// Which of a plugin's streams a line of output came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line a plugin wrote to stdout or stderr.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// How a plugin's stdout and stderr are logged and kept.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// The level stdout lines are logged at. Off to not log them.
+    /// Plugins can print a lot, so this defaults to debug.
+    pub stdout_level: LevelFilter,
+    /// The level stderr lines are logged at. Off to not log them.
+    pub stderr_level: LevelFilter,
+    /// How many of the most recent lines are kept per process.
+    pub buffer_lines: usize,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            stdout_level: LevelFilter::Debug,
+            stderr_level: LevelFilter::Warn,
+            buffer_lines: 200,
+        }
+    }
+}
+
+impl OutputConfig {
+    fn level_for(&self, stream: OutputStream) -> LevelFilter {
+        match stream {
+            OutputStream::Stdout => self.stdout_level,
+            OutputStream::Stderr => self.stderr_level,
+        }
+    }
+}
+
+/// A ring buffer of the most recent lines of output from a process.
+#[derive(Debug, Clone)]
+pub(crate) struct OutputBuffer {
+    lines: Arc<Mutex<VecDeque<OutputLine>>>,
+    capacity: usize,
+}
+
+impl OutputBuffer {
+    pub fn new(capacity: usize) -> Self {
+        OutputBuffer {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, line: OutputLine) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns every buffered line, oldest first.
+    pub fn get_lines(&self) -> Vec<OutputLine> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns up to `count` of the most recent lines from one stream, oldest first.
+    pub fn get_last_lines(&self, stream: OutputStream, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        let mut last: Vec<String> = lines.iter().rev()
+            .filter(|l| l.stream == stream)
+            .take(count)
+            .map(|l| l.line.clone())
+            .collect();
+        last.reverse();
+        last
+    }
+}
+
+/**
+ * Reads one of a process's output streams line by line until it closes,
+ * logging each line and keeping it in the buffer.
+ * 
+ * # Arguments
+ * ## tag
+ * Identifies the process in the log, such as its name and PID.
+ */
+pub(crate) async fn forward_output<R: AsyncRead + Unpin>(reader: R, stream: OutputStream, tag: String,
+    config: OutputConfig, buffer: OutputBuffer)
+{
+    let mut lines = BufReader::new(reader).lines();
+    let level = config.level_for(stream);
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if let Some(level) = level.to_level() {
+                    log!(target: PLUGIN_OUTPUT_TARGET, level, "[{}] {}", tag, line);
+                }
+                buffer.push(OutputLine { stream, line });
+            },
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Could not read {:?} of {}: {}", stream, tag, e);
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Writes a record the plugin sent with a Log instruction to the core's log.
+ * It is logged under [PLUGIN_OUTPUT_TARGET], followed by the plugin's own target.
+ */
+pub(crate) fn forward_log_record(record: &LogInstruction, tag: &str) {
+    let target = format!("{}::{}", PLUGIN_OUTPUT_TARGET, record.target);
+    let fields: String = record.fields.iter().map(|(k, v)| format!(" {}={}", k, v)).collect();
+    log!(target: &target, Level::from(record.level), "[{}] {}{}", tag, record.message, fields);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    fn line(stream: OutputStream, line: &str) -> OutputLine {
+        OutputLine { stream, line: line.to_string() }
+    }
+
+    #[test]
+    fn test_buffer_keeps_most_recent_lines() {
+        let buffer = OutputBuffer::new(2);
+        buffer.push(line(OutputStream::Stdout, "1"));
+        buffer.push(line(OutputStream::Stderr, "2"));
+        buffer.push(line(OutputStream::Stdout, "3"));
+
+        assert_eq!(vec![line(OutputStream::Stderr, "2"), line(OutputStream::Stdout, "3")], buffer.get_lines());
+    }
+
+    #[test]
+    fn test_last_lines_of_one_stream() {
+        let buffer = OutputBuffer::new(10);
+        for (stream, text) in [(OutputStream::Stderr, "a"), (OutputStream::Stdout, "b"),
+            (OutputStream::Stderr, "c"), (OutputStream::Stderr, "d")]
+        {
+            buffer.push(line(stream, text));
+        }
+
+        assert_eq!(vec!["c", "d"], buffer.get_last_lines(OutputStream::Stderr, 2));
+        assert_eq!(vec!["b"], buffer.get_last_lines(OutputStream::Stdout, 2));
+    }
+
+    #[test(tokio::test)]
+    async fn test_forward_output_reads_every_line() {
+        let buffer = OutputBuffer::new(10);
+        let output: &[u8] = b"first\nsecond\n";
+        forward_output(output, OutputStream::Stdout, "test".to_string(), OutputConfig::default(), buffer.clone()).await;
+
+        assert_eq!(vec!["first", "second"], buffer.get_last_lines(OutputStream::Stdout, 10));
+    }
+}
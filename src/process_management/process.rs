@@ -1,49 +1,302 @@
 use crate::{
-    core::socket_handler::SocketHandler,
-    api::schema::instructions::{DeserializableCoreInstr, SerializablePluginInstr}
+    core::{socket_handler::{SocketReader, SocketWriter}, transport::PluginTransport},
+    utils::{auth::AuthToken, socket::SocketError},
+    api::schema::{
+        instructions::{
+            DeserializableCoreInstr, SerializablePluginInstr, PluginInstructionType, CoreInstructionType, RequestId
+        },
+        log::LogInstruction,
+        protocol::{
+            InitDataInstruction, InitAckInstruction, InitRejectedInstruction, Framing, Encoding,
+            AUTH_TOKEN_ENV, CORE_API_VERSION, DEFAULT_MAX_FRAME_SIZE
+        },
+        shutdown::{ShutdownInstruction, ShutdownReason}
+    },
+    process_management::{
+        request_tracker::{RequestTracker, RequestTimeouts, TimeoutPolicy, PluginErrorSender, Responder},
+        keepalive::KeepaliveConfig,
+        lifecycle::{ProcessState, StateTracker},
+        process_manager::RestartPolicy,
+        manifest::PluginManifest,
+        error::{PluginError, ExitReason},
+        output::{OutputConfig, OutputBuffer, OutputLine, OutputStream, forward_output, forward_log_record}
+    }
 };
 
 use std::{
-    process::{Child, Command, Stdio},
-    fmt::Debug, path::PathBuf,
-    sync::Arc, time::Duration,
-    thread::sleep
+    process::Stdio,
+    fmt::{Debug, Display}, path::{Path, PathBuf},
+    sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}}, time::{Duration, Instant},
 };
-use log::{warn, debug, error, trace};
+use log::{warn, debug, error};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use serde::Serialize;
-use tokio::{task::JoinHandle, sync::{ Mutex, mpsc::{self, Receiver, Sender}}, time::timeout};
+use tokio::{
+    task::JoinHandle,
+    sync::{ Mutex, oneshot, watch, mpsc::{self, Receiver, Sender}},
+    time::timeout,
+    process::{Child, Command}
+};
+
+/// How many of the most recent stderr lines are included in crash reports.
+const CRASH_REPORT_LINES: usize = 20;
+
+/// Settings for how the core supervises a plugin process.
+#[derive(Debug, Clone)]
+pub struct ProcessConfig {
+    pub request_timeouts: RequestTimeouts,
+    /// Applies to both timed out requests and hung plugins.
+    pub timeout_policy: TimeoutPolicy,
+    pub keepalive: KeepaliveConfig,
+    pub restart: RestartPolicy,
+    pub output: OutputConfig,
+    /// How long a plugin has after starting to send an Init that is accepted.
+    /// If it doesn't, it failed to load.
+    pub init_timeout: Duration,
+    pub shutdown: ShutdownConfig,
+    pub limits: MessageLimits,
+}
+
+/// Limits on what a plugin can send the core, so that a buggy or hostile
+/// plugin can't exhaust its memory. A plugin that goes over them is disconnected.
+#[derive(Debug, Clone)]
+pub struct MessageLimits {
+    /// The largest message the plugin can send, in bytes, whatever the framing.
+    /// Plugins are told it in their InitAck.
+    pub max_message_size: u32,
+    /// How many messages that can't be decoded the plugin can send before it's disconnected.
+    pub max_malformed_messages: u32,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            max_malformed_messages: 10,
+        }
+    }
+}
+
+/// How long a plugin has to exit once it's asked to shut down.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// How long it has after being sent a Shutdown instruction, before it's sent SIGTERM.
+    /// Plugins that never connected can't be sent one, so they get SIGTERM right away.
+    pub grace: Duration,
+    /// How long it has after SIGTERM, before it's killed.
+    /// Windows has no SIGTERM, so plugins are killed as soon as the grace period passes.
+    pub terminate_grace: Duration,
+}
+
+impl ShutdownConfig {
+    /// Kills the plugin without waiting.
+    fn immediate() -> Self {
+        ShutdownConfig { grace: Duration::ZERO, terminate_grace: Duration::ZERO }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            grace: Duration::from_secs(5),
+            terminate_grace: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        ProcessConfig {
+            request_timeouts: RequestTimeouts::default(),
+            timeout_policy: TimeoutPolicy::default(),
+            keepalive: KeepaliveConfig::default(),
+            restart: RestartPolicy::default(),
+            output: OutputConfig::default(),
+            init_timeout: Duration::from_secs(10),
+            shutdown: ShutdownConfig::default(),
+            limits: MessageLimits::default(),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Process {
-    child: Child,
+    pid: Option<u32>,
     process_path: PathBuf,
+    started_at: Instant,
     core_read_thread: JoinHandle<()>,
-    socket: Arc<Mutex<SocketHandler>>,
-    rx: Receiver<DeserializableCoreInstr>
+    /// Tells the task that owns the child to stop it, rather than report its
+    /// exit as a crash.
+    stop_tx: Option<oneshot::Sender<ShutdownConfig>>,
+    shutdown_config: ShutdownConfig,
+    init: watch::Receiver<Option<Arc<InitDataInstruction>>>,
+    rx: Receiver<DeserializableCoreInstr>,
+    handle: ProcessHandle,
+    output: OutputBuffer,
+    /// Only set for processes started from a plugin directory.
+    manifest: Option<PluginManifest>,
+    #[cfg(any(test, feature = "test-util"))]
+    auth_token: AuthToken,
+}
+
+/// A cloneable handle that can send instructions to a [Process] from other
+/// tasks, such as the [KeepaliveSupervisor](super::keepalive::KeepaliveSupervisor).
+/// Identifies one run of a plugin, since a plugin that's restarted keeps its path.
+pub type InstanceId = u64;
+
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone)]
+pub struct ProcessHandle {
+    process_path: PathBuf,
+    instance_id: InstanceId,
+    /// Set once the plugin connects, and cleared when its connection is closed.
+    /// The read half is owned by the task reading from it, so sending never waits on a read.
+    writer: Arc<Mutex<Option<SocketWriter>>>,
+    requests: Arc<RequestTracker>,
+    next_request_id: Arc<AtomicU64>,
+    /// Set when the plugin's Init is rejected. Nothing more is sent to it after that.
+    rejected: Arc<AtomicBool>,
+    /// Set when the plugin is disconnected for going over its [MessageLimits].
+    disconnected: Arc<AtomicBool>,
+    /// Set once the core starts stopping the plugin, which may exit as soon as it's told to.
+    stopping: Arc<AtomicBool>,
+    /// How many messages from the plugin couldn't be decoded.
+    malformed_messages: Arc<AtomicU32>,
+    state: StateTracker,
 }
 
 impl Process {
-    pub fn new<T>(path: T, socket: SocketHandler) -> Result<Process> where PathBuf: From<T> {
-        let socket_name_arg = socket.get_socket_name().clone();
-        let path = PathBuf::from(path);
+    /// Starts a process with the default [ProcessConfig], that connects over the given transport,
+    /// such as a [SocketHandler](crate::core::socket_handler::SocketHandler).
+    /// Any [PluginError](super::error::PluginError) it raises is only logged.
+    pub fn new<T, S>(path: T, transport: S) -> Result<Process> where PathBuf: From<T>, S: Into<PluginTransport> {
+        let (errors, _) = mpsc::unbounded_channel();
+        Process::with_config(path, transport, ProcessConfig::default(), errors)
+    }
+
+    /**
+     * Starts a process that is supervised according to the given config.
+     * 
+     * # Arguments
+     * ## errors
+     * Where any [PluginError](super::error::PluginError) raised about this process is sent.
+     */
+    pub fn with_config<T, S>(path: T, transport: S, config: ProcessConfig, errors: PluginErrorSender)
+        -> Result<Process> where PathBuf: From<T>, S: Into<PluginTransport>
+    {
+        let mut command = Command::new(PathBuf::from(path));
+        Process::start(&mut command, None, transport.into(), config, errors)
+    }
+
+    /**
+     * Starts the plugin described by a manifest, with the manifest's
+     * arguments and environment, supervised according to the given config.
+     * 
+     * # Arguments
+     * ## errors
+     * Where any [PluginError](super::error::PluginError) raised about this process is sent.
+     */
+    pub fn with_manifest<S>(manifest: PluginManifest, transport: S, config: ProcessConfig, errors: PluginErrorSender)
+        -> Result<Process> where S: Into<PluginTransport>
+    {
+        let mut command = Command::new(&manifest.executable);
+        command.envs(&manifest.env);
+        Process::start(&mut command, Some(manifest), transport.into(), config, errors)
+    }
+
+    fn start(command: &mut Command, manifest: Option<PluginManifest>, mut transport: PluginTransport,
+        config: ProcessConfig, errors: PluginErrorSender) -> Result<Process>
+    {
+        let connection_arg = transport.get_plugin_arg();
+        let auth_token = AuthToken::generate();
+        let path = PathBuf::from(command.as_std().get_program());
         let (tx, rx) = mpsc::channel(100);
-        let socket = Arc::new(Mutex::new(socket));
-        let thrd_socket = socket.clone();
-        debug!("Starting process at {:?} with socket name argument {}", &path, &socket_name_arg);
+        let state = StateTracker::new(path.clone());
+        let instance_id = NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed);
+        let requests = Arc::new(RequestTracker::new(
+            path.clone(), instance_id, config.request_timeouts, config.timeout_policy, state.clone(), errors.clone()
+        ));
+        let handle = ProcessHandle {
+            process_path: path.clone(),
+            instance_id,
+            writer: Arc::new(Mutex::new(None)),
+            requests,
+            next_request_id: Arc::new(AtomicU64::new(1)),
+            rejected: Arc::new(AtomicBool::new(false)),
+            disconnected: Arc::new(AtomicBool::new(false)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            malformed_messages: Arc::new(AtomicU32::new(0)),
+            state,
+        };
+        let thrd_handle = handle.clone();
+        let (init_tx, init) = watch::channel(None);
+        debug!("Starting process at {:?} with connection argument {}", &path, &connection_arg);
+
+        let args = manifest.iter().flat_map(|m| m.args.iter());
+        transport.prepare_command(command);
+        let spawned = command
+            .arg(connection_arg)
+            .args(args)
+            // Set after the manifest's environment, so it can't be overridden.
+            .env(AUTH_TOKEN_ENV, auth_token.as_str())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // In case the runtime shuts down before the child can be stopped.
+            .kill_on_drop(true)
+            .spawn();
+        match spawned {
+            Ok(mut child) => {
+                transport.plugin_started();
+                let pid = child.id();
+                debug!("Successfully started process {:?} with PID {:?}", path, pid);
+                let output = OutputBuffer::new(config.output.buffer_lines);
+                let tag = match pid {
+                    Some(pid) => format!("{} ({})", path.display(), pid),
+                    None => path.display().to_string(),
+                };
+                let stdout_reader = child.stdout.take().map(|stdout| {
+                    tokio::spawn(forward_output(stdout, OutputStream::Stdout, tag.clone(), config.output.clone(), output.clone()))
+                });
+                let stderr_reader = child.stderr.take().map(|stderr| {
+                    let tag = tag.clone();
+                    tokio::spawn(forward_output(stderr, OutputStream::Stderr, tag, config.output.clone(), output.clone()))
+                });
+                let (stop_tx, stop_rx) = oneshot::channel();
+                let exit_handle = handle.clone();
+                let exit_output = output.clone();
+                let exit_errors = errors.clone();
+                tokio::spawn(async move {
+                    let readers = [stdout_reader, stderr_reader].into_iter().flatten().collect();
+                    watch_exit(child, stop_rx, exit_handle, readers, exit_output, exit_errors).await;
+                });
+                tokio::spawn(enforce_init_deadline(init.clone(), handle.clone(), config.init_timeout, errors.clone()));
+                let token = auth_token.clone();
+                let disconnect_grace = config.shutdown.grace;
 
-        match Command::new(&path).arg(socket_name_arg).stdout(Stdio::null()).spawn() {
-            Ok(child) => {
-                debug!("Successfully started process {:?} with PID {}", path, child.id());
                 Ok(Process {
-                    child,
+                    pid,
+                    started_at: Instant::now(),
+                    stop_tx: Some(stop_tx),
                     core_read_thread: tokio::spawn(async move {
-                        fetch_message_loop(thrd_socket, tx).await;
+                        // The transport is kept until the connection closes.
+                        let mut transport = transport;
+                        let connected = connect_plugin(&mut transport, &token, &thrd_handle, &config.limits,
+                            config.init_timeout, &tag).await;
+                        if let Some(reader) = connected {
+                            fetch_message_loop(reader, thrd_handle, tx, init_tx, config.limits, disconnect_grace, errors, tag).await;
+                        }
                     }),
+                    handle,
                     process_path: path,
                     rx,
-                    socket
+                    output,
+                    manifest,
+                    init,
+                    shutdown_config: config.shutdown,
+                    #[cfg(any(test, feature = "test-util"))]
+                    auth_token,
                 })
             },
             Err(e) => {
@@ -53,6 +306,9 @@ impl Process {
         }
     }
 
+    /// Returns the next instruction from the plugin that is not a response
+    /// to a request made with [Process::request].
+    /// Log instructions are written to the core's log instead of being returned.
     pub async fn get_next_instruction(&mut self) -> Result<Option<DeserializableCoreInstr>> {
         match self.rx.recv().await {
             Some(v) => Ok(Some(v)),
@@ -60,109 +316,693 @@ impl Process {
         }
     }
 
+    /// Sends an instruction to the plugin. The plugin's response will be
+    /// returned by [Process::get_next_instruction], and it is flagged as a
+    /// plugin error if it doesn't arrive in time.
     pub async fn send_instruction<P: Serialize + Debug>(&mut self, inst: &SerializablePluginInstr<P>) -> Result<()>{
-        debug!("Awaiting lock to send data across tasks");
-        let mut lock = self.socket.lock().await;
-        debug!("Lock acquired to send data across tasks");
-        lock.send_plugin_instruction(inst).await
+        self.handle.send_instruction(inst).await
+    }
+
+    /// Allocates a request ID that has not yet been used for this process.
+    pub fn next_request_id(&mut self) -> RequestId {
+        self.handle.next_request_id()
+    }
+
+    /// See [ProcessHandle::request].
+    pub async fn request<P: Serialize + Debug>(&mut self, instruction_type: PluginInstructionType, payload: P)
+        -> Result<DeserializableCoreInstr>
+    {
+        self.handle.request(instruction_type, payload).await
+    }
+
+    /// Whether a request timed out under the [TimeoutPolicy::MarkDegraded] policy,
+    /// and the plugin hasn't responded to one since.
+    /// This is the same as being in the [ProcessState::Degraded] state.
+    pub fn is_degraded(&self) -> bool {
+        self.handle.requests.is_degraded()
+    }
+
+    /// Returns where the process is in its lifecycle.
+    pub fn get_state(&self) -> ProcessState {
+        self.handle.state.get()
+    }
+
+    /// Returns a receiver that sees every change to the process's [ProcessState].
+    pub fn subscribe_state(&self) -> watch::Receiver<ProcessState> {
+        self.handle.state.subscribe()
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.process_path
+    }
+
+    /// Whether the plugin's Init was rejected, which happens when its API
+    /// version is incompatible with the core's.
+    pub fn is_rejected(&self) -> bool {
+        self.handle.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Whether the plugin was disconnected for going over its [MessageLimits].
+    pub fn is_disconnected(&self) -> bool {
+        self.handle.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many messages from the plugin couldn't be decoded.
+    pub fn get_malformed_count(&self) -> u32 {
+        self.handle.malformed_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the data the plugin sent in its Init instruction, if it was accepted.
+    pub fn get_init_data(&self) -> Option<Arc<InitDataInstruction>> {
+        self.init.borrow().clone()
+    }
+
+    /// Returns a receiver that sees the plugin's Init data once it arrives.
+    pub fn subscribe_init(&self) -> watch::Receiver<Option<Arc<InitDataInstruction>>> {
+        self.init.clone()
+    }
+
+    /**
+     * Stops the process gracefully. This is not reported as a crash.
+     * 
+     * The plugin is sent a Shutdown instruction, and its connection is closed.
+     * If it doesn't exit within [ShutdownConfig::grace], it's sent SIGTERM,
+     * and if it still doesn't exit within [ShutdownConfig::terminate_grace], it's killed.
+     * 
+     * Dropping a Process does the same in the background.
+     * 
+     * # Arguments
+     * ## reason
+     * Passed on to the plugin.
+     */
+    pub async fn shutdown(mut self, reason: ShutdownReason) {
+        debug!("Shutting down process {} (PID {:?})", self.process_path.display(), self.pid);
+        let mut state = self.subscribe_state();
+        self.core_read_thread.abort();
+        if let Some(stop_tx) = self.stop_tx.take() {
+            request_shutdown(self.handle.clone(), stop_tx, reason, self.shutdown_config.clone()).await;
+        }
+        // The task that owns the child sets the final state once it's gone.
+        let _ = state.wait_for(ProcessState::is_terminal).await;
+    }
+
+    /// Returns the manifest the process was started from, if it was started from one.
+    pub fn get_manifest(&self) -> Option<&PluginManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Returns which run of the plugin this is. See [InstanceId].
+    pub fn get_instance_id(&self) -> InstanceId {
+        self.handle.instance_id
+    }
+
+    pub fn get_started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    /// Returns the most recent lines the plugin wrote to stdout and stderr, oldest first.
+    /// The number of lines kept is set by [OutputConfig::buffer_lines].
+    pub fn get_recent_output(&self) -> Vec<OutputLine> {
+        self.output.get_lines()
+    }
+
+    /// Returns a handle that can send instructions to this process from other tasks.
+    pub fn get_handle(&self) -> ProcessHandle {
+        self.handle.clone()
+    }
+
+    /// Returns the token the plugin has to present when it connects, which
+    /// it's passed in the [AUTH_TOKEN_ENV] environment variable.
+    /// Only needed to connect in the plugin's place, so it's only public with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn get_auth_token(&self) -> &str {
+        self.auth_token.as_str()
+    }
+}
+
+impl ProcessHandle {
+    /// Sends an instruction to the plugin. The plugin's response will be
+    /// returned by [Process::get_next_instruction], and it is flagged as a
+    /// plugin error if it doesn't arrive in time.
+    pub async fn send_instruction<P: Serialize + Debug>(&self, inst: &SerializablePluginInstr<P>) -> Result<()>{
+        self.send_tracked_instruction(inst, None).await
+    }
+
+    /// Allocates a request ID that has not yet been used for this process.
+    pub fn next_request_id(&self) -> RequestId {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /**
+     * Sends an instruction to the plugin under a newly allocated request ID,
+     * then waits for the core instruction that the plugin sends back with
+     * the same ID.
+     * 
+     * # Returns
+     * The plugin's response on success.
+     * 
+     * An error if the instruction could not be sent, or if the response did
+     * not arrive in time. Timeouts are a [PluginError::RequestTimedOut](super::error::PluginError::RequestTimedOut).
+     */
+    pub async fn request<P: Serialize + Debug>(&self, instruction_type: PluginInstructionType, payload: P)
+        -> Result<DeserializableCoreInstr>
+    {
+        let request_id = self.next_request_id();
+        let (resp_tx, mut resp_rx) = oneshot::channel();
+        let inst = SerializablePluginInstr { instruction_type, request_id, payload };
+        let resp = tokio::select! {
+            sent = self.send_tracked_instruction(&inst, Some(resp_tx)) => {
+                sent?;
+                debug!("Waiting for response to request {}", request_id);
+                resp_rx.await
+            },
+            // The deadline can pass before the plugin is even initialized to receive it.
+            resp = &mut resp_rx => resp
+        };
+        match resp {
+            Ok(resp) => Ok(resp?),
+            Err(_) => Err(anyhow!("Request {} was dropped before the plugin responded", request_id))
+        }
+    }
+
+    /// Returns which run of the plugin it's a handle to. See [InstanceId].
+    pub fn get_instance_id(&self) -> InstanceId {
+        self.instance_id
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.process_path
+    }
+
+    /// Whether the plugin was told to exit, by being rejected, disconnected or
+    /// stopped, so its exiting isn't a crash.
+    fn expects_exit(&self) -> bool {
+        self.rejected.load(Ordering::Relaxed) || self.disconnected.load(Ordering::Relaxed)
+            || self.stopping.load(Ordering::Relaxed)
+    }
+
+    async fn send_tracked_instruction<P: Serialize + Debug>(&self, inst: &SerializablePluginInstr<P>,
+        responder: Option<Responder>) -> Result<()>
+    {
+        if self.rejected.load(Ordering::Relaxed) {
+            return Err(anyhow!("Not sending {} to {}, since its Init was rejected",
+                inst.instruction_type, self.process_path.display()));
+        }
+        self.requests.track(inst.request_id, inst.instruction_type, responder);
+        let result = self.send_plugin_instruction(inst).await;
+        if result.is_err() {
+            self.requests.cancel(inst.request_id);
+        }
+        result
+    }
+
+    /**
+     * Sends an instruction to the plugin, first waiting for it to be initialized
+     * if it hasn't been yet, since plugins ignore everything before their InitAck.
+     * 
+     * # Returns
+     * An error if the instruction could not be sent, or if the plugin exited,
+     * was rejected or was disconnected before it could be.
+     */
+    async fn send_plugin_instruction<P: Serialize + Debug>(&self, inst: &SerializablePluginInstr<P>) -> Result<()> {
+        // Subscribed before checking the writer, so an Init accepted in between isn't missed.
+        let mut state = self.state.subscribe();
+        loop {
+            let current = *state.borrow_and_update();
+            match self.writer.lock().await.as_mut() {
+                Some(writer) if self.state.is_initialized() => return writer.send_plugin_instruction(inst).await,
+                None if current != ProcessState::Spawned => {
+                    return Err(anyhow!("{} is not connected", self.process_path.display()));
+                },
+                _ => {}
+            }
+            if current.is_terminal() || self.rejected.load(Ordering::Relaxed) {
+                return Err(anyhow!("{} was not initialized", self.process_path.display()));
+            }
+            debug!("Waiting for {} to be initialized", self.process_path.display());
+            if state.changed().await.is_err() {
+                return Err(anyhow!("{} is not connected", self.process_path.display()));
+            }
+        }
+    }
+
+    /// Sends an instruction to the plugin right away, whether it's initialized or not.
+    async fn send_plugin_instruction_now<P: Serialize + Debug>(&self, inst: &SerializablePluginInstr<P>)
+        -> Result<()>
+    {
+        match self.writer.lock().await.as_mut() {
+            Some(writer) => writer.send_plugin_instruction(inst).await,
+            None => Err(anyhow!("{} is not connected", self.process_path.display()))
+        }
     }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
-        let id = self.child.id();
+        debug!("Stopping process {} (PID {:?})", self.process_path.display(), self.pid);
         self.core_read_thread.abort();
-        // TODO: Handle the case where the process terminates early, either
-        // by checking if it's still running, or gracefully failing on kill.
-        // On Windows, if it terminates early, it's an access denied error.
-        match self.child.try_wait() {
-            Ok(Some(status)) => {
-                debug!("Process {} exited early: code {}", self.process_path.display(), status);
-                return;
-            },
-            Ok(None) => {}, // Process still running
-            Err(e) => {
-                error!("Error checking state of process {}, reason: {}", self.process_path.display(), e);
-                return;
+        if let Some(stop_tx) = self.stop_tx.take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let config = self.shutdown_config.clone();
+                    runtime.spawn(request_shutdown(self.handle.clone(), stop_tx, ShutdownReason::Requested, config));
+                },
+                // Without a runtime, there's no way to ask the plugin, so it's killed.
+                // If the watcher is gone, the process already exited.
+                Err(_) => { let _ = stop_tx.send(ShutdownConfig::immediate()); }
             }
-        };
+        }
+    }
+}
 
-        match self.child.kill() {
-            Err(e) => {
-                warn!("Could not kill process {}: {}", id, e);
-                #[cfg(test)]
-                assert!(false, "Error killing process {}: {}", id, e);
+/**
+ * Sends the plugin a Shutdown instruction and closes its connection, then
+ * tells the task that owns the child to wait for it to exit.
+ */
+async fn request_shutdown(handle: ProcessHandle, stop_tx: oneshot::Sender<ShutdownConfig>, reason: ShutdownReason,
+    mut config: ShutdownConfig)
+{
+    // Set first, since the plugin can exit as soon as it's sent Shutdown or its connection closes,
+    // before the task that owns the child is told to stop it.
+    handle.stopping.store(true, Ordering::Relaxed);
+    // A rejected or disconnected plugin was already told to exit.
+    let told_before = handle.rejected.load(Ordering::Relaxed) || handle.disconnected.load(Ordering::Relaxed);
+    let mut writer = handle.writer.lock().await;
+    let mut told = told_before;
+    if let Some(socket) = writer.as_mut().filter(|_| !told_before && !handle.state.get().is_terminal()) {
+        told = send_shutdown(&handle, socket, reason, config.grace).await;
+    }
+    // Closing the write half closes the connection, since the read half was dropped with the reader task.
+    debug!("Closing connection to {}", handle.process_path.display());
+    *writer = None;
+    drop(writer);
+    if !told {
+        config.grace = Duration::ZERO;
+    }
+    // If the watcher is gone, the process already exited.
+    let _ = stop_tx.send(config);
+}
+
+/**
+ * Tells the plugin to exit within the grace period.
+ *
+ * # Returns
+ * Whether it was sent.
+ */
+async fn send_shutdown(handle: &ProcessHandle, socket: &mut SocketWriter, reason: ShutdownReason, grace: Duration)
+    -> bool
+{
+    let inst = SerializablePluginInstr {
+        instruction_type: PluginInstructionType::Shutdown,
+        request_id: handle.next_request_id(),
+        payload: ShutdownInstruction { reason, deadline_ms: grace.as_millis() as u64 },
+    };
+    match timeout(grace, socket.send_plugin_instruction(&inst)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("Could not send Shutdown to {}: {}", handle.process_path.display(), e);
+            false
+        },
+        Err(_) => {
+            warn!("Timed out sending Shutdown to {}", handle.process_path.display());
+            false
+        }
+    }
+}
 
+/**
+ * Owns the child process until it exits or is told to stop.
+ * If it exits on its own, that's a crash, which is raised as a [PluginError::Crashed],
+ * unless it was rejected, disconnected or stopped and is expected to exit.
+ */
+async fn watch_exit(mut child: Child, stop_rx: oneshot::Receiver<ShutdownConfig>, handle: ProcessHandle,
+    output_readers: Vec<JoinHandle<()>>, output: OutputBuffer, errors: PluginErrorSender)
+{
+    let process_path = handle.process_path.clone();
+    tokio::select! {
+        status = child.wait() => {
+            let status = match status {
+                Ok(status) => status,
+                Err(e) => {
+                    error!("Error checking state of process {}, reason: {}", process_path.display(), e);
+                    return;
+                }
+            };
+            if handle.expects_exit() {
+                debug!("Process {}, which was told to exit, exited with {}", process_path.display(), status);
+                handle.state.advance(ProcessState::Stopped);
                 return;
-            },
-            Ok(()) => debug!("Successfully killed process {}", id)
-        };
+            }
+            handle.state.advance(ProcessState::Crashed);
+            // Let the readers catch up on whatever was written right before the exit.
+            for reader in output_readers {
+                let _ = timeout(Duration::from_millis(100), reader).await;
+            }
+            let err = PluginError::Crashed {
+                process_path,
+                instance_id: handle.instance_id,
+                reason: ExitReason::from(status),
+                stderr_tail: output.get_last_lines(OutputStream::Stderr, CRASH_REPORT_LINES),
+            };
+            warn!("{}", err);
+            let _ = errors.send(err);
+        },
+        config = stop_rx => {
+            stop_child(&mut child, config.unwrap_or_else(|_| ShutdownConfig::immediate())).await;
+            handle.state.advance(ProcessState::Stopped);
+        }
+    }
+}
 
-        match self.child.wait() {
-            Err(e) => {
-                warn!("Process {} did not exit: {}", id, e);
-                #[cfg(test)]
-                assert!(false, "Error closing process {}: {}", id, e);
-            },
-            Ok(code) => debug!("Process {} exited with code: {}", id, code)
-        };
+/// Waits for the child to exit, escalating to SIGTERM, then to killing it,
+/// as each grace period passes.
+async fn stop_child(child: &mut Child, config: ShutdownConfig) {
+    let id = child.id();
+    if let Ok(Ok(status)) = timeout(config.grace, child.wait()).await {
+        debug!("Process {:?} stopped with {}", id, status);
+        return;
+    }
+    #[cfg(unix)]
+    if let Some(pid) = id {
+        debug!("Sending SIGTERM to process {}", pid);
+        // The child hasn't been waited on, so the PID can't have been reused.
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        if let Ok(Ok(status)) = timeout(config.terminate_grace, child.wait()).await {
+            debug!("Process {} stopped with {} after SIGTERM", pid, status);
+            return;
+        }
+    }
+    match child.kill().await {
+        Ok(()) => debug!("Successfully killed process {:?}", id),
+        Err(e) => warn!("Could not kill process {:?}: {}", id, e)
     }
 }
 
-async fn fetch_message_loop(socket: Arc<Mutex<SocketHandler>>, tx: Sender<DeserializableCoreInstr>) {
-    let mut msg_buffer = Vec::new();
-    loop {
-        trace!("Attempting to aquire lock to SocketHandler");
-        match timeout(Duration::from_millis(16), socket.lock()).await {
-            Ok(mut lock) => {
-                // Receive data from socket
-                trace!("Getting data from SocketHandler");
-                match timeout(Duration::from_millis(16), lock.get_instruction()).await {
-                    Ok(v) => {
-                        match v {
-                            Ok(d) => msg_buffer.push(d),
-                            Err(e) => {
-                                warn!("Error obtaining next core instruction: {}", e);
-                            }
-                        }
-                    },
-                    Err(_) => {
-                        trace!("Receive timed out");
-                    }
-                };
+/// Raises a [PluginError::InitTimedOut] if the plugin doesn't send an
+/// accepted Init in time. Rejected plugins already had their Init answered,
+/// and disconnected ones already had an error raised.
+async fn enforce_init_deadline(mut init: watch::Receiver<Option<Arc<InitDataInstruction>>>, handle: ProcessHandle,
+    deadline: Duration, errors: PluginErrorSender)
+{
+    // The sender is dropped if the process stops before it sends Init.
+    if timeout(deadline, init.wait_for(Option::is_some)).await.is_ok() {
+        return;
+    }
+    if handle.expects_exit() || handle.state.get().is_terminal() {
+        return;
+    }
+    let err = PluginError::InitTimedOut {
+        process_path: handle.process_path.clone(),
+        instance_id: handle.instance_id,
+        timeout: deadline,
+    };
+    warn!("{}", err);
+    let _ = errors.send(err);
+}
+
+/**
+ * Checks that the plugin's API version is compatible with the core's, and
+ * tells the plugin whether it was accepted with InitAck or InitRejected.
+ * 
+ * # Arguments
+ * ## max_frame_size
+ * The largest message the plugin can send, which it's told in the InitAck.
+ * 
+ * # Returns
+ * The InitAck that was sent, if the plugin was accepted and it could be sent.
+ * The write half already uses the framing and encoding it names. If it wasn't accepted,
+ * it's marked as rejected, and a [PluginError::IncompatibleApi] is raised.
+ */
+async fn accept_init(handle: &ProcessHandle, init: &InitDataInstruction, max_frame_size: u32,
+    errors: &PluginErrorSender) -> Option<InitAckInstruction>
+{
+    let request_id = handle.next_request_id();
+    if init.api_version.is_compatible_with(&CORE_API_VERSION) {
+        let framing = Framing::negotiate(&init.framing);
+        let ack = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::InitAck,
+            request_id,
+            payload: InitAckInstruction {
+                core_api_version: CORE_API_VERSION,
+                framing,
+                max_frame_size,
+                encoding: Encoding::negotiate(&init.encodings, framing),
             },
-            Err(_) => {}
+        };
+        // Switched while still holding the writer, so nothing else is sent the old way after the InitAck.
+        let mut writer = handle.writer.lock().await;
+        match writer.as_mut() {
+            Some(writer) => {
+                if let Err(e) = writer.send_plugin_instruction(&ack).await {
+                    warn!("Could not send InitAck to {}: {}", handle.process_path.display(), e);
+                    return None;
+                }
+                writer.set_framing(ack.payload.framing, ack.payload.max_frame_size);
+                writer.set_encoding(ack.payload.encoding);
+            },
+            None => {
+                warn!("Could not send InitAck to {}, since it's disconnected", handle.process_path.display());
+                return None;
+            }
         }
+        return Some(ack.payload);
+    }
+
+    handle.rejected.store(true, Ordering::Relaxed);
+    let err = PluginError::IncompatibleApi {
+        process_path: handle.process_path.clone(),
+        instance_id: handle.instance_id,
+        api_version: init.api_version,
+        core_api_version: CORE_API_VERSION,
+    };
+    warn!("{}", err);
+    let rejection = SerializablePluginInstr {
+        instruction_type: PluginInstructionType::InitRejected,
+        request_id,
+        payload: InitRejectedInstruction { core_api_version: CORE_API_VERSION, reason: err.to_string() },
+    };
+    if let Err(e) = handle.send_plugin_instruction_now(&rejection).await {
+        warn!("Could not send InitRejected to {}: {}", handle.process_path.display(), e);
+    }
+    let _ = errors.send(err);
+    None
+}
 
-        if msg_buffer.len() > 0 {
-            let rx_data = msg_buffer.get(0).unwrap();
+/**
+ * Waits for the plugin to connect and present its auth token, then hands
+ * the write half of the connection to the [ProcessHandle]. A plugin that
+ * inherited its connection has until its init timeout to present the token,
+ * since it may take a while to start.
+ * 
+ * # Returns
+ * The read half, or None if a connection could not be accepted.
+ */
+async fn connect_plugin(transport: &mut PluginTransport, token: &AuthToken, handle: &ProcessHandle,
+    limits: &MessageLimits, init_timeout: Duration, tag: &str) -> Option<SocketReader>
+{
+    let (mut reader, writer) = match transport.accept_authenticated(token, init_timeout).await {
+        Ok(halves) => halves,
+        Err(e) => {
+            warn!("Could not accept a connection from {}: {}", tag, e);
+            return None;
+        }
+    };
+    reader.set_max_frame_size(limits.max_message_size);
+    // Set before the state changes, so senders waiting on the state find it.
+    *handle.writer.lock().await = Some(writer);
+    handle.state.advance(ProcessState::Connected);
+    Some(reader)
+}
 
-            // Sent to parent thread
-            match timeout(Duration::from_millis(16), tx.send(rx_data.clone())).await {
-                Err(e) => {
-                    error!("Could not send instruction {}", e);
-                }
+/**
+ * Owns the read half of the plugin's connection, and passes along each
+ * instruction as soon as it arrives, until the connection closes.
+ * 
+ * When the channel to the [Process] is full, this waits for room before
+ * reading more, so a core that isn't keeping up slows the plugin down
+ * instead of buffering without limit.
+ * 
+ * A plugin that goes over its [MessageLimits] is disconnected, and a
+ * [PluginError::MessageTooLarge] or [PluginError::MalformedMessages] is raised.
+ * Once the connection can't be read from, the write half is dropped too.
+ *
+ * # Arguments
+ * ## disconnect_grace
+ * How long a disconnected plugin is given to exit.
+ */
+#[allow(clippy::too_many_arguments)]
+async fn fetch_message_loop(mut reader: SocketReader, handle: ProcessHandle, tx: Sender<DeserializableCoreInstr>,
+    init_tx: watch::Sender<Option<Arc<InitDataInstruction>>>, limits: MessageLimits, disconnect_grace: Duration,
+    errors: PluginErrorSender, tag: String)
+{
+    loop {
+        let encoding = reader.get_encoding();
+        let data = match reader.get_data().await {
+            Ok(data) => data,
+            Err(e) => match e.downcast_ref::<SocketError>() {
+                Some(SocketError::Closed) => {
+                    debug!("{} closed the connection", tag);
+                    // Nothing can be sent over a closed connection, so senders fail right away.
+                    *handle.writer.lock().await = None;
+                    return;
+                },
+                Some(SocketError::FrameTooLarge { size, max_frame_size }) => {
+                    let err = PluginError::MessageTooLarge {
+                        process_path: handle.process_path.clone(),
+                        instance_id: handle.instance_id,
+                        size: *size,
+                        max_message_size: *max_frame_size,
+                    };
+                    disconnect(&handle, err, disconnect_grace, &errors).await;
+                    return;
+                },
                 _ => {
-                    trace!("Send successful");
-                    msg_buffer.remove(0);
+                    warn!("Could not read from {}: {}", tag, e);
+                    *handle.writer.lock().await = None;
+                    return;
+                }
+            }
+        };
+        let routed = match DeserializableCoreInstr::decode(data, encoding) {
+            Ok(instr) => route_instruction(&handle, &mut reader, instr, &init_tx, &limits, &errors, &tag).await,
+            Err(e) => count_malformed(&handle, &limits, "core instruction", e, &tag).map(|_| None)
+        };
+        match routed {
+            Ok(Some(instr)) => {
+                if tx.send(instr).await.is_err() {
+                    debug!("Nothing is receiving instructions from {}", tag);
+                    return;
                 }
-            };   
+            },
+            Ok(None) => {},
+            Err(err) => {
+                disconnect(&handle, err, disconnect_grace, &errors).await;
+                return;
+            }
         }
-        // TODO: Determine if the loop efficiently waits, and if so, remove this.
-        sleep(Duration::from_millis(16));
+    }
+}
+
+/**
+ * Counts a message from the plugin that couldn't be decoded.
+ *
+ * # Returns
+ * A [PluginError::MalformedMessages] if the plugin has now sent more than
+ * [MessageLimits::max_malformed_messages], and should be disconnected.
+ */
+fn count_malformed(handle: &ProcessHandle, limits: &MessageLimits, what: &str, e: impl Display, tag: &str)
+    -> std::result::Result<(), PluginError>
+{
+    let count = handle.malformed_messages.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!("Invalid {} from {} ({} malformed so far): {}", what, tag, count, e);
+    if count > limits.max_malformed_messages {
+        return Err(PluginError::MalformedMessages {
+            process_path: handle.process_path.clone(),
+            instance_id: handle.instance_id,
+            count,
+        });
+    }
+    Ok(())
+}
+
+/**
+ * Tells a plugin that went over its [MessageLimits] to shut down, closes its
+ * connection, and raises why. The read half is dropped by the caller, once
+ * this returns. The plugin should then exit, which isn't a crash.
+ */
+async fn disconnect(handle: &ProcessHandle, err: PluginError, grace: Duration, errors: &PluginErrorSender) {
+    warn!("{}", err);
+    handle.disconnected.store(true, Ordering::Relaxed);
+    let mut writer = handle.writer.lock().await;
+    if let Some(socket) = writer.as_mut() {
+        send_shutdown(handle, socket, ShutdownReason::ProtocolViolation, grace).await;
+    }
+    *writer = None;
+    drop(writer);
+    let _ = errors.send(err);
+}
+
+/**
+ * Handles the instructions that the core doesn't need to see, and routes
+ * responses to whoever made the request. Once an Init is accepted, the rest
+ * are read with the framing and encoding agreed on.
+ * 
+ * Log and Init payloads that can't be decoded count as malformed messages.
+ * Only the first Init is handled, while the plugin is connected.
+ *
+ * # Returns
+ * The instruction, if it should be passed along to the core.
+ *
+ * A [PluginError::MalformedMessages] if the plugin should be disconnected.
+ */
+async fn route_instruction(handle: &ProcessHandle, reader: &mut SocketReader, instr: DeserializableCoreInstr,
+    init_tx: &watch::Sender<Option<Arc<InitDataInstruction>>>, limits: &MessageLimits, errors: &PluginErrorSender,
+    tag: &str) -> std::result::Result<Option<DeserializableCoreInstr>, PluginError>
+{
+    match instr.instruction_type {
+        CoreInstructionType::Log => {
+            match instr.payload.decode::<LogInstruction>() {
+                Ok(record) => forward_log_record(&record, tag),
+                Err(e) => count_malformed(handle, limits, "data for instruction type Log", e, tag)?
+            }
+            Ok(None)
+        },
+        CoreInstructionType::Init
+            if handle.state.get() != ProcessState::Connected || handle.rejected.load(Ordering::Relaxed) =>
+        {
+            // Already accepted or rejected, so the framing and encoding stay as they are.
+            warn!("Ignoring another Init from {}, which is {}", tag, handle.state.get());
+            Ok(None)
+        },
+        CoreInstructionType::Init => {
+            match instr.payload.decode::<InitDataInstruction>() {
+                Ok(init) => {
+                    debug!("{} sent Init for {}", tag, init.protocol_data.protocol_service_name);
+                    let ack = match accept_init(handle, &init, limits.max_message_size, errors).await {
+                        Some(ack) => ack,
+                        None => return Ok(None)
+                    };
+                    reader.set_framing(ack.framing, ack.max_frame_size);
+                    reader.set_encoding(ack.encoding);
+                    handle.state.advance(ProcessState::Initialized);
+                    init_tx.send_replace(Some(Arc::new(init)));
+                },
+                Err(e) => count_malformed(handle, limits, "data for instruction type Init", e, tag)?
+            }
+            // Still passed on, since the core needs the rest of the data.
+            Ok(Some(instr))
+        },
+        _ if handle.rejected.load(Ordering::Relaxed) => {
+            debug!("Ignoring {} from rejected plugin {}", instr.instruction_type, tag);
+            Ok(None)
+        },
+        _ => Ok(handle.requests.resolve(instr))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{process_management::process::Process, core::socket_handler::SocketHandler};
-    use claims::assert_ok;
+    use crate::{
+        process_management::{
+            process::{Process, ProcessConfig},
+            error::{PluginError, ExitReason},
+            lifecycle::ProcessState
+        },
+        api::schema::shutdown::ShutdownReason,
+        core::socket_handler::SocketHandler
+    };
+    use claims::{assert_ok, assert_some};
+    use std::time::Duration;
     use test_log::test;
+    use tokio::{sync::mpsc, time::timeout};
 
     #[cfg(target_os = "windows")]
     const TEST_PROGRAM: &str = "calc.exe";
     #[cfg(not(target_os = "windows"))]
     const TEST_PROGRAM: &str = "yes";
+    /// Tries to run the socket as a shell script, which fails with an error on stderr.
+    #[cfg(not(target_os = "windows"))]
+    const CRASHING_PROGRAM: &str = "sh";
 
     #[test(tokio::test)]
     async fn test_loading_process() {
@@ -176,6 +1016,48 @@ mod test {
         drop(proc);
     }
 
+    #[test(tokio::test)]
+    async fn test_dropped_process_is_not_a_crash() {
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let socket = create_socket("polychat-drop-no-crash-test");
+        let proc = assert_ok!(Process::with_config(TEST_PROGRAM, socket, ProcessConfig::default(), errors_tx));
+
+        drop(proc);
+        // Either nothing is raised, or the channel closes once the process is cleaned up.
+        let raised = timeout(Duration::from_millis(100), errors_rx.recv()).await;
+        assert!(!matches!(raised, Ok(Some(_))), "Unexpected error {:?}", raised);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test(tokio::test)]
+    async fn test_crash_is_reported() {
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let socket = create_socket("polychat-crash-test");
+        let proc = assert_ok!(Process::with_config(CRASHING_PROGRAM, socket, ProcessConfig::default(), errors_tx));
+
+        match assert_some!(errors_rx.recv().await) {
+            PluginError::Crashed { process_path, instance_id, reason, stderr_tail } => {
+                assert_eq!(CRASHING_PROGRAM, process_path.to_str().unwrap());
+                assert_eq!(proc.get_instance_id(), instance_id);
+                assert!(matches!(reason, ExitReason::Code(code) if code != 0));
+                assert!(!stderr_tail.is_empty());
+            },
+            other => panic!("Unexpected error {}", other),
+        }
+        assert_eq!(ProcessState::Crashed, proc.get_state());
+    }
+
+    #[test(tokio::test)]
+    async fn test_shutdown_process_is_stopped() {
+        let proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket("polychat-shutdown-test")));
+        let state = proc.subscribe_state();
+        assert_eq!(ProcessState::Spawned, *state.borrow());
+
+        // It never connects, so it's sent SIGTERM right away.
+        assert_ok!(timeout(Duration::from_secs(5), proc.shutdown(ShutdownReason::Requested)).await);
+        assert_eq!(ProcessState::Stopped, *state.borrow());
+    }
+
     fn create_socket(name: &str) -> SocketHandler {
         let socket = SocketHandler::new(name);
         assert_ok!(socket, "Could not initialize SocketHandler")
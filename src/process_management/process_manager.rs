@@ -1,8 +1,9 @@
 use std::{
-    ffi::OsStr,
-    path::{PathBuf, Path}, str::FromStr
+    path::{PathBuf, Path}, str::FromStr,
+    collections::{BTreeSet, HashMap, VecDeque}, time::Duration, future, sync::Mutex
 };
-use log::{error, warn, debug};
+use futures::future::select_all;
+use log::{error, warn, info, debug};
 use walkdir::{DirEntry, WalkDir};
 use anyhow::Result;
 use rand::{
@@ -10,20 +11,94 @@ use rand::{
     thread_rng, Rng
 };
 
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
 use crate::{process_management::{
-    process::Process,
-    error::ProcessManagerError
-}, core::socket_handler::SocketHandler};
+    process::{Process, ProcessConfig},
+    error::{ProcessManagerError, PluginError},
+    request_tracker::TimeoutPolicy,
+    manifest::PluginManifest,
+    keepalive::{KeepaliveSupervisor, KeepaliveStats}
+}, core::{socket_handler::SocketHandler, transport::{PluginTransport, TransportKind}},
+    api::schema::{protocol::{Version, CORE_API_VERSION}, shutdown::ShutdownReason, instructions::DeserializableCoreInstr},
+    utils::socket::{default_socket_dir, prepare_socket_dir, remove_stale_sockets}};
+#[cfg(unix)]
+use crate::core::transport::SocketPair;
+
+/// How crashed plugins are restarted.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// How many times in a row a plugin is restarted before giving up.
+    pub max_restarts: u32,
+    /// The delay before the first restart. Each restart after that doubles it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// A plugin that runs at least this long before crashing is considered
+    /// to have recovered, and gets its full restart budget back.
+    pub reset_after: Duration,
+}
+
+impl RestartPolicy {
+    fn backoff(&self, restarts: u32) -> Duration {
+        let multiplier = 2u32.saturating_pow(restarts);
+        self.initial_backoff.saturating_mul(multiplier).min(self.max_backoff)
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
 
-#[cfg(target_os = "windows")]
-const EXEC_EXTENSION: &str = "exe";
-#[cfg(not(target_os = "windows"))]
-const EXEC_EXTENSION: &str = "";
+/// Something that happened to one of the plugins a [ProcessManager] loaded.
+#[derive(Debug)]
+pub enum ProcessEvent {
+    /// An instruction a plugin sent that isn't a response to a request.
+    Instruction { process_path: PathBuf, instruction: DeserializableCoreInstr },
+    /// An error raised by a plugin, after it was acted on according to the configured policies.
+    Error(PluginError),
+    /// The plugin loaded from the path was stopped, because it was restarted, crashed, failed to
+    /// load or was superseded. If it was restarted, the new process is already loaded.
+    Unloaded(PathBuf),
+}
 
 #[derive(Debug)]
 pub struct ProcessManager {
     dir: Option<PathBuf>,
     loaded_processes: Vec<Process>,
+    config: ProcessConfig,
+    errors_tx: UnboundedSender<PluginError>,
+    errors_rx: UnboundedReceiver<PluginError>,
+    /// Started along with the first process, since it needs a tokio runtime.
+    keepalive: Option<KeepaliveSupervisor>,
+    /// How many times each crashed plugin has been restarted in a row.
+    restart_counts: HashMap<PathBuf, u32>,
+    /// Plugins whose backoff has passed, and are ready to be restarted.
+    restarts_tx: UnboundedSender<PathBuf>,
+    restarts_rx: UnboundedReceiver<PathBuf>,
+    /// The manifests of loaded plugins, by executable path.
+    manifests: HashMap<PathBuf, PluginManifest>,
+    /// Plugins that have just sent Init.
+    inits_tx: UnboundedSender<PathBuf>,
+    inits_rx: UnboundedReceiver<PathBuf>,
+    /// How plugins connect to the core.
+    transport: TransportKind,
+    /// Where plugins' sockets are created, for [TransportKind::NamedSocket].
+    socket_dir: PathBuf,
+    /// Plugins that were unloaded since the last [ProcessManager::next_event].
+    unloaded: VecDeque<PathBuf>,
+}
+
+impl Default for ProcessManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProcessManager {
@@ -34,10 +109,39 @@ impl ProcessManager {
      * You can still load executables afterwards with load_executable.
      */
     pub fn new() -> ProcessManager {
-        ProcessManager {
+        Self::with_config(ProcessConfig::default())
+    }
+
+    /**
+     * Creates a new ProcessManager that supervises every process it loads
+     * according to the given config.
+     * Does not attempt to load any processes from executables, but does
+     * remove the sockets a previous core left behind in the [default_socket_dir],
+     * if that wasn't done since the core started.
+     */
+    pub fn with_config(config: ProcessConfig) -> ProcessManager {
+        let (errors_tx, errors_rx) = mpsc::unbounded_channel();
+        let (restarts_tx, restarts_rx) = mpsc::unbounded_channel();
+        let (inits_tx, inits_rx) = mpsc::unbounded_channel();
+        let manager = ProcessManager {
             dir: None,
             loaded_processes: vec![],
-        }
+            config,
+            errors_tx,
+            errors_rx,
+            keepalive: None,
+            restart_counts: HashMap::new(),
+            restarts_tx,
+            restarts_rx,
+            manifests: HashMap::new(),
+            inits_tx,
+            inits_rx,
+            transport: TransportKind::default(),
+            socket_dir: default_socket_dir(),
+            unloaded: VecDeque::new(),
+        };
+        clean_socket_dir(&manager.socket_dir);
+        manager
     }
 
     /**
@@ -45,16 +149,15 @@ impl ProcessManager {
      * 
      * # Required Arguments
      * ## path
-     * The absolute path to a directory containing a set of directories which contain executables
+     * The absolute path to a directory containing a set of plugin directories
      *
      * # Returns
      * A ProcessManager on success
      * 
      * A string slice describing the error on failure (check logs for more details)
      * 
-     * # Platform Dependent Behavior
-     * - Windows: Expects `.exe` to be the extension of the executables
-     * - Everything Else: Expects no extension on the executables
+     * Each plugin directory must have a manifest naming its executable.
+     * See [PluginManifest].
      */
     pub fn from_dir_str(path: &str) -> Result<ProcessManager> {
         ProcessManager::from_dir_path(PathBuf::from_str(path)?)
@@ -65,27 +168,59 @@ impl ProcessManager {
      * 
      * # Required Arguments
      * ## dir
-     * The absolute path to a directory containing a set of directories which contain executables
+     * The absolute path to a directory containing a set of plugin directories
      * 
      * # Returns
      * A ProcessManager on success
      * 
      * A string slice describing the error on failure (check logs for more details)
      * 
-     * # Platform Dependent Behavior
-     * - Windows: Expects `.exe` to be the extension of the executables
-     * - Everything Else: Expects no extension on the executables
+     * Each plugin directory must have a manifest naming its executable.
+     * See [PluginManifest].
      */
     pub fn from_dir_path(dir: PathBuf) -> Result<ProcessManager> {
         let mut manager = Self::new();
-        manager.dir = Some(dir);
+        manager.set_dir(dir);
         manager.load_processes()?;
         Ok(manager)
     }
 
+    /// Sets the directory that load_processes loads from.
+    pub fn set_dir(&mut self, dir: PathBuf) {
+        self.dir = Some(dir);
+    }
+
+    /**
+     * Sets the directory that plugins' sockets are created in, instead of the
+     * [default_socket_dir]. It's created if it doesn't exist yet, and must
+     * only be accessible by the current user. The sockets a previous core
+     * left behind in it are removed, the first time it's used since the core
+     * started. Plugins that are already loaded keep
+     * their sockets where they are. Only [TransportKind::NamedSocket] uses it.
+     */
+    pub fn set_socket_dir(&mut self, dir: PathBuf) {
+        clean_socket_dir(&dir);
+        self.socket_dir = dir;
+    }
+
+    pub fn get_socket_dir(&self) -> &Path {
+        &self.socket_dir
+    }
+
+    /**
+     * Sets how plugins started after this connect to the core. On Unix, the default is
+     * [TransportKind::SocketPair](TransportKind), so that no socket has to be named.
+     */
+    pub fn set_transport(&mut self, transport: TransportKind) {
+        self.transport = transport;
+    }
+
+    pub fn get_transport(&self) -> TransportKind {
+        self.transport
+    }
+
     pub fn load_processes(&mut self) -> Result<()> {
-        let dir: PathBuf;
-        match self.dir.clone() {
+        let dir = match self.dir.clone() {
             None => {
                 let err = ProcessManagerError::NoPath;
                 error!("{}", err);
@@ -93,14 +228,14 @@ impl ProcessManager {
             },
             Some(manager_dir) => {
                 check_directory(manager_dir.clone())?;
-                dir = manager_dir;
+                manager_dir
             }
-        }
+        };
 
-        let dir_walk = WalkDir::new(dir).max_depth(2).min_depth(2).follow_links(false);
+        let dir_walk = WalkDir::new(dir).max_depth(1).min_depth(1).follow_links(false);
 
-        for entry in dir_walk.into_iter().filter_entry(|e| expected_file(e)) {
-            let file = match entry {
+        for entry in dir_walk.into_iter().filter_entry(expected_dir) {
+            let plugin_dir = match entry {
                 Ok(e) => e,
                 Err(e) => {
                     warn!("Could not read directory entry: {}", e);
@@ -108,21 +243,78 @@ impl ProcessManager {
                 }
             };
 
-            debug!("Found executable: {}", file.path().display());
-            self.load_process(file.path())?;
+            debug!("Found plugin directory: {}", plugin_dir.path().display());
+            // One broken plugin shouldn't keep the others from loading. Why it failed is already logged.
+            if let Err(e) = self.load_plugin(plugin_dir.path()) {
+                warn!("Skipping plugin directory {}: {}", plugin_dir.path().display(), e);
+            }
         }
 
         Ok(())
     }
 
+    /**
+     * Loads the plugin in a plugin directory, as described by its manifest.
+     * 
+     * # Arguments
+     * ## dir
+     * The plugin directory, which contains a [MANIFEST_FILE_NAME](super::manifest::MANIFEST_FILE_NAME) file.
+     * 
+     * # Returns
+     * Nothing on success
+     * 
+     * A [ProcessManagerError] if the manifest is missing or can't be used,
+     * or an error if the executable could not be started. Plugins whose
+     * manifest says they need an API version the core isn't compatible with
+     * aren't started, and fail with [ProcessManagerError::IncompatibleManifest].
+     */
+    pub fn load_plugin(&mut self, dir: &Path) -> Result<()> {
+        let manifest = PluginManifest::from_dir(dir)?;
+        if !manifest.api_version.is_compatible_with(&CORE_API_VERSION) {
+            let err = ProcessManagerError::IncompatibleManifest {
+                path: dir.to_path_buf(),
+                min_api_version: manifest.api_version.min,
+                max_api_version: manifest.api_version.max,
+                core_api_version: CORE_API_VERSION,
+            };
+            warn!("{}", err);
+            return Err(err.into());
+        }
+        let path = manifest.executable.clone();
+        // Remembered so that the plugin is started the same way when it's restarted.
+        self.manifests.insert(path.clone(), manifest);
+        self.load_process(&path)
+    }
+
+    /// Starts the executable at the given path. If it belongs to a plugin
+    /// loaded with [ProcessManager::load_plugin], it's started as its manifest describes.
     pub fn load_process(&mut self, path: &Path) -> Result<()> {
-        let socket_name: String = generate_random_ipc_id();
-        let socket = SocketHandler::new(socket_name)?;
+        let transport = self.create_transport()?;
         
-        let proc = Process::new(path, socket);
+        let proc = match self.manifests.get(path) {
+            Some(manifest) => {
+                Process::with_manifest(manifest.clone(), transport, self.config.clone(), self.errors_tx.clone())
+            },
+            None => Process::with_config(path, transport, self.config.clone(), self.errors_tx.clone())
+        };
 
         match proc {
-            Ok(p) => self.loaded_processes.insert(0, p),
+            Ok(p) => {
+                let keepalive = self.keepalive.get_or_insert_with(|| {
+                    KeepaliveSupervisor::start(self.config.keepalive.clone(), self.errors_tx.clone())
+                });
+                keepalive.watch(p.get_handle());
+                let mut init = p.subscribe_init();
+                let inits_tx = self.inits_tx.clone();
+                let path = path.to_path_buf();
+                // Ends without sending if the process is stopped before it sends Init.
+                tokio::spawn(async move {
+                    if init.wait_for(Option::is_some).await.is_ok() {
+                        let _ = inits_tx.send(path);
+                    }
+                });
+                self.loaded_processes.insert(0, p)
+            },
             Err(e) => {
                 warn!("{}", e);
                 return Err(e);
@@ -130,6 +322,248 @@ impl ProcessManager {
         };
         Ok(())
     }
+
+    /// Stops the process that was loaded from the given path, then loads it again.
+    pub fn restart_process(&mut self, path: &Path) -> Result<()> {
+        match self.unload_process(path) {
+            Some(process) => {
+                debug!("Restarting process {}", path.display());
+                tokio::spawn(process.shutdown(ShutdownReason::Restarting));
+                self.load_process(path)
+            },
+            None => {
+                let err = ProcessManagerError::NotLoaded(path.to_path_buf());
+                warn!("{}", err);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Creates a transport of the kind set with [ProcessManager::set_transport] for a new plugin.
+    fn create_transport(&mut self) -> Result<PluginTransport> {
+        match self.transport {
+            TransportKind::NamedSocket => {
+                let socket_name: String = generate_random_ipc_id();
+                Ok(SocketHandler::new_in(&self.socket_dir, socket_name)?.into())
+            },
+            #[cfg(unix)]
+            TransportKind::SocketPair => Ok(SocketPair::new()?.into())
+        }
+    }
+
+    /// Stops watching the process loaded from the given path, and gives it
+    /// to the caller. Dropping it kills it.
+    fn unload_process(&mut self, path: &Path) -> Option<Process> {
+        let index = self.loaded_processes.iter().position(|p| p.get_path() == path)?;
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.unwatch(path);
+        }
+        self.unloaded.push_back(path.to_path_buf());
+        Some(self.loaded_processes.remove(index))
+    }
+
+    pub fn get_processes(&self) -> &Vec<Process> {
+        &self.loaded_processes
+    }
+
+    /// Returns how responsive the process loaded from the given path has been to keepalives.
+    pub fn get_keepalive_stats(&self, path: &Path) -> Option<KeepaliveStats> {
+        self.keepalive.as_ref()?.get_stats(path)
+    }
+
+    /**
+     * Waits for the next error raised by any loaded plugin, after acting on
+     * it according to the configured policies. Like [ProcessManager::next_event],
+     * but the instructions plugins send are dropped.
+     * 
+     * # Returns
+     * The error. It's never None, since the manager can always raise more errors itself,
+     * such as when a plugin is loaded later.
+     */
+    pub async fn next_error(&mut self) -> Option<PluginError> {
+        loop {
+            if let ProcessEvent::Error(err) = self.next_event().await? {
+                return Some(err);
+            }
+        }
+    }
+
+    /**
+     * Waits for the next instruction sent by, or error raised by, any loaded
+     * plugin, or for a plugin to be unloaded. Errors are acted on according
+     * to the configured policies first.
+     * 
+     * This, or [ProcessManager::next_error], must be called regularly for crashed
+     * plugins to be restarted, and for older versions of a plugin to be shut
+     * down once a newer one is running.
+     * 
+     * Errors raised by a run of a plugin that isn't loaded anymore, such as a
+     * keepalive that expires after the plugin was restarted, are ignored.
+     * 
+     * # Returns
+     * The event. Like [ProcessManager::next_error], it's never None.
+     */
+    pub async fn next_event(&mut self) -> Option<ProcessEvent> {
+        loop {
+            if let Some(path) = self.unloaded.pop_front() {
+                return Some(ProcessEvent::Unloaded(path));
+            }
+            tokio::select! {
+                err = self.errors_rx.recv() => {
+                    let err = err?;
+                    if !self.is_current(&err) {
+                        debug!("Ignoring error from a process that isn't loaded anymore: {}", err);
+                        continue;
+                    }
+                    self.apply_policies(&err);
+                    return Some(ProcessEvent::Error(err));
+                },
+                (process_path, instruction) = next_instruction(&mut self.loaded_processes) => {
+                    return Some(ProcessEvent::Instruction { process_path, instruction });
+                },
+                Some(path) = self.restarts_rx.recv() => {
+                    debug!("Restarting crashed process {}", path.display());
+                    if self.load_process(&path).is_err() {
+                        // Failing to start counts as crashing again.
+                        self.schedule_restart(path);
+                    }
+                },
+                Some(path) = self.inits_rx.recv() => {
+                    self.discard_superseded(&path);
+                }
+            }
+        }
+    }
+
+    /// Whether the error was raised by the run of its plugin that's loaded now,
+    /// or by the manager itself.
+    fn is_current(&self, err: &PluginError) -> bool {
+        match err.get_instance_id() {
+            Some(instance_id) => self.loaded_processes.iter()
+                .any(|p| p.get_path() == err.get_process_path() && p.get_instance_id() == instance_id),
+            None => true
+        }
+    }
+
+    fn apply_policies(&mut self, err: &PluginError) {
+        match err {
+            // A single timed out request, such as one missed keepalive, isn't enough to restart
+            // over. The KeepaliveSupervisor only raises Unresponsive once too many are missed.
+            PluginError::RequestTimedOut { .. } => {},
+            PluginError::Unresponsive { process_path, .. } => {
+                if self.config.timeout_policy == TimeoutPolicy::Restart {
+                    // The error is still returned if the restart fails, and
+                    // the failure to restart is already logged.
+                    let _ = self.restart_process(&process_path.clone());
+                }
+            },
+            PluginError::Crashed { process_path, .. } => {
+                // Dropping the crashed process cleans up its socket. If it isn't loaded anymore,
+                // it was unloaded or replaced on purpose, so it isn't restarted.
+                let crashed = match self.unload_process(process_path) {
+                    Some(crashed) => crashed,
+                    None => {
+                        debug!("Not restarting {}, since it isn't loaded", process_path.display());
+                        return;
+                    }
+                };
+                if crashed.get_started_at().elapsed() >= self.config.restart.reset_after {
+                    self.restart_counts.remove(process_path);
+                }
+                self.schedule_restart(process_path.clone());
+            },
+            PluginError::IncompatibleApi { process_path, .. } | PluginError::InitTimedOut { process_path, .. } => {
+                // The plugin failed to load, so it isn't restarted.
+                // A rejected plugin is told why, and should exit on its own.
+                if let Some(failed) = self.unload_process(process_path) {
+                    self.manifests.remove(process_path);
+                    self.restart_counts.remove(process_path);
+                    tokio::spawn(failed.shutdown(ShutdownReason::FailedToLoad));
+                }
+            },
+            PluginError::MessageTooLarge { process_path, .. } | PluginError::MalformedMessages { process_path, .. } => {
+                // Restarting it would most likely have it send the same messages again.
+                if let Some(disconnected) = self.unload_process(process_path) {
+                    self.manifests.remove(process_path);
+                    self.restart_counts.remove(process_path);
+                    tokio::spawn(disconnected.shutdown(ShutdownReason::ProtocolViolation));
+                }
+            },
+            PluginError::RestartLimitReached { .. } | PluginError::Superseded { .. } => {},
+        }
+    }
+
+    /**
+     * Once a plugin has sent Init, makes sure only the newest version of
+     * its protocol's plugin keeps running. Of equal versions, the one that
+     * was loaded first is kept.
+     * 
+     * Each plugin that is shut down is raised as a [PluginError::Superseded].
+     */
+    fn discard_superseded(&mut self, path: &Path) {
+        let protocol = match self.loaded_processes.iter().find(|p| p.get_path() == path).and_then(Process::get_init_data) {
+            Some(init) => init.protocol_data.protocol_service_name.clone(),
+            // It was stopped since it sent Init.
+            None => return,
+        };
+        // Loaded processes are newest first, and max_by_key picks the last of equal versions.
+        let candidates: Vec<(PathBuf, Version)> = self.loaded_processes.iter()
+            .filter_map(|p| {
+                let init = p.get_init_data()?;
+                (init.protocol_data.protocol_service_name == protocol)
+                    .then(|| (p.get_path().to_path_buf(), init.plugin_version))
+            })
+            .collect();
+        let (kept_path, kept_version) = match candidates.iter().max_by_key(|(_, version)| *version) {
+            Some(kept) => kept.clone(),
+            None => return,
+        };
+
+        for (process_path, version) in candidates {
+            if process_path == kept_path {
+                continue;
+            }
+            let process = match self.unload_process(&process_path) {
+                Some(process) => process,
+                None => continue,
+            };
+            self.manifests.remove(&process_path);
+            self.restart_counts.remove(&process_path);
+            tokio::spawn(process.shutdown(ShutdownReason::Superseded));
+            let err = PluginError::Superseded {
+                process_path,
+                version,
+                protocol_service_name: protocol.clone(),
+                kept_path: kept_path.clone(),
+                kept_version,
+            };
+            info!("{}", err);
+            let _ = self.errors_tx.send(err);
+        }
+    }
+
+    /// Restarts the plugin after its backoff, or gives up on it if it has
+    /// used its restart budget.
+    fn schedule_restart(&mut self, path: PathBuf) {
+        let policy = &self.config.restart;
+        let restarts = self.restart_counts.entry(path.clone()).or_insert(0);
+        if *restarts >= policy.max_restarts {
+            let err = PluginError::RestartLimitReached { process_path: path, restarts: *restarts };
+            error!("{}", err);
+            // Raised after the crash that caused it.
+            let _ = self.errors_tx.send(err);
+            return;
+        }
+
+        let backoff = policy.backoff(*restarts);
+        *restarts += 1;
+        debug!("Restarting {} in {:?} (restart {} of {})", path.display(), backoff, restarts, policy.max_restarts);
+        let restarts_tx = self.restarts_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(backoff).await;
+            let _ = restarts_tx.send(path);
+        });
+    }
 }
 
 /**
@@ -169,33 +603,91 @@ fn check_directory(dir: PathBuf) -> Result<(), ProcessManagerError> {
 
 /**
  * Applies the following criteria to a [DirEntry]
- * - It is a file
- * - It has an extension indicative of an executable (defined by [EXEC_EXTENSION])
+ * - It is a directory
+ * 
+ * Each plugin is in its own directory, alongside its manifest.
  * 
  * # Arguments
  * ## entry
  * The [DirEntry] to be checked
  * 
  * # Returns
- * A boolean value indicating wheter or not the file is an executable
+ * A boolean value indicating whether or not the entry could be a plugin directory
  */
-fn expected_file(entry: &DirEntry) -> bool {
-    let is_file = entry.file_type().is_file();
-    let extension = entry.path().extension().unwrap_or(OsStr::new(""));
-
-    return is_file && extension == EXEC_EXTENSION;
+fn expected_dir(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir()
 }
 
 fn generate_random_ipc_id() -> String {
     thread_rng().sample_iter(&Alphanumeric).take(7).map(char::from).collect()
 }
 
+/// Waits for the next instruction any of the processes sends, along with the
+/// path of the process that sent it. Never returns if none of them can send any more.
+async fn next_instruction(processes: &mut [Process]) -> (PathBuf, DeserializableCoreInstr) {
+    if processes.is_empty() {
+        return future::pending().await;
+    }
+    let receiving = processes.iter_mut().map(|process| Box::pin(async move {
+        let path = process.get_path().to_path_buf();
+        match process.get_next_instruction().await {
+            Ok(Some(instruction)) => (path, instruction),
+            // Its connection is closed, and it's about to be unloaded.
+            _ => future::pending().await
+        }
+    }));
+    select_all(receiving).await.0
+}
+
+/// The socket directories that were already cleaned since the core started.
+static CLEANED_SOCKET_DIRS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/**
+ * Removes the sockets that a previous core left behind in a socket directory.
+ * Each directory is only cleaned once while the core runs, however many
+ * [ProcessManager]s use it, since the sockets in it after that are its own.
+ * A directory that doesn't exist yet has none, and is created along with the
+ * first socket in it. Problems are only logged, since new sockets get names
+ * of their own anyway.
+ */
+fn clean_socket_dir(dir: &Path) {
+    if !CLEANED_SOCKET_DIRS.lock().unwrap().insert(dir.to_path_buf()) || !dir.exists() {
+        return;
+    }
+    // Nothing is removed from a directory someone else could have put there.
+    if let Err(e) = prepare_socket_dir(dir) {
+        warn!("Not removing stale sockets from {}: {}", dir.display(), e);
+        return;
+    }
+    match remove_stale_sockets(dir) {
+        Ok(0) => {},
+        Ok(removed) => info!("Removed {} stale sockets from {}", removed, dir.display()),
+        Err(e) => warn!("Could not remove stale sockets from {}: {}", dir.display(), e)
+    }
+}
+
 #[cfg(test)]
 mod test{
-    use crate::process_management::process_manager::ProcessManager;
+    use crate::process_management::{
+        process_manager::{ProcessManager, ProcessEvent, RestartPolicy},
+        process::ProcessConfig,
+        error::{PluginError, ExitReason},
+        request_tracker::TimeoutPolicy
+    };
+    use crate::api::schema::instructions::PluginInstructionType;
+    use std::{path::{Path, PathBuf}, time::Duration};
+    use tokio::time::timeout;
 
     // The Ok tests will be done in the integration tests with a plugin binary.
-    use claims::assert_err;
+    use claims::{assert_err, assert_ok, assert_some};
+    use test_log::test;
+
+    /// Tries to run the socket as a shell script, which fails with an error on stderr.
+    #[cfg(not(target_os = "windows"))]
+    const CRASHING_PROGRAM: &str = "sh";
+    /// Runs until it's stopped.
+    #[cfg(not(target_os = "windows"))]
+    const TEST_PROGRAM: &str = "yes";
 
     #[test]
     fn test_loading_from_relative_path() {
@@ -206,4 +698,121 @@ mod test{
     fn test_loading_from_file() {
         assert_err!(ProcessManager::from_dir_str("/etc/passwd"));
     }
+
+    #[test]
+    fn test_restart_backoff_doubles_up_to_max() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..Default::default()
+        };
+        assert_eq!(Duration::from_millis(100), policy.backoff(0));
+        assert_eq!(Duration::from_millis(200), policy.backoff(1));
+        assert_eq!(Duration::from_millis(350), policy.backoff(2));
+        assert_eq!(Duration::from_millis(350), policy.backoff(40));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test(tokio::test)]
+    async fn test_crashing_plugin_is_restarted_until_limit() {
+        let config = ProcessConfig {
+            restart: RestartPolicy {
+                max_restarts: 2,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(20),
+                reset_after: Duration::from_secs(60),
+            },
+            ..Default::default()
+        };
+        let mut manager = ProcessManager::with_config(config);
+        assert_ok!(manager.load_process(Path::new(CRASHING_PROGRAM)));
+
+        // The first run, then both restarts, crash.
+        for _ in 0..3 {
+            let err = assert_some!(manager.next_error().await);
+            assert!(matches!(err, PluginError::Crashed { .. }), "Unexpected error {}", err);
+        }
+        let err = assert_some!(manager.next_error().await);
+        assert!(matches!(err, PluginError::RestartLimitReached { restarts: 2, .. }), "Unexpected error {}", err);
+        assert!(manager.get_processes().is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn test_unloaded_plugin_is_not_restarted() {
+        let mut manager = ProcessManager::new();
+        manager.apply_policies(&PluginError::Crashed {
+            process_path: PathBuf::from("unloaded"),
+            instance_id: 0,
+            reason: ExitReason::Code(1),
+            stderr_tail: vec![],
+        });
+        assert!(manager.restart_counts.is_empty());
+        assert!(manager.get_processes().is_empty());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test(tokio::test)]
+    async fn test_restart_policy_waits_for_unresponsive() {
+        let config = ProcessConfig { timeout_policy: TimeoutPolicy::Restart, ..Default::default() };
+        let mut manager = ProcessManager::with_config(config);
+        let path = Path::new(CRASHING_PROGRAM);
+        assert_ok!(manager.load_process(path));
+        let started_at = manager.get_processes()[0].get_started_at();
+        let instance_id = manager.get_processes()[0].get_instance_id();
+
+        manager.apply_policies(&PluginError::RequestTimedOut {
+            process_path: path.to_path_buf(),
+            instance_id,
+            request_id: 1,
+            instruction_type: PluginInstructionType::Keepalive,
+        });
+        assert_eq!(started_at, manager.get_processes()[0].get_started_at());
+
+        manager.apply_policies(&PluginError::Unresponsive { process_path: path.to_path_buf(), instance_id, missed: 3 });
+        assert_eq!(1, manager.get_processes().len());
+        assert_ne!(started_at, manager.get_processes()[0].get_started_at());
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test(tokio::test)]
+    async fn test_errors_from_previous_run_are_ignored() {
+        let config = ProcessConfig { timeout_policy: TimeoutPolicy::Restart, ..Default::default() };
+        let mut manager = ProcessManager::with_config(config);
+        let path = Path::new(TEST_PROGRAM);
+        assert_ok!(manager.load_process(path));
+        let previous = manager.get_processes()[0].get_instance_id();
+        assert_ok!(manager.restart_process(path));
+        let restarted = manager.get_processes()[0].get_instance_id();
+        assert_ne!(previous, restarted);
+        assert!(matches!(assert_some!(manager.next_event().await), ProcessEvent::Unloaded(_)));
+
+        // As if a keepalive sent to the previous run expired after the restart.
+        assert_ok!(manager.errors_tx.send(PluginError::Unresponsive {
+            process_path: path.to_path_buf(),
+            instance_id: previous,
+            missed: 3,
+        }));
+        assert_err!(timeout(Duration::from_millis(100), manager.next_error()).await);
+        assert_eq!(restarted, manager.get_processes()[0].get_instance_id());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_dir_is_only_cleaned_once() {
+        use std::os::unix::net::UnixListener;
+        use crate::utils::socket::prepare_socket_dir;
+
+        let parent = assert_ok!(tempfile::tempdir());
+        let dir = parent.path().join("sockets");
+        assert_ok!(prepare_socket_dir(&dir));
+        let stale = dir.join("stale.sock");
+        drop(assert_ok!(UnixListener::bind(&stale)));
+        ProcessManager::new().set_socket_dir(dir.clone());
+        assert!(!stale.exists());
+
+        // Another manager in the same core leaves the sockets in it alone.
+        drop(assert_ok!(UnixListener::bind(&stale)));
+        ProcessManager::new().set_socket_dir(dir.clone());
+        assert!(stale.exists());
+    }
 }
@@ -0,0 +1,292 @@
+use crate::api::schema::instructions::{DeserializableCoreInstr, PluginInstructionType, RequestId};
+use super::{error::PluginError, lifecycle::{ProcessState, StateTracker}, process::InstanceId};
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, Weak},
+    time::Duration
+};
+use log::{warn, trace};
+use tokio::{sync::{oneshot, mpsc::UnboundedSender}, task::AbortHandle};
+
+/// The channel that plugin errors are raised to the core through.
+pub type PluginErrorSender = UnboundedSender<PluginError>;
+
+/// Where the response to a [Process::request](super::process::Process::request) is delivered.
+pub(crate) type Responder = oneshot::Sender<Result<DeserializableCoreInstr, PluginError>>;
+
+/// How long the core waits for a plugin to respond to each type of instruction.
+#[derive(Debug, Clone)]
+pub struct RequestTimeouts {
+    /// Used for any instruction type that does not have an entry in `per_type`.
+    pub default: Duration,
+    pub per_type: HashMap<PluginInstructionType, Duration>,
+}
+
+impl RequestTimeouts {
+    pub fn get(&self, instruction_type: PluginInstructionType) -> Duration {
+        *self.per_type.get(&instruction_type).unwrap_or(&self.default)
+    }
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        RequestTimeouts {
+            default: Duration::from_secs(30),
+            per_type: HashMap::from([
+                (PluginInstructionType::Keepalive, Duration::from_secs(5)),
+            ]),
+        }
+    }
+}
+
+/// What the core does with a plugin when one of its requests times out.
+/// The timeout is always logged and raised as a [PluginError].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeoutPolicy {
+    /// Only log it.
+    #[default]
+    Log,
+    /// Move the process to the [ProcessState::Degraded] state, which the core can check.
+    MarkDegraded,
+    /// Have the ProcessManager restart the plugin once the
+    /// [KeepaliveSupervisor](super::keepalive::KeepaliveSupervisor) finds it hung.
+    /// Other timed out requests are only logged.
+    Restart,
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    instruction_type: PluginInstructionType,
+    /// None when nobody is waiting on the response, in which case it is
+    /// passed along with the rest of the plugin's instructions.
+    responder: Option<Responder>,
+    /// The task waiting for the deadline, which is stopped once the request is done with.
+    timer: AbortHandle,
+}
+
+/// The table of requests a single process has yet to respond to.
+/// Each request is given a deadline based on its instruction type, and is
+/// flagged as a plugin error if the deadline passes.
+#[derive(Debug)]
+pub(crate) struct RequestTracker {
+    process_path: PathBuf,
+    instance_id: InstanceId,
+    pending: Mutex<HashMap<RequestId, PendingRequest>>,
+    timeouts: RequestTimeouts,
+    policy: TimeoutPolicy,
+    state: StateTracker,
+    errors: PluginErrorSender,
+}
+
+impl RequestTracker {
+    pub fn new(process_path: PathBuf, instance_id: InstanceId, timeouts: RequestTimeouts, policy: TimeoutPolicy,
+        state: StateTracker, errors: PluginErrorSender) -> Self
+    {
+        RequestTracker {
+            process_path,
+            instance_id,
+            pending: Mutex::new(HashMap::new()),
+            timeouts,
+            policy,
+            state,
+            errors,
+        }
+    }
+
+    /// Starts tracking a request that was just sent, and starts the timer for its deadline.
+    pub fn track(self: &Arc<Self>, request_id: RequestId, instruction_type: PluginInstructionType,
+        responder: Option<Responder>)
+    {
+        let deadline = self.timeouts.get(instruction_type);
+        // Held until the request is inserted, so that even a deadline of zero can't expire it first.
+        let mut pending = self.pending.lock().unwrap();
+        // The timer doesn't keep the tracker alive, since the process is done with it once it's dropped.
+        let tracker = Arc::downgrade(self);
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(deadline).await;
+            if let Some(tracker) = Weak::upgrade(&tracker) {
+                tracker.expire(request_id);
+            }
+        }).abort_handle();
+        pending.insert(request_id, PendingRequest { instruction_type, responder, timer });
+    }
+
+    /// Stops tracking a request without raising an error, such as when it could not be sent.
+    pub fn cancel(&self, request_id: RequestId) {
+        if let Some(request) = self.pending.lock().unwrap().remove(&request_id) {
+            request.timer.abort();
+        }
+    }
+
+    /**
+     * Marks the request that the instruction responds to as complete.
+     * A process that was [ProcessState::Degraded] recovers once it responds to a request in time.
+     * 
+     * # Returns
+     * None if the instruction was handed to a waiting requester.
+     * 
+     * The instruction otherwise, so it can be passed along to the core.
+     */
+    pub fn resolve(&self, instr: DeserializableCoreInstr) -> Option<DeserializableCoreInstr> {
+        let request = instr.request_id.and_then(|id| self.pending.lock().unwrap().remove(&id));
+        if let Some(request) = &request {
+            request.timer.abort();
+            self.state.recover();
+        }
+        match request.and_then(|r| r.responder) {
+            Some(responder) => {
+                trace!("Routing response to request {:?}", instr.request_id);
+                // If the requester gave up waiting, there's nobody left to tell.
+                let _ = responder.send(Ok(instr));
+                None
+            },
+            None => Some(instr)
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.state.get() == ProcessState::Degraded
+    }
+
+    fn expire(&self, request_id: RequestId) {
+        let request = match self.pending.lock().unwrap().remove(&request_id) {
+            Some(r) => r,
+            None => return, // Already responded to
+        };
+
+        let err = PluginError::RequestTimedOut {
+            process_path: self.process_path.clone(),
+            instance_id: self.instance_id,
+            request_id,
+            instruction_type: request.instruction_type,
+        };
+        warn!("{}", err);
+        if self.policy == TimeoutPolicy::MarkDegraded {
+            self.state.advance(ProcessState::Degraded);
+        }
+        if let Some(responder) = request.responder {
+            let _ = responder.send(Err(err.clone()));
+        }
+        // The receiver is gone if nothing is supervising this process.
+        let _ = self.errors.send(err);
+    }
+}
+
+impl Drop for RequestTracker {
+    fn drop(&mut self) {
+        for request in self.pending.get_mut().unwrap().values() {
+            request.timer.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::schema::instructions::CoreInstructionType;
+    use claims::{assert_ok, assert_err_eq, assert_none, assert_some};
+    use serde_json::value::RawValue;
+    use test_log::test;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    fn create_tracker(policy: TimeoutPolicy) -> (Arc<RequestTracker>, UnboundedReceiver<PluginError>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let timeouts = RequestTimeouts {
+            default: Duration::from_millis(20),
+            per_type: HashMap::new(),
+        };
+        let state = StateTracker::new(PathBuf::from("test"));
+        state.advance(ProcessState::Initialized);
+        (Arc::new(RequestTracker::new(PathBuf::from("test"), 1, timeouts, policy, state, tx)), rx)
+    }
+
+    fn create_response(request_id: RequestId) -> DeserializableCoreInstr {
+        DeserializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: Some(request_id),
+            payload: RawValue::from_string("{}".to_string()).unwrap().into(),
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn test_response_goes_to_requester() {
+        let (tracker, mut errors) = create_tracker(TimeoutPolicy::Log);
+        let (tx, rx) = oneshot::channel();
+        tracker.track(1, PluginInstructionType::AuthAccount, Some(tx));
+
+        assert_none!(tracker.resolve(create_response(1)));
+        let response = assert_ok!(assert_ok!(rx.await));
+        assert_eq!(Some(1), response.request_id);
+
+        // Responding in time must not raise an error after the deadline.
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[test(tokio::test)]
+    async fn test_timers_stop_once_requests_are_done() {
+        let (tracker, _errors) = create_tracker(TimeoutPolicy::Log);
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let alive = metrics.num_alive_tasks();
+        for request_id in 0..10 {
+            tracker.track(request_id, PluginInstructionType::AuthAccount, None);
+        }
+        assert_eq!(alive + 10, metrics.num_alive_tasks());
+
+        for request_id in 0..5 {
+            assert_some!(tracker.resolve(create_response(request_id)));
+        }
+        for request_id in 5..10 {
+            tracker.cancel(request_id);
+        }
+        // Aborted tasks are only reaped once the runtime gets to them.
+        tokio::task::yield_now().await;
+        assert_eq!(alive, metrics.num_alive_tasks());
+    }
+
+    #[test(tokio::test)]
+    async fn test_untracked_response_is_passed_along() {
+        let (tracker, _errors) = create_tracker(TimeoutPolicy::Log);
+        assert_some!(tracker.resolve(create_response(7)));
+    }
+
+    #[test(tokio::test)]
+    async fn test_timeout_raises_error() {
+        let (tracker, mut errors) = create_tracker(TimeoutPolicy::MarkDegraded);
+        let (tx, rx) = oneshot::channel();
+        tracker.track(3, PluginInstructionType::AuthAccount, Some(tx));
+
+        let expected = PluginError::RequestTimedOut {
+            process_path: PathBuf::from("test"),
+            instance_id: 1,
+            request_id: 3,
+            instruction_type: PluginInstructionType::AuthAccount,
+        };
+        assert_eq!(Some(expected.clone()), errors.recv().await);
+        assert_err_eq!(assert_ok!(rx.await), expected);
+        assert!(tracker.is_degraded());
+    }
+
+    #[test(tokio::test)]
+    async fn test_response_clears_degraded() {
+        let (tracker, mut errors) = create_tracker(TimeoutPolicy::MarkDegraded);
+        tracker.track(1, PluginInstructionType::Keepalive, None);
+        assert_some!(errors.recv().await);
+        assert!(tracker.is_degraded());
+
+        tracker.track(2, PluginInstructionType::Keepalive, None);
+        assert_some!(tracker.resolve(create_response(2)));
+        assert!(!tracker.is_degraded());
+        assert_eq!(ProcessState::Initialized, tracker.state.get());
+    }
+
+    #[test(tokio::test)]
+    async fn test_log_policy_does_not_degrade() {
+        let (tracker, mut errors) = create_tracker(TimeoutPolicy::Log);
+        tracker.track(1, PluginInstructionType::Keepalive, None);
+        assert_some!(errors.recv().await);
+        assert!(!tracker.is_degraded());
+    }
+}
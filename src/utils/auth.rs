@@ -0,0 +1,64 @@
+use std::fmt::Debug;
+
+use rand::{rngs::OsRng, RngCore};
+
+/// How many random bytes are in a token.
+const TOKEN_BYTES: usize = 32;
+
+/// A secret generated for each plugin process, which the plugin has to
+/// present when it connects. See [AuthFrame](crate::api::schema::protocol::AuthFrame).
+#[derive(Clone, PartialEq, Eq)]
+pub struct AuthToken(String);
+
+impl AuthToken {
+    /// Generates a new token from the OS's secure random number generator.
+    pub fn generate() -> AuthToken {
+        let mut bytes = [0; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        AuthToken(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the presented token is this one. Compared in constant time,
+    /// so how long it takes doesn't reveal how much of it was right.
+    pub fn matches(&self, presented: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let presented = presented.as_bytes();
+        if expected.len() != presented.len() {
+            return false;
+        }
+        expected.iter().zip(presented).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// Kept out of logs.
+impl Debug for AuthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AuthToken(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_log::test;
+
+    #[test]
+    fn test_token_matches_only_itself() {
+        let token = AuthToken::generate();
+        assert_eq!(TOKEN_BYTES * 2, token.as_str().len());
+        assert!(token.matches(token.as_str()));
+        assert!(!token.matches(AuthToken::generate().as_str()));
+        assert!(!token.matches(&token.as_str()[1..]));
+        assert!(!token.matches(""));
+    }
+
+    #[test]
+    fn test_token_is_not_logged() {
+        let token = AuthToken::generate();
+        assert!(!format!("{:?}", token).contains(token.as_str()));
+    }
+}
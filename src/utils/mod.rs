@@ -1 +1,2 @@
+pub mod auth;
 pub mod socket;
\ No newline at end of file
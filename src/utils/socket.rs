@@ -1,73 +1,428 @@
 use std::fmt::Debug;
 use std::any::type_name;
+use std::{env, fs, io, path::{Path, PathBuf}, pin::Pin, task::{Context, Poll}};
 
 use interprocess::local_socket::tokio::{OwnedReadHalf, OwnedWriteHalf};
 use interprocess::local_socket::NameTypeSupport;
 use futures::{
-    io::BufReader, AsyncBufReadExt, AsyncWriteExt
+    io::BufReader, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt
 };
 use log::{debug, warn, trace};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
 
 use anyhow::Result;
+use thiserror::Error;
 
-pub async fn receive_line(reader: &mut OwnedReadHalf) -> Result<String> {
-    let mut bufreader = BufReader::new(reader);
-    let mut data = String::with_capacity(128);
-
-    match bufreader.read_line(&mut data).await {
-        Ok(size) => {
-            debug!("Received {} bytes from connection", size);
-        },
-        Err(e) => {
-            warn!("Could not read line from connection: {}", e.to_string());
-            return Err(e.into());
+use crate::api::schema::protocol::{Encoding, Framing, DEFAULT_MAX_FRAME_SIZE};
+
+#[derive(Error, Debug)]
+pub enum SocketError {
+    #[error("The other end closed the connection")]
+    Closed,
+    /// The connection can't be read from after this, since the rest of the frame is still in it.
+    /// For newline delimited frames, the size is how much was read before the frame was refused.
+    #[error("A frame of {size} bytes is larger than the maximum of {max_frame_size} bytes")]
+    FrameTooLarge { size: usize, max_frame_size: u32 },
+    /// Anyone else who could write to it could replace the sockets in it.
+    #[error("Socket directory '{0}' must be a directory that only the current user owns and can access")]
+    InsecureDirectory(PathBuf),
+    /// Only for connections that can't be replaced by another one, like an inherited socket pair.
+    #[error("The other end didn't present its auth token")]
+    Unauthenticated,
+}
+
+/// The read half of a connection, whichever way it was made.
+#[derive(Debug)]
+pub enum ReadHalf {
+    /// A connection to a named local socket.
+    Local(OwnedReadHalf),
+    /// One end of a socket pair, one of which was inherited by the plugin.
+    #[cfg(unix)]
+    Pair(tokio::net::unix::OwnedReadHalf),
+}
+
+/// The write half of a connection, whichever way it was made.
+#[derive(Debug)]
+pub enum WriteHalf {
+    /// A connection to a named local socket.
+    Local(OwnedWriteHalf),
+    /// One end of a socket pair, one of which was inherited by the plugin.
+    #[cfg(unix)]
+    Pair(tokio::net::unix::OwnedWriteHalf),
+}
+
+impl From<OwnedReadHalf> for ReadHalf {
+    fn from(read: OwnedReadHalf) -> Self {
+        ReadHalf::Local(read)
+    }
+}
+
+impl From<OwnedWriteHalf> for WriteHalf {
+    fn from(write: OwnedWriteHalf) -> Self {
+        WriteHalf::Local(write)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::OwnedReadHalf> for ReadHalf {
+    fn from(read: tokio::net::unix::OwnedReadHalf) -> Self {
+        ReadHalf::Pair(read)
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::OwnedWriteHalf> for WriteHalf {
+    fn from(write: tokio::net::unix::OwnedWriteHalf) -> Self {
+        WriteHalf::Pair(write)
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ReadHalf::Local(read) => Pin::new(read).poll_read(cx, buf),
+            #[cfg(unix)]
+            ReadHalf::Pair(read) => {
+                let mut buf = tokio::io::ReadBuf::new(buf);
+                match tokio::io::AsyncRead::poll_read(Pin::new(read), cx, &mut buf) {
+                    Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+                    Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                    Poll::Pending => Poll::Pending
+                }
+            }
         }
-    };
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            WriteHalf::Local(write) => Pin::new(write).poll_write(cx, buf),
+            #[cfg(unix)]
+            WriteHalf::Pair(write) => tokio::io::AsyncWrite::poll_write(Pin::new(write), cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Local(write) => Pin::new(write).poll_flush(cx),
+            #[cfg(unix)]
+            WriteHalf::Pair(write) => tokio::io::AsyncWrite::poll_flush(Pin::new(write), cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Local(write) => Pin::new(write).poll_close(cx),
+            #[cfg(unix)]
+            WriteHalf::Pair(write) => tokio::io::AsyncWrite::poll_shutdown(Pin::new(write), cx),
+        }
+    }
+}
 
-    Ok(data)
+/// Reads frames from one end of a connection.
+/// There should be one per connection, kept for as long as the connection is,
+/// since a single read from the socket can return several frames, and the ones
+/// that weren't asked for yet stay in its buffer.
+#[derive(Debug)]
+pub struct FrameReader {
+    reader: BufReader<ReadHalf>,
+    frame: Vec<u8>,
+    framing: Framing,
+    max_frame_size: u32,
 }
 
-pub fn convert_str_to_struct<'a, T>(data: &'a String) -> Result<T> where T: Deserialize<'a>{
-    let template_type_name = type_name::<T>();
-    trace!("Attempting to deserialize '{}' into {}", data, template_type_name);
-    match serde_json::from_str::<T>(data) {
-        Ok(s_struct) => Ok(s_struct),
-        Err(e) => {
-            warn!("Error serializing data into {}: {}", template_type_name, e.to_string());
-            Err(e.into())
+impl FrameReader {
+    /// Starts out reading [Framing::Newline] frames, as every connection does.
+    pub fn new<R>(reader: R) -> FrameReader where R: Into<ReadHalf> {
+        FrameReader {
+            reader: BufReader::new(reader.into()),
+            frame: Vec::with_capacity(128),
+            framing: Framing::Newline,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Reads the frames after this one with a different framing.
+    /// Anything already buffered is kept, and read with the new framing.
+    pub fn set_framing(&mut self, framing: Framing, max_frame_size: u32) {
+        debug!("Reading {:?} frames from now on", framing);
+        self.framing = framing;
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Refuses frames after this one that are larger than the given size, in bytes.
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /**
+     * Waits for the next frame from the connection.
+     * 
+     * # Returns
+     * A [Result] containing the frame on success. Newline delimited frames include their newline.
+     * If the other end closed the connection, the error is [SocketError::Closed].
+     * If the frame is larger than the maximum, the error is [SocketError::FrameTooLarge].
+     */
+    pub async fn next_frame(&mut self) -> Result<&[u8]> {
+        self.frame.clear();
+        let result = match self.framing {
+            Framing::Newline => self.read_newline_delimited().await,
+            Framing::LengthPrefixed => self.read_length_prefixed().await,
+        };
+        match result {
+            Ok(0) => {
+                debug!("Connection closed");
+                Err(SocketError::Closed.into())
+            },
+            Ok(size) => {
+                trace!("Received {} bytes from connection", size);
+                Ok(&self.frame)
+            },
+            Err(e) => {
+                warn!("Could not read frame from connection: {}", e);
+                Err(e)
+            }
         }
     }
+
+    /// Reads a newline delimited frame into [FrameReader::frame], returning its size.
+    /// The newline doesn't count towards the maximum, and the frame is refused
+    /// as soon as it's too large, rather than after all of it is buffered.
+    async fn read_newline_delimited(&mut self) -> Result<usize> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                return Ok(self.frame.len());
+            }
+            let (used, complete) = match available.iter().position(|b| *b == b'\n') {
+                Some(newline) => (newline + 1, true),
+                None => (available.len(), false)
+            };
+            let size = self.frame.len() + used - complete as usize;
+            if size > self.max_frame_size as usize {
+                return Err(SocketError::FrameTooLarge { size, max_frame_size: self.max_frame_size }.into());
+            }
+            self.frame.extend_from_slice(&available[..used]);
+            self.reader.consume_unpin(used);
+            if complete {
+                return Ok(self.frame.len());
+            }
+        }
+    }
+
+    /// Reads a length prefixed frame into [FrameReader::frame], returning its size.
+    async fn read_length_prefixed(&mut self) -> Result<usize> {
+        // Only a connection closed between frames is a clean close.
+        if self.reader.fill_buf().await?.is_empty() {
+            return Ok(0);
+        }
+        let mut prefix = [0; 4];
+        self.reader.read_exact(&mut prefix).await?;
+        let size = u32::from_be_bytes(prefix) as usize;
+        if size > self.max_frame_size as usize {
+            return Err(SocketError::FrameTooLarge { size, max_frame_size: self.max_frame_size }.into());
+        }
+        self.frame.resize(size, 0);
+        self.reader.read_exact(&mut self.frame).await?;
+        // Counts the prefix, so that an empty frame isn't mistaken for the connection closing.
+        Ok(prefix.len() + size)
+    }
 }
 
-pub fn convert_struct_to_str<'a, T: 'a>(msg: &T) -> Result<String> where T: Serialize + Debug {
-    match serde_json::to_string(msg) {
-        Ok(s) => Ok(s),
-        Err(e) => {
-            debug!("Error serializing {:?}: {}", msg, e.to_string());
-            return Err(e.into());
+/// Writes frames to one end of a connection.
+#[derive(Debug)]
+pub struct FrameWriter {
+    writer: WriteHalf,
+    framing: Framing,
+    max_frame_size: u32,
+}
+
+impl FrameWriter {
+    /// Starts out writing [Framing::Newline] frames, as every connection does.
+    pub fn new<W>(writer: W) -> FrameWriter where W: Into<WriteHalf> {
+        FrameWriter { writer: writer.into(), framing: Framing::Newline, max_frame_size: DEFAULT_MAX_FRAME_SIZE }
+    }
+
+    /// Writes the frames after this one with a different framing.
+    pub fn set_framing(&mut self, framing: Framing, max_frame_size: u32) {
+        debug!("Writing {:?} frames from now on", framing);
+        self.framing = framing;
+        self.max_frame_size = max_frame_size;
+    }
+
+    /**
+     * Sends a frame over the connection in a single write.
+     * 
+     * # Returns
+     * A [Result], void on success. A frame that's too large for the
+     * connection's framing is not sent, and the error is [SocketError::FrameTooLarge].
+     */
+    pub async fn send_frame(&mut self, msg: &[u8]) -> Result<()> {
+        let payload = match self.framing {
+            Framing::Newline => [msg, b"\n"].concat(),
+            Framing::LengthPrefixed => {
+                let size = msg.len();
+                if size > self.max_frame_size as usize {
+                    warn!("Not sending a frame of {} bytes, since the maximum is {}", size, self.max_frame_size);
+                    return Err(SocketError::FrameTooLarge { size, max_frame_size: self.max_frame_size }.into());
+                }
+                let mut payload = Vec::with_capacity(4 + size);
+                payload.extend_from_slice(&(size as u32).to_be_bytes());
+                payload.extend_from_slice(msg);
+                payload
+            }
+        };
+        trace!("Sending {} bytes across", msg.len());
+        match self.writer.write_all(&payload).await {
+            Ok(_) => {
+                debug!("Data sent");
+                Ok(())
+            },
+            Err(e) => {
+                warn!("Error sending data: {}", e);
+                Err(e.into())
+            }
         }
     }
 }
 
-pub async fn send_str_over_ipc(msg: &String, ipc: &mut OwnedWriteHalf) -> Result<()> {
-    let payload = format!("{}\n", msg);
-    trace!("Sending {} across", msg);
-    match ipc.write_all(payload.as_bytes()).await {
-        Ok(_) => {
-            debug!("Data sent");
-            Ok(())
-        },
-        Err(e) => {
-            warn!("Error sending data: {}", e.to_string());
-            Err(e.into())
+/**
+ * Decodes a value that was encoded with the given encoding.
+ * 
+ * # Returns
+ * The value on success, or an error describing why the data isn't a valid T.
+ */
+pub fn decode_value<T>(data: &[u8], encoding: Encoding) -> Result<T> where T: DeserializeOwned {
+    let template_type_name = type_name::<T>();
+    trace!("Attempting to decode {} bytes of {} into {}", data.len(), encoding, template_type_name);
+    let result = match encoding {
+        Encoding::Json => serde_json::from_slice::<T>(data).map_err(|e| e.into()),
+        Encoding::MessagePack => rmp_serde::from_slice::<T>(data).map_err(|e| e.into()),
+        Encoding::Cbor => ciborium::from_reader::<T, _>(data).map_err(|e| e.into()),
+    };
+    if let Err(e) = &result {
+        warn!("Error decoding {} into {}: {}", encoding, template_type_name, e);
+    }
+    result
+}
+
+/**
+ * Encodes a value with the given encoding.
+ * 
+ * # Returns
+ * The encoded value on success.
+ */
+pub fn encode_value<T>(value: &T, encoding: Encoding) -> Result<Vec<u8>> where T: Serialize + Debug + ?Sized {
+    let result = match encoding {
+        Encoding::Json => serde_json::to_vec(value).map_err(|e| e.into()),
+        // Structs are encoded as maps, so that fields can be added without breaking older plugins.
+        Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.into()),
+        Encoding::Cbor => {
+            let mut data = Vec::with_capacity(128);
+            ciborium::into_writer(value, &mut data).map(|_| data).map_err(|e| e.into())
         }
+    };
+    if let Err(e) = &result {
+        debug!("Error encoding {:?} as {}: {}", value, encoding, e);
     }
+    result
 }
 
+/// Returns the full name of a socket in the [default_socket_dir].
 pub fn get_socket_name<S>(name: S) -> String where S: Into<String> + std::fmt::Display {
+    get_socket_name_in(&default_socket_dir(), name)
+}
+
+/**
+ * Returns the full name of a socket in the given directory.
+ * On platforms that only have namespaced sockets, the directory isn't used.
+ */
+pub fn get_socket_name_in<S>(dir: &Path, name: S) -> String where S: Into<String> + std::fmt::Display {
     match NameTypeSupport::query() {
-        NameTypeSupport::OnlyPaths | NameTypeSupport::Both => format!("/tmp/{}.sock", name),
+        NameTypeSupport::OnlyPaths | NameTypeSupport::Both => dir.join(format!("{}.sock", name)).display().to_string(),
         NameTypeSupport::OnlyNamespaced => format!("@{}.sock", name)
     }
 }
+
+/**
+ * Returns where path based sockets are created unless another directory is
+ * given. That's a polychat directory in `$XDG_RUNTIME_DIR`, which only the
+ * current user can access. Without one, it's a directory for the current user
+ * in the system's temporary directory.
+ */
+pub fn default_socket_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) if !runtime_dir.is_empty() => PathBuf::from(runtime_dir).join("polychat"),
+        _ => env::temp_dir().join(format!("polychat-{}", current_user_id()))
+    }
+}
+
+#[cfg(unix)]
+fn current_user_id() -> u32 {
+    unsafe { libc::geteuid() }
+}
+#[cfg(not(unix))]
+fn current_user_id() -> u32 {
+    0
+}
+
+/**
+ * Creates a directory for sockets, if it doesn't exist yet, that only the
+ * current user can access.
+ * 
+ * # Returns
+ * Nothing on success. If the directory already exists, but isn't owned by
+ * the current user, or others can access it, the error is [SocketError::InsecureDirectory].
+ */
+#[cfg(unix)]
+pub fn prepare_socket_dir(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    // Not following links, so the directory can't be swapped for one someone else owns.
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != current_user_id() || metadata.mode() & 0o077 != 0 {
+        let err = SocketError::InsecureDirectory(dir.to_path_buf());
+        warn!("{}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+#[cfg(not(unix))]
+pub fn prepare_socket_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/**
+ * Removes the sockets in a directory that nothing is listening on anymore,
+ * such as the ones left behind by a core that crashed.
+ * 
+ * # Returns
+ * How many were removed.
+ */
+#[cfg(unix)]
+pub fn remove_stale_sockets(dir: &Path) -> Result<usize> {
+    use std::{io::ErrorKind, os::unix::{fs::FileTypeExt, net::UnixStream}};
+
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_socket() {
+            continue;
+        }
+        let path = entry.path();
+        if let Err(e) = UnixStream::connect(&path) {
+            if e.kind() == ErrorKind::ConnectionRefused {
+                debug!("Removing stale socket {}", path.display());
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+#[cfg(not(unix))]
+pub fn remove_stale_sockets(_dir: &Path) -> Result<usize> {
+    Ok(0)
+}
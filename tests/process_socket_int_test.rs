@@ -1,13 +1,30 @@
 #[cfg(test)]
 mod test {
-    use polychat_ipc::{core::socket_handler::SocketHandler, polychat_plugin_sdk_rust::socket::SocketCommunicator, api::schema::instructions::{SerializableCoreInstr, SerializablePluginInstr}};
-    use claims::{assert_ok, assert_some};
+    use polychat_ipc::{core::socket_handler::SocketHandler, polychat_plugin_sdk_rust::socket::SocketCommunicator, api::schema::instructions::{SerializableCoreInstr, SerializablePluginInstr, Payload}};
+    use claims::{assert_ok, assert_err, assert_none, assert_some};
     use polychat_ipc::{
-        api::schema::instructions::{CoreInstructionType, PluginInstructionType},
-        process_management::process::Process
+        api::schema::{
+            instructions::{CoreInstructionType, PluginInstructionType},
+            protocol::{
+                InitDataInstruction, InitAckInstruction, InitRejectedInstruction, ProtocolData, MessageCapabilities, Version,
+                Framing, Encoding, CORE_API_VERSION
+            },
+            shutdown::{ShutdownInstruction, ShutdownReason}
+        },
+        process_management::{
+            process::{Process, ProcessConfig, MessageLimits},
+            request_tracker::{RequestTimeouts, TimeoutPolicy},
+            error::PluginError,
+            lifecycle::ProcessState
+        }
     };
+    use polychat_ipc::utils::socket::{get_socket_name, SocketError};
     use rstest::*;
     use serde_json::value::RawValue;
+    use std::{collections::HashMap, time::Duration};
+    use futures::AsyncWriteExt;
+    use interprocess::local_socket::tokio::LocalSocketStream;
+    use tokio::{sync::mpsc, time::timeout};
 
     // TEST_PROGRAM is an executable that can be run on the local system.
     // The purpose of using this instead of a plugin is to test it in isolation.
@@ -28,12 +45,13 @@ mod test {
         // The connection to the socket will be tested separately from process execution in this test.
         let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
         // Run the code that plugins usually run to connect to the socket server.
-        let mut comms = create_socket_client(&name).await;
+        let mut comms = create_socket_client(&name, &proc).await;
 
         // Send a message from the plugin code to the core code, and verify that it
         // was passed from comms (the plugin code) to proc (the core code).
         let core_payload = SerializableCoreInstr {
             instruction_type: ins_type,
+            request_id: None,
             payload: create_core_payload()
         };
 
@@ -44,6 +62,31 @@ mod test {
         assert_eq!(core_payload, recv_data.into());
     }
 
+    /// Tests that instructions written to the socket all at once each reach
+    /// the process, in order, rather than only the first one in the read.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_recv_core_insts_in_one_write() {
+        let name = "polychat_process_recv_one_write".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut conn = assert_ok!(LocalSocketStream::connect(get_socket_name(&name)).await);
+        assert_ok!(conn.write_all(format!("{{\"token\":\"{}\"}}\n", proc.get_auth_token()).as_bytes()).await);
+
+        let core_payloads: Vec<_> = (0..50).map(|i| SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: Payload::from(assert_ok!(RawValue::from_string(format!("{{\"index\":{}}}", i))))
+        }).collect();
+        let lines: String = core_payloads.iter().map(|i| assert_ok!(serde_json::to_string(i)) + "\n").collect();
+        assert_ok!(conn.write_all(lines.as_bytes()).await);
+        for core_payload in core_payloads {
+            let recv_data = assert_some!(assert_ok!(
+                assert_ok!(timeout(Duration::from_secs(1), proc.get_next_instruction()).await)
+            ));
+            assert_eq!(core_payload, recv_data.into());
+        }
+    }
+
     #[rstest]
     #[case(PluginInstructionType::Keepalive)]
     #[case(PluginInstructionType::AuthAccount)]
@@ -54,21 +97,16 @@ mod test {
         // The connection to the socket will be tested separately from process execution in this test.
         let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
         // Run the code that plugins usually run to connect to the socket server.
-        let mut comms = create_socket_client(&name).await;
+        let mut comms = create_socket_client(&name, &proc).await;
 
-        // Send the Init instruction from the plugin code to the core code, and verify
-        // that it was passed from comms (the plugin code) to proc (the core code).
-        let core_payload = SerializableCoreInstr {
-            instruction_type: CoreInstructionType::Init,
-            payload: create_core_payload()
-        };
-        assert_ok!(comms.send_core_instruction(&core_payload).await);
-        assert_ok!(proc.get_next_instruction().await);
+        // Nothing is sent to the plugin until its Init is accepted.
+        initialize(&mut comms, &mut proc).await;
 
         // Now the other way. Send the case's instruction type from the core's code (proc)
         // to the plugin's code (comms).
         let plugin_payload = SerializablePluginInstr {
             instruction_type: ins_type,
+            request_id: proc.next_request_id(),
             payload: create_core_payload()
         };
         assert_ok!(proc.send_instruction(&plugin_payload).await);
@@ -78,19 +116,440 @@ mod test {
         assert_eq!(plugin_payload, recv_data.into());
     }
 
+    /// Tests that once the plugin closes its connection, sending to it fails
+    /// right away, rather than writing to a closed socket.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_closed_connection_is_dropped() {
+        let name = "polychat_process_closed_connection".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let comms = create_socket_client(&name, &proc).await;
+        drop(comms);
+        // Nothing more comes in once the reader sees the connection close.
+        assert_none!(assert_ok!(assert_ok!(timeout(Duration::from_secs(1), proc.get_next_instruction()).await)));
+
+        let plugin_payload = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::Keepalive,
+            request_id: proc.next_request_id(),
+            payload: create_core_payload()
+        };
+        let err = assert_err!(proc.send_instruction(&plugin_payload).await);
+        assert!(err.to_string().contains("is not connected"), "Unexpected error {}", err);
+    }
+
+    /// Tests that a response carrying the request's ID is handed back to the
+    /// matching [Process::request] call instead of get_next_instruction.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_request_gets_matching_response() {
+        let name = "polychat_process_request_response".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut comms = create_socket_client(&name, &proc).await;
+        initialize(&mut comms, &mut proc).await;
+
+        // Plugin side: answer the request, echoing back its ID.
+        let plugin = tokio::spawn(async move {
+            let recv_data = assert_ok!(comms.recv_plugin_instruction().await);
+            assert_eq!(PluginInstructionType::AuthAccount, recv_data.instruction_type);
+            let response = SerializableCoreInstr {
+                instruction_type: CoreInstructionType::AuthAccountResponse,
+                request_id: Some(recv_data.request_id),
+                payload: create_core_payload()
+            };
+            assert_ok!(comms.send_core_instruction(&response).await);
+            (comms, recv_data.request_id)
+        });
+
+        let response = assert_ok!(proc.request(PluginInstructionType::AuthAccount, create_core_payload()).await);
+        let (_comms, request_id) = assert_ok!(plugin.await);
+        assert_eq!(CoreInstructionType::AuthAccountResponse, response.instruction_type);
+        assert_eq!(Some(request_id), response.request_id);
+    }
+
+    /// Tests that a request the plugin never answers fails with a typed
+    /// error, and that the same error is raised to whoever supervises the process.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_request_times_out() {
+        let name = "polychat_process_request_timeout".to_string();
+        let config = ProcessConfig {
+            request_timeouts: RequestTimeouts { default: Duration::from_millis(50), per_type: HashMap::new() },
+            timeout_policy: TimeoutPolicy::MarkDegraded,
+            ..Default::default()
+        };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let mut proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name), config, errors_tx));
+        // Connect, but never respond.
+        let _comms = create_socket_client(&name, &proc).await;
+
+        let err = assert_err!(proc.request(PluginInstructionType::AuthAccount, create_core_payload()).await);
+        let err = assert_ok!(err.downcast::<PluginError>());
+        assert!(matches!(err, PluginError::RequestTimedOut { instruction_type: PluginInstructionType::AuthAccount, .. }));
+        assert_eq!(Some(err), errors_rx.recv().await);
+        assert!(proc.is_degraded());
+    }
+
+    /// Tests that a plugin with a compatible API version is told it was
+    /// accepted, and its Init is passed on.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_compatible_init_is_acknowledged() {
+        let name = "polychat_process_init_ack".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        assert_ok!(comms.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::InitAck, ack.instruction_type);
+        let init = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(CoreInstructionType::Init, init.instruction_type);
+        assert_some!(proc.get_init_data());
+        assert!(!proc.is_rejected());
+    }
+
+    /// Tests that both directions switch to the framing picked in the InitAck,
+    /// which can carry payloads with raw newlines.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_init_negotiates_framing() {
+        let name = "polychat_process_init_framing".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        let init = create_init_negotiating(CORE_API_VERSION, vec![Framing::LengthPrefixed, Framing::Newline], vec![]);
+        assert_ok!(comms.send_core_instruction(&init).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::InitAck, ack.instruction_type);
+        let ack = assert_ok!(ack.payload.decode::<InitAckInstruction>());
+        assert_eq!(Framing::LengthPrefixed, ack.framing);
+        comms.set_framing(ack.framing, ack.max_frame_size).await;
+        assert_ok!(proc.get_next_instruction().await);
+
+        let core_payload = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: Payload::from(assert_ok!(RawValue::from_string("{\n  \"multi\": \"line\"\n}".to_string())))
+        };
+        assert_ok!(comms.send_core_instruction(&core_payload).await);
+        let recv_data = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(core_payload, recv_data.into());
+
+        let plugin_payload = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::Keepalive,
+            request_id: proc.next_request_id(),
+            payload: create_core_payload()
+        };
+        assert_ok!(proc.send_instruction(&plugin_payload).await);
+        let recv_data = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(plugin_payload, recv_data.into());
+    }
+
+    /// Tests that both directions switch to the encoding picked in the InitAck,
+    /// and that binary encodings are only picked along with length-prefixed framing.
+    #[rstest]
+    #[case(vec![Framing::LengthPrefixed], vec![Encoding::MessagePack, Encoding::Json], Encoding::MessagePack)]
+    #[case(vec![Framing::LengthPrefixed], vec![Encoding::Cbor], Encoding::Cbor)]
+    #[case(vec![Framing::LengthPrefixed], vec![], Encoding::Json)]
+    #[case(vec![Framing::Newline], vec![Encoding::MessagePack], Encoding::Json)]
+    #[test_log::test(tokio::test)]
+    async fn test_init_negotiates_encoding(#[case] framing: Vec<Framing>, #[case] encodings: Vec<Encoding>,
+        #[case] expected: Encoding)
+    {
+        let name = format!("polychat_process_init_encoding_{}_{:?}", expected, framing[0]);
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        assert_ok!(comms.send_core_instruction(&create_init_negotiating(CORE_API_VERSION, framing, encodings)).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        let ack = assert_ok!(ack.payload.decode::<InitAckInstruction>());
+        assert_eq!(expected, ack.encoding);
+        comms.set_framing(ack.framing, ack.max_frame_size).await;
+        comms.set_encoding(ack.encoding).await;
+        assert_ok!(proc.get_next_instruction().await);
+
+        let core_payload = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: Payload::from(assert_ok!(RawValue::from_string("{\"index\":1}".to_string())))
+        };
+        assert_ok!(comms.send_core_instruction(&core_payload).await);
+        let recv_data = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(expected, recv_data.payload.encoding());
+        assert_eq!(core_payload, recv_data.into());
+
+        let plugin_payload = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::Keepalive,
+            request_id: proc.next_request_id(),
+            payload: create_core_payload()
+        };
+        assert_ok!(proc.send_instruction(&plugin_payload).await);
+        let recv_data = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(expected, recv_data.payload.encoding());
+        assert_eq!(plugin_payload, recv_data.into());
+    }
+
+    /// Tests that an Init sent after one was accepted is ignored, rather than
+    /// answered or passed on again.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_second_init_is_ignored() {
+        let name = "polychat_process_second_init".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut comms = create_socket_client(&name, &proc).await;
+        initialize(&mut comms, &mut proc).await;
+
+        assert_ok!(comms.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        let core_payload = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: create_core_payload()
+        };
+        assert_ok!(comms.send_core_instruction(&core_payload).await);
+        let recv_data = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(core_payload, recv_data.into());
+        assert_eq!(ProcessState::Initialized, proc.get_state());
+    }
+
+    /// Tests that a plugin with an incompatible API version is told why it
+    /// was rejected, and that nothing else is sent to it.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_incompatible_init_is_rejected() {
+        let name = "polychat_process_init_rejected".to_string();
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let mut proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name),
+            ProcessConfig::default(), errors_tx));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        let api_version = Version { major: CORE_API_VERSION.major + 1, minor: 0, patch: 0 };
+        assert_ok!(comms.send_core_instruction(&create_init(api_version)).await);
+        let rejection = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::InitRejected, rejection.instruction_type);
+        assert_ok!(rejection.payload.decode::<InitRejectedInstruction>());
+
+        let err = assert_some!(errors_rx.recv().await);
+        assert!(matches!(err, PluginError::IncompatibleApi { api_version: v, .. } if v == api_version));
+        assert!(proc.is_rejected());
+        assert!(proc.get_init_data().is_none());
+        assert_err!(proc.request(PluginInstructionType::AuthAccount, create_core_payload()).await);
+    }
+
+    /// Tests that the process moves from Spawned to Connected to Initialized
+    /// as the plugin connects and sends Init.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_state_follows_handshake() {
+        let name = "polychat_process_state_handshake".to_string();
+        let proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+        let mut state = proc.subscribe_state();
+        assert_eq!(ProcessState::Spawned, proc.get_state());
+
+        let mut comms = create_socket_client(&name, &proc).await;
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(5), state.wait_for(|s| *s == ProcessState::Connected)).await));
+        assert_ok!(comms.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(5), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+    }
+
+    /// Tests that connections that don't present the process's auth token are
+    /// closed without anything they sent being accepted, and that the plugin
+    /// can still connect after them.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_connection_without_token_is_closed() {
+        let name = "polychat_process_auth_token".to_string();
+        let mut proc = assert_ok!(Process::new(TEST_PROGRAM, create_socket_server(&name)));
+
+        let mut impostor = assert_ok!(SocketCommunicator::new(&get_socket_name(&name)).await);
+        assert_ok!(impostor.authenticate("not the token").await);
+        assert_ok!(impostor.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        let err = assert_err!(impostor.recv_plugin_instruction().await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Closed)), "Unexpected error {}", err);
+
+        // Skipping the token entirely doesn't work either.
+        let mut impostor = assert_ok!(SocketCommunicator::new(&get_socket_name(&name)).await);
+        assert_ok!(impostor.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        assert_err!(impostor.recv_plugin_instruction().await);
+        assert_eq!(ProcessState::Spawned, proc.get_state());
+        assert!(proc.get_init_data().is_none());
+
+        let mut comms = create_socket_client(&name, &proc).await;
+        assert_ok!(comms.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::InitAck, ack.instruction_type);
+        let init = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(CoreInstructionType::Init, init.instruction_type);
+    }
+
+    /// Tests that a plugin that connects, but never sends Init, fails to load.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_init_times_out() {
+        let name = "polychat_process_init_timeout".to_string();
+        let config = ProcessConfig { init_timeout: Duration::from_millis(50), ..Default::default() };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name), config, errors_tx));
+        let _comms = create_socket_client(&name, &proc).await;
+
+        let err = assert_some!(errors_rx.recv().await);
+        assert!(matches!(err, PluginError::InitTimedOut { .. }), "Unexpected error {}", err);
+        assert_eq!(ProcessState::Connected, proc.get_state());
+    }
+
+    /// Tests that a plugin is told the largest message it can send, and is
+    /// disconnected with a typed error if it sends a larger one.
+    #[rstest]
+    #[case(vec![Framing::Newline])]
+    #[case(vec![Framing::LengthPrefixed])]
+    #[test_log::test(tokio::test)]
+    async fn test_message_too_large_disconnects(#[case] framing: Vec<Framing>) {
+        let name = format!("polychat_process_message_too_large_{:?}", framing[0]);
+        let config = ProcessConfig {
+            limits: MessageLimits { max_message_size: 1024, ..Default::default() },
+            ..Default::default()
+        };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name), config, errors_tx));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        assert_ok!(comms.send_core_instruction(&create_init_negotiating(CORE_API_VERSION, framing, vec![])).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        let ack = assert_ok!(ack.payload.decode::<InitAckInstruction>());
+        assert_eq!(1024, ack.max_frame_size);
+        // Not switched to the ack's maximum, so the plugin's end doesn't refuse to send it.
+        comms.set_framing(ack.framing, 4096).await;
+
+        let large = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: Payload::from(assert_ok!(RawValue::from_string(format!("\"{}\"", "a".repeat(2048)))))
+        };
+        assert_ok!(comms.send_core_instruction(&large).await);
+        let err = assert_some!(errors_rx.recv().await);
+        assert!(matches!(err, PluginError::MessageTooLarge { max_message_size: 1024, .. }), "Unexpected error {}", err);
+        assert!(proc.is_disconnected());
+        assert_protocol_violation(&mut comms).await;
+    }
+
+    /// Tests that Log and Init instructions whose payloads can't be decoded
+    /// count towards the plugin's malformed messages.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_malformed_payloads_disconnect() {
+        let name = "polychat_process_malformed_payloads".to_string();
+        let config = ProcessConfig {
+            limits: MessageLimits { max_malformed_messages: 1, ..Default::default() },
+            ..Default::default()
+        };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name), config, errors_tx));
+        let mut comms = create_socket_client(&name, &proc).await;
+
+        for instruction_type in [CoreInstructionType::Init, CoreInstructionType::Log] {
+            let malformed = SerializableCoreInstr {
+                instruction_type,
+                request_id: None,
+                payload: Payload::from(assert_ok!(RawValue::from_string("[]".to_string())))
+            };
+            assert_ok!(comms.send_core_instruction(&malformed).await);
+        }
+        let err = assert_some!(errors_rx.recv().await);
+        assert!(matches!(err, PluginError::MalformedMessages { count: 2, .. }), "Unexpected error {}", err);
+        assert!(proc.is_disconnected());
+        assert_protocol_violation(&mut comms).await;
+    }
+
+    /// Tests that a plugin can send a few malformed messages, but is
+    /// disconnected with a typed error once it sends too many.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn test_malformed_messages_disconnect() {
+        let name = "polychat_process_malformed".to_string();
+        let config = ProcessConfig {
+            limits: MessageLimits { max_malformed_messages: 2, ..Default::default() },
+            ..Default::default()
+        };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let mut proc = assert_ok!(Process::with_config(TEST_PROGRAM, create_socket_server(&name), config, errors_tx));
+        let mut conn = assert_ok!(LocalSocketStream::connect(get_socket_name(&name)).await);
+        assert_ok!(conn.write_all(format!("{{\"token\":\"{}\"}}\n", proc.get_auth_token()).as_bytes()).await);
+
+        assert_ok!(conn.write_all(b"not an instruction\n{\"instruction_type\":\"Unknown\"}\n").await);
+        let valid = SerializableCoreInstr {
+            instruction_type: CoreInstructionType::AuthAccountResponse,
+            request_id: None,
+            payload: create_core_payload()
+        };
+        assert_ok!(conn.write_all(format!("{}\n", assert_ok!(serde_json::to_string(&valid))).as_bytes()).await);
+        let recv_data = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(valid, recv_data.into());
+        assert_eq!(2, proc.get_malformed_count());
+        assert!(!proc.is_disconnected());
+
+        assert_ok!(conn.write_all(b"[]\n").await);
+        let err = assert_some!(errors_rx.recv().await);
+        assert!(matches!(err, PluginError::MalformedMessages { count: 3, .. }), "Unexpected error {}", err);
+        assert!(proc.is_disconnected());
+    }
+
+    /// Checks that the plugin is told it broke the protocol, then disconnected.
+    async fn assert_protocol_violation(comms: &mut SocketCommunicator) {
+        let shutdown = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::Shutdown, shutdown.instruction_type);
+        let shutdown = assert_ok!(shutdown.payload.decode::<ShutdownInstruction>());
+        assert_eq!(ShutdownReason::ProtocolViolation, shutdown.reason);
+        let err = assert_err!(comms.recv_plugin_instruction().await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Closed)), "Unexpected error {}", err);
+    }
+
+    /// Sends a compatible Init as the plugin, and waits for it to be accepted.
+    async fn initialize(comms: &mut SocketCommunicator, proc: &mut Process) {
+        assert_ok!(comms.send_core_instruction(&create_init(CORE_API_VERSION)).await);
+        let ack = assert_ok!(comms.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::InitAck, ack.instruction_type);
+        let init = assert_some!(assert_ok!(proc.get_next_instruction().await));
+        assert_eq!(CoreInstructionType::Init, init.instruction_type);
+    }
+
+    fn create_init(api_version: Version) -> SerializableCoreInstr<InitDataInstruction> {
+        create_init_negotiating(api_version, vec![], vec![])
+    }
+
+    fn create_init_negotiating(api_version: Version, framing: Vec<Framing>, encodings: Vec<Encoding>)
+        -> SerializableCoreInstr<InitDataInstruction>
+    {
+        SerializableCoreInstr {
+            instruction_type: CoreInstructionType::Init,
+            request_id: None,
+            payload: InitDataInstruction {
+                api_version,
+                plugin_version: Version { major: 0, minor: 1, patch: 0 },
+                protocol_data: ProtocolData {
+                    protocol_service_name: "test".to_string(),
+                    auth_methods: vec![],
+                    capabilities: MessageCapabilities::default(),
+                },
+                framing,
+                encodings,
+            }
+        }
+    }
+
     // Used for creating a core socket server
     pub fn create_socket_server(name: &String) -> SocketHandler {
         assert_ok!(SocketHandler::new(name))
     }
 
     // Used for creating the socket client, which is plugin SDK code.
-    async fn create_socket_client(name: &String) -> SocketCommunicator {
-        assert_ok!(SocketCommunicator::new(name).await)
+    // Presents the process's auth token, as a plugin it started would.
+    async fn create_socket_client(name: &String, proc: &Process) -> SocketCommunicator {
+        let mut comms = assert_ok!(SocketCommunicator::new(&get_socket_name(name)).await);
+        assert_ok!(comms.authenticate(proc.get_auth_token()).await);
+        comms
     }
     
-    /// Generates an empty RawValue for use in testing everything but payload transfer.
-    fn create_core_payload() -> Box<RawValue> {
+    /// Generates an empty payload for use in testing everything but payload transfer.
+    fn create_core_payload() -> Payload {
         let payload = r#"{}"#;
-        serde_json::value::RawValue::from_string(payload.to_string()).unwrap()
+        serde_json::value::RawValue::from_string(payload.to_string()).unwrap().into()
     }
 }
\ No newline at end of file
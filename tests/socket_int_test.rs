@@ -3,37 +3,55 @@ mod test {
     use rstest::*;
     use serde_json::value::RawValue;
 
-    use claims::assert_ok;
+    use claims::{assert_err, assert_ok, assert_some};
+    use futures::AsyncWriteExt;
+    use interprocess::local_socket::tokio::{LocalSocketListener, LocalSocketStream};
+    use serde::Serialize;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::time::timeout;
 
     use polychat_ipc::{
-        core::socket_handler::SocketHandler,
+        core::{socket_handler::SocketHandler, transport::SocketPair},
         polychat_plugin_sdk_rust::socket::SocketCommunicator,
+        utils::{
+            auth::AuthToken,
+            socket::{get_socket_name, default_socket_dir, prepare_socket_dir, remove_stale_sockets, SocketError}
+        },
         api::schema::{
             instructions::{
                 CoreInstructionType,
                 PluginInstructionType, SerializablePluginInstr, SerializableCoreInstr
-            }
+            },
+            protocol::{Framing, Encoding, DEFAULT_MAX_FRAME_SIZE, INHERITED_FD_PREFIX},
+            log::{LogInstruction, LogLevel}
         }
     };
 
+    // Enough that they can't all be read one at a time by chance.
+    const BACK_TO_BACK_COUNT: u64 = 50;
+
     #[rstest]
     #[case(CoreInstructionType::Init)]
     #[case(CoreInstructionType::KeepaliveResponse)]
     #[case(CoreInstructionType::AuthAccountResponse)]
+    #[case(CoreInstructionType::Log)]
     #[test_log::test(tokio::test)]
     async fn integration_test_core_instruction_sending(#[case] ins_type: CoreInstructionType){
         let socket_name = format!("int_test_{}", ins_type);
         let mut handler = create_handler(&socket_name);
 
         let mut comm = create_communicator(&socket_name).await;
+        let (mut reader, _writer) = assert_ok!(handler.accept().await);
         let instruct = SerializableCoreInstr {
             payload: create_core_payload(),
+            request_id: None,
             instruction_type: ins_type
         };
 
         let send_res = comm.send_core_instruction(&instruct);
 
-        let recv_res = handler.get_instruction();
+        let recv_res = reader.get_instruction();
         assert_ok!(send_res.await);
 
         let recv_res = assert_ok!(recv_res.await);
@@ -45,30 +63,453 @@ mod test {
     #[rstest]
     #[case(PluginInstructionType::Keepalive)]
     #[case(PluginInstructionType::AuthAccount)]
+    #[case(PluginInstructionType::InitAck)]
+    #[case(PluginInstructionType::InitRejected)]
+    #[case(PluginInstructionType::Shutdown)]
     #[test_log::test(tokio::test)]
     async fn integration_test_plugin_instruction_client(#[case] ins_type: PluginInstructionType) {
         let socket_name = format!("client_ins_{}", ins_type);
         let mut server = create_handler(&socket_name);
         let mut client = create_communicator(&socket_name).await;
+        let (_reader, mut writer) = assert_ok!(server.accept().await);
 
         let instruct = SerializablePluginInstr {
             payload: create_core_payload(),
+            request_id: 1,
             instruction_type: ins_type
         };
 
-        assert_ok!(server.send_plugin_instruction(&instruct).await);
+        assert_ok!(writer.send_plugin_instruction(&instruct).await);
 
         let recv = assert_ok!(client.recv_plugin_instruction().await);
         assert_eq!(instruct.instruction_type, recv.instruction_type);
+        assert_eq!(instruct.request_id, recv.request_id);
+        assert_eq!(instruct.payload.to_string(), recv.payload.to_string());
+    }
+
+    /// Tests that the writer can send while the reader is waiting for data.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_send_while_reading() {
+        let socket_name = "int_test_send_while_reading".to_string();
+        let mut handler = create_handler(&socket_name);
+        let (accepted, mut client) = tokio::join!(handler.accept(), create_communicator(&socket_name));
+        let (mut reader, mut writer) = assert_ok!(accepted);
+
+        // Nothing has been sent by the plugin, so this waits until it's dropped.
+        let pending_read = tokio::spawn(async move { reader.get_instruction().await.map(|_| ()) });
+
+        let instruct = SerializablePluginInstr {
+            payload: create_core_payload(),
+            request_id: 1,
+            instruction_type: PluginInstructionType::Keepalive
+        };
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(1), writer.send_plugin_instruction(&instruct)).await));
+        let recv = assert_ok!(client.recv_plugin_instruction().await);
+        assert_eq!(instruct.request_id, recv.request_id);
+        assert!(!pending_read.is_finished());
+        pending_read.abort();
+    }
+
+    /// Tests that instructions written to the socket all at once are each
+    /// received, rather than only the first one in the read.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_core_instructions_in_one_write() {
+        let socket_name = "int_test_core_one_write".to_string();
+        let mut handler = create_handler(&socket_name);
+        let mut client = assert_ok!(LocalSocketStream::connect(get_socket_name(&socket_name)).await);
+        let (mut reader, _writer) = assert_ok!(handler.accept().await);
+
+        let instructs: Vec<_> = (0..BACK_TO_BACK_COUNT).map(|i| SerializableCoreInstr {
+            payload: create_indexed_payload(i),
+            request_id: Some(i),
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        }).collect();
+        assert_ok!(client.write_all(join_lines(&instructs).as_bytes()).await);
+
+        for instruct in instructs {
+            let recv = assert_ok!(assert_ok!(timeout(Duration::from_secs(1), reader.get_instruction()).await));
+            assert_eq!(instruct.request_id, recv.request_id);
+            assert_eq!(instruct.payload.to_string(), recv.payload.to_string());
+        }
+    }
+
+    /// Tests the same for the plugin's end of the connection.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_plugin_instructions_in_one_write() {
+        let socket_name = get_socket_name("int_test_plugin_one_write");
+        // Left behind if an earlier run failed, since there's no SocketHandler to clean it up.
+        let _ = std::fs::remove_file(&socket_name);
+        assert_ok!(prepare_socket_dir(&default_socket_dir()));
+        let listener = assert_ok!(LocalSocketListener::bind(socket_name.clone()));
+        let (server, mut client) = tokio::join!(listener.accept(), create_communicator_at(&socket_name));
+        let mut server = assert_ok!(server);
+
+        let instructs: Vec<_> = (0..BACK_TO_BACK_COUNT).map(|i| SerializablePluginInstr {
+            payload: create_indexed_payload(i),
+            request_id: i,
+            instruction_type: PluginInstructionType::Keepalive
+        }).collect();
+        assert_ok!(server.write_all(join_lines(&instructs).as_bytes()).await);
+
+        for instruct in instructs {
+            let recv = assert_ok!(assert_ok!(timeout(Duration::from_secs(1), client.recv_plugin_instruction()).await));
+            assert_eq!(instruct.request_id, recv.request_id);
+            assert_eq!(instruct.payload.to_string(), recv.payload.to_string());
+        }
+        drop(client);
+        let _ = std::fs::remove_file(&socket_name);
+    }
+
+    /// Tests that length prefixed frames can carry raw newlines in both directions.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_length_prefixed_framing() {
+        let socket_name = "int_test_length_prefixed".to_string();
+        let mut handler = create_handler(&socket_name);
+        let (accepted, mut client) = tokio::join!(handler.accept(), create_communicator(&socket_name));
+        let (mut reader, mut writer) = assert_ok!(accepted);
+        reader.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        writer.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        client.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE).await;
+
+        let core_instruct = SerializableCoreInstr {
+            payload: create_multiline_payload(),
+            request_id: None,
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        };
+        assert_ok!(client.send_core_instruction(&core_instruct).await);
+        let recv = assert_ok!(reader.get_instruction().await);
+        assert_eq!(core_instruct.payload.to_string(), recv.payload.to_string());
+
+        let plugin_instruct = SerializablePluginInstr {
+            payload: create_multiline_payload(),
+            request_id: 1,
+            instruction_type: PluginInstructionType::AuthAccount
+        };
+        assert_ok!(writer.send_plugin_instruction(&plugin_instruct).await);
+        let recv = assert_ok!(client.recv_plugin_instruction().await);
+        assert_eq!(plugin_instruct.payload.to_string(), recv.payload.to_string());
+    }
+
+    /// Tests that frames over the maximum size are refused by both the sender and the receiver.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_frame_too_large() {
+        let socket_name = "int_test_frame_too_large".to_string();
+        let mut handler = create_handler(&socket_name);
+        let (accepted, mut client) = tokio::join!(handler.accept(), create_communicator(&socket_name));
+        let (mut reader, _writer) = assert_ok!(accepted);
+        let instruct = SerializableCoreInstr {
+            payload: create_multiline_payload(),
+            request_id: None,
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        };
+        let size = assert_ok!(serde_json::to_string(&instruct)).len();
+
+        client.set_framing(Framing::LengthPrefixed, size as u32 - 1).await;
+        let err = assert_err!(client.send_core_instruction(&instruct).await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::FrameTooLarge { .. })), "Unexpected error {}", err);
+
+        client.set_framing(Framing::LengthPrefixed, size as u32).await;
+        reader.set_framing(Framing::LengthPrefixed, size as u32 - 1);
+        assert_ok!(client.send_core_instruction(&instruct).await);
+        let err = assert_err!(reader.get_instruction().await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::FrameTooLarge { .. })), "Unexpected error {}", err);
+    }
+
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_accept_authenticated() {
+        let socket_name = "int_test_accept_authenticated".to_string();
+        let mut handler = create_handler(&socket_name);
+        let token = AuthToken::generate();
+        let instruct = SerializableCoreInstr {
+            payload: create_core_payload(),
+            request_id: None,
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        };
+
+        let plugin = async {
+            let mut impostor = create_communicator(&socket_name).await;
+            assert_ok!(impostor.authenticate(&AuthToken::generate().as_str()[1..]).await);
+            assert_ok!(impostor.send_core_instruction(&instruct).await);
+            assert_err!(impostor.recv_plugin_instruction().await);
+
+            let mut client = create_communicator(&socket_name).await;
+            assert_ok!(client.authenticate(token.as_str()).await);
+            assert_ok!(client.send_core_instruction(&instruct).await);
+            client
+        };
+        let (accepted, _client) = tokio::join!(handler.accept_authenticated(&token), plugin);
+        let (mut reader, _writer) = assert_ok!(accepted);
+        let recv = assert_ok!(reader.get_instruction().await);
         assert_eq!(instruct.payload.to_string(), recv.payload.to_string());
     }
 
+    /// Tests that connections that never send anything don't hold up the
+    /// plugin's, since each connection is authenticated on its own.
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_silent_connections_do_not_block_auth() {
+        let socket_name = "int_test_silent_connections".to_string();
+        let mut handler = create_handler(&socket_name);
+        let token = AuthToken::generate();
+
+        let plugin = async {
+            let mut silent = vec![];
+            for _ in 0..5 {
+                silent.push(assert_ok!(LocalSocketStream::connect(get_socket_name(&socket_name)).await));
+            }
+            let mut client = create_communicator(&socket_name).await;
+            assert_ok!(client.authenticate(token.as_str()).await);
+            (silent, client)
+        };
+        // Well under the time each silent connection has to authenticate.
+        let accepted = timeout(Duration::from_millis(500), async {
+            tokio::join!(handler.accept_authenticated(&token), plugin)
+        });
+        let (accepted, _connections) = assert_ok!(accepted.await);
+        assert_ok!(accepted);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_socket_pair() {
+        let mut pair = assert_ok!(SocketPair::new());
+        let token = AuthToken::generate();
+        let mut client = create_pair_communicator(&mut pair).await;
+        assert_ok!(client.authenticate(token.as_str()).await);
+        let instruct = SerializableCoreInstr {
+            payload: create_core_payload(),
+            request_id: None,
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        };
+        assert_ok!(client.send_core_instruction(&instruct).await);
+
+        let (mut reader, mut writer) = assert_ok!(pair.accept_authenticated(&token, Duration::from_secs(1)).await);
+        let recv = assert_ok!(reader.get_instruction().await);
+        assert_eq!(instruct.payload.to_string(), recv.payload.to_string());
+        let plugin_instruct = SerializablePluginInstr {
+            payload: create_core_payload(),
+            request_id: 1,
+            instruction_type: PluginInstructionType::Keepalive
+        };
+        assert_ok!(writer.send_plugin_instruction(&plugin_instruct).await);
+        let recv = assert_ok!(client.recv_plugin_instruction().await);
+        assert_eq!(PluginInstructionType::Keepalive, recv.instruction_type);
+
+        // The core doesn't hold the plugin's end open, so the plugin going away closes the connection.
+        drop(client);
+        let err = assert_err!(reader.get_instruction().await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Closed)), "Unexpected error {}", err);
+    }
+
+    /// Tests that a plugin that inherits its connection has as long as it's
+    /// given to present its token, since it may take a while to start.
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_socket_pair_waits_for_slow_plugin() {
+        let mut pair = assert_ok!(SocketPair::new());
+        let token = AuthToken::generate();
+        let mut client = create_pair_communicator(&mut pair).await;
+        let plugin = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_ok!(client.authenticate(token.as_str()).await);
+        };
+        let (accepted, _) = tokio::join!(pair.accept_authenticated(&token, Duration::from_secs(5)), plugin);
+        assert_ok!(accepted);
+
+        // Nothing is presented at all here.
+        let mut pair = assert_ok!(SocketPair::new());
+        let _client = create_pair_communicator(&mut pair).await;
+        let err = assert_err!(pair.accept_authenticated(&token, Duration::from_millis(100)).await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Unauthenticated)), "Unexpected error {}", err);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_socket_pair_unauthenticated() {
+        let mut pair = assert_ok!(SocketPair::new());
+        let mut client = create_pair_communicator(&mut pair).await;
+        assert_ok!(client.authenticate(AuthToken::generate().as_str()).await);
+
+        let err = assert_err!(pair.accept_authenticated(&AuthToken::generate(), Duration::from_secs(1)).await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Unauthenticated)), "Unexpected error {}", err);
+        // Unlike a named socket, there's no other connection to wait for.
+        let err = assert_err!(pair.accept());
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::Closed)), "Unexpected error {}", err);
+    }
+
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_line_too_large() {
+        let socket_name = "int_test_line_too_large".to_string();
+        let mut handler = create_handler(&socket_name);
+        let (accepted, mut client) = tokio::join!(handler.accept(), create_communicator(&socket_name));
+        let (mut reader, _writer) = assert_ok!(accepted);
+        let instruct = SerializableCoreInstr {
+            payload: create_core_payload(),
+            request_id: None,
+            instruction_type: CoreInstructionType::AuthAccountResponse
+        };
+        let size = assert_ok!(serde_json::to_string(&instruct)).len();
+
+        // The newline doesn't count.
+        reader.set_max_frame_size(size as u32);
+        assert_ok!(client.send_core_instruction(&instruct).await);
+        assert_ok!(reader.get_instruction().await);
+
+        reader.set_max_frame_size(size as u32 - 1);
+        assert_ok!(client.send_core_instruction(&instruct).await);
+        let err = assert_err!(reader.get_instruction().await);
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::FrameTooLarge { .. })), "Unexpected error {}", err);
+    }
+
+    #[rstest]
+    #[case(Encoding::MessagePack)]
+    #[case(Encoding::Cbor)]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_binary_encoding(#[case] encoding: Encoding) {
+        let socket_name = format!("int_test_encoding_{}", encoding);
+        let mut handler = create_handler(&socket_name);
+        let (accepted, mut client) = tokio::join!(handler.accept(), create_communicator(&socket_name));
+        let (mut reader, mut writer) = assert_ok!(accepted);
+        reader.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        reader.set_encoding(encoding);
+        writer.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE);
+        writer.set_encoding(encoding);
+        client.set_framing(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_SIZE).await;
+        client.set_encoding(encoding).await;
+
+        let core_instruct = SerializableCoreInstr {
+            payload: create_log(),
+            request_id: Some(1),
+            instruction_type: CoreInstructionType::Log
+        };
+        assert_ok!(client.send_core_instruction(&core_instruct).await);
+        let recv = assert_ok!(reader.get_instruction().await);
+        assert_eq!(encoding, recv.payload.encoding());
+        assert_eq!(Some(1), recv.request_id);
+        assert_eq!(create_log(), assert_ok!(recv.payload.decode::<LogInstruction>()));
+
+        let plugin_instruct = SerializablePluginInstr {
+            payload: create_log(),
+            request_id: 2,
+            instruction_type: PluginInstructionType::AuthAccount
+        };
+        assert_ok!(writer.send_plugin_instruction(&plugin_instruct).await);
+        let recv = assert_ok!(client.recv_plugin_instruction().await);
+        assert_eq!(encoding, recv.payload.encoding());
+        assert_eq!(2, recv.request_id);
+        assert_eq!(create_log(), assert_ok!(recv.payload.decode::<LogInstruction>()));
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_socket_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_parent, dir) = create_socket_dir("permissions");
+        let handler = assert_ok!(SocketHandler::new_in(&dir, "int_test_permissions"));
+        let dir_mode = assert_ok!(std::fs::metadata(&dir)).permissions().mode();
+        assert_eq!(0o700, dir_mode & 0o777);
+        let socket_mode = assert_ok!(std::fs::metadata(handler.get_socket_name())).permissions().mode();
+        assert_eq!(0o600, socket_mode & 0o777);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_insecure_socket_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (_parent, dir) = create_socket_dir("insecure");
+        assert_ok!(std::fs::create_dir_all(&dir));
+        assert_ok!(std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)));
+        let err = assert_err!(SocketHandler::new_in(&dir, "int_test_insecure"));
+        assert!(matches!(err.downcast_ref::<SocketError>(), Some(SocketError::InsecureDirectory(_))), "Unexpected error {}", err);
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_remove_stale_sockets() {
+        use std::os::unix::net::UnixListener;
+
+        let (_parent, dir) = create_socket_dir("stale");
+        assert_ok!(prepare_socket_dir(&dir));
+        let live = assert_ok!(SocketHandler::new_in(&dir, "int_test_live"));
+        // As if a core crashed while it was listening.
+        let stale = dir.join("int_test_stale.sock");
+        drop(assert_ok!(UnixListener::bind(&stale)));
+        let other_file = dir.join("not_a_socket");
+        assert_ok!(std::fs::write(&other_file, ""));
+
+        assert_eq!(1, assert_ok!(remove_stale_sockets(&dir)));
+        assert!(!stale.exists());
+        assert!(std::path::Path::new(live.get_socket_name()).exists());
+        assert!(other_file.exists());
+    }
+
+    /// Returns a socket directory that doesn't exist yet, in a temporary
+    /// directory that's removed once the returned [TempDir] is dropped.
+    fn create_socket_dir(name: &str) -> (TempDir, std::path::PathBuf) {
+        let parent = assert_ok!(tempfile::tempdir());
+        let dir = parent.path().join(format!("polychat-int-test-{}", name));
+        (parent, dir)
+    }
+
+    /// Connects to the plugin's end of the pair the way a plugin that inherited it would.
+    #[cfg(unix)]
+    async fn create_pair_communicator(pair: &mut SocketPair) -> SocketCommunicator {
+        use std::os::unix::io::{BorrowedFd, IntoRawFd};
+
+        let fd = assert_some!(pair.get_plugin_fd());
+        // As the plugin's own copy would be, after it's started.
+        let fd = assert_ok!(unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned());
+        pair.close_plugin_end();
+        assert_ok!(SocketCommunicator::connect(&format!("{}{}", INHERITED_FD_PREFIX, fd.into_raw_fd())).await)
+    }
+
     fn create_handler(name: &String) -> SocketHandler {
         assert_ok!(SocketHandler::new(name))
     }
 
     async fn create_communicator(name: &String) -> SocketCommunicator {
-        assert_ok!(SocketCommunicator::new(name).await)
+        create_communicator_at(&get_socket_name(name)).await
+    }
+
+    async fn create_communicator_at(socket_name: &str) -> SocketCommunicator {
+        assert_ok!(SocketCommunicator::new(socket_name).await)
+    }
+
+    fn create_multiline_payload() -> Box<RawValue> {
+        assert_ok!(RawValue::from_string("{\n  \"first\": 1,\n  \"second\": 2\n}".to_string()))
+    }
+
+    fn create_log() -> LogInstruction {
+        LogInstruction {
+            level: LogLevel::Warn,
+            target: "int_test".to_string(),
+            message: "A message\nwith a newline".to_string(),
+            fields: [("protocol".to_string(), "test".to_string())].into(),
+        }
+    }
+
+    fn create_indexed_payload(index: u64) -> Box<RawValue> {
+        assert_ok!(RawValue::from_string(format!("{{\"index\":{}}}", index)))
+    }
+
+    /// Serializes each instruction onto its own line, the way they're sent.
+    fn join_lines<T: Serialize>(instructs: &[T]) -> String {
+        instructs.iter().map(|i| assert_ok!(serde_json::to_string(i)) + "\n").collect()
     }
 
     fn create_core_payload() -> Box<RawValue> {
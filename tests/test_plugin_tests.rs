@@ -3,13 +3,42 @@
 
 #[cfg(test)]
 mod test {
-    use polychat_ipc::{core::socket_handler::SocketHandler, api::schema::{instructions::CoreInstructionType, protocol::InitDataInstruction}, process_management::process_manager::ProcessManager};
+    use polychat_ipc::{
+        core::{Core, error::CoreError, socket_handler::SocketHandler, transport::TransportKind},
+        api::schema::{
+            instructions::{CoreInstructionType, PluginInstructionType, SerializablePluginInstr, SerializableCoreInstr,
+                DeserializableCoreInstr},
+            protocol::{InitDataInstruction, InitAckInstruction, Version, Framing, Encoding, CORE_API_VERSION,
+                DEFAULT_MAX_FRAME_SIZE},
+            log::{LogInstruction, LogLevel},
+            message::{
+                ReactionAction, ReactToMessageInstruction, DeliveryState, MessageReceivedInstruction, Message,
+                EditMessageInstruction, DeleteMessageInstruction
+            },
+            conversation::Participant,
+            auth::{AccountIdentity, AuthAccountResponse, AuthResult},
+            shutdown::ShutdownReason,
+            keepalive::KeepaliveInstruction
+        },
+        process_management::{
+            process_manager::{ProcessManager, ProcessEvent},
+            manifest::{PluginManifest, ApiVersionRange, MANIFEST_FILE_NAME},
+            error::{ProcessManagerError, PluginError},
+            process::{Process, ProcessConfig, ShutdownConfig},
+            lifecycle::ProcessState,
+            keepalive::KeepaliveConfig,
+            request_tracker::RequestTimeouts,
+            output::{OutputLine, OutputStream}
+        }
+    };
     use rstest::*;
-    use claims::assert_ok;
+    use claims::{assert_err, assert_ok, assert_some};
+    use std::{collections::HashMap, time::Duration};
+    use tokio::{sync::mpsc, time::timeout};
     use std::process::Command;
     use assert_cmd::prelude::*; // Add methods on command
     use log::debug;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     use testdir::testdir;
 
     /**
@@ -23,28 +52,69 @@ mod test {
     async fn integration_test_plugin_init() {
         // Start the component from core that starts the IPC connections.
         debug!("Starting socket");
-        let socket_name = format!("test_plugin_test_init");
+        let socket_name = "test_plugin_test_init".to_string();
         let mut handler = create_handler(socket_name.clone());
         
         // Start the plugin
         // Does not use ProcessManager in order to isolate this test to the plugin itself.
         debug!("Starting plugin");
         let mut cmd = Command::cargo_bin("test-plugin").unwrap();
-        cmd.arg(socket_name.clone());
-        let plugin_output = cmd.unwrap();
-        debug!("Output of plugin: {:?}", plugin_output);
+        cmd.arg(handler.get_socket_name());
+        // The plugin runs until the connection closes, so don't wait for it to exit.
+        let mut plugin = cmd.spawn().unwrap();
         debug!("Started plugin. Now receiving instruction from plugin.");
 
         // Await the init instruction
-        let recv_res = handler.get_instruction();
+        let (mut reader, _writer) = assert_ok!(handler.accept().await);
+        let recv_res = reader.get_instruction();
         let recv_res = assert_ok!(recv_res.await);
         assert_eq!(CoreInstructionType::Init, recv_res.instruction_type);
         debug!("Received Init. Now validating that it can deserialize it.");
-        let deserialized_instr = serde_json::from_str::<InitDataInstruction>(recv_res.payload.get());
+        let deserialized_instr = recv_res.payload.decode::<InitDataInstruction>();
         assert_ok!(deserialized_instr);
+        assert_ok!(plugin.kill());
+        assert_ok!(plugin.wait());
         debug!("Done");
     }
 
+    /**
+     * This function verifies that the plugin's log records are sent to the core
+     * as Log instructions once its Init is accepted.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_plugin_log_forwarding() {
+        let mut handler = create_handler("test_plugin_test_log".to_string());
+        let mut cmd = Command::cargo_bin("test-plugin").unwrap();
+        cmd.arg(handler.get_socket_name());
+        let mut plugin = cmd.spawn().unwrap();
+
+        let (mut reader, mut writer) = assert_ok!(handler.accept().await);
+        let init = assert_ok!(reader.get_instruction().await);
+        assert_eq!(CoreInstructionType::Init, init.instruction_type);
+        // Keeps the newline framing and JSON, since the handler is used directly.
+        let ack = SerializablePluginInstr {
+            instruction_type: PluginInstructionType::InitAck,
+            request_id: 1,
+            payload: InitAckInstruction {
+                core_api_version: CORE_API_VERSION,
+                framing: Framing::Newline,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                encoding: Encoding::Json
+            }
+        };
+        assert_ok!(writer.send_plugin_instruction(&ack).await);
+        let log = assert_ok!(reader.get_instruction().await);
+        assert_eq!(CoreInstructionType::Log, log.instruction_type);
+        let record = assert_ok!(log.payload.decode::<LogInstruction>());
+        assert_eq!(LogLevel::Info, record.level);
+        assert_eq!("The core accepted this plugin", record.message);
+        assert_eq!(Some(&"example_protocol".to_string()), record.fields.get("protocol"));
+
+        assert_ok!(plugin.kill());
+        assert_ok!(plugin.wait());
+    }
+
     fn create_handler(name: String) -> SocketHandler {
         assert_ok!(SocketHandler::new(name))
     }
@@ -72,9 +142,175 @@ mod test {
             debug!("Adding test plugin at \"{:?}\" to plugin folder at \"{:?}\"", &test_plugin_binary, &plugin_dir);
             let exe_in_plugin_dir = plugin_dir.join("test_plugin.exe"); // exe shouldn't matter on unix
             assert_ok!(std::fs::copy(&test_plugin_binary, &exe_in_plugin_dir));
+            write_manifest(&plugin_dir, &format!("test-plugin-{}", i), "test_plugin.exe", HashMap::new());
         }
         debug!("Created test plugin dir. Testing loading from the path.");
-        assert_ok!(ProcessManager::from_dir_path(plugins_dir));
+        let process_manager = assert_ok!(ProcessManager::from_dir_path(plugins_dir));
+        assert_eq!(plugin_count as usize, process_manager.get_processes().len());
+        for process in process_manager.get_processes() {
+            let manifest = assert_some!(process.get_manifest());
+            assert_eq!(manifest.executable, process.get_path());
+        }
+    }
+
+    /**
+     * This function verifies that a plugin directory without a manifest is not
+     * loaded, and doesn't keep the other plugins from loading.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_process_manager_missing_manifest() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let plugins_dir: PathBuf = testdir!();
+        let plugin_dir = plugins_dir.join("plugin");
+        assert_ok!(std::fs::create_dir(&plugin_dir));
+        assert_ok!(std::fs::copy(&test_plugin_binary, plugin_dir.join("test_plugin.exe")));
+        let err = assert_err!(ProcessManager::new().load_plugin(&plugin_dir));
+        let err = assert_some!(err.downcast_ref::<ProcessManagerError>());
+        assert!(matches!(err, ProcessManagerError::MissingManifest(_)), "Unexpected error {}", err);
+
+        let valid_dir = plugins_dir.join("valid");
+        assert_ok!(std::fs::create_dir(&valid_dir));
+        assert_ok!(std::fs::copy(&test_plugin_binary, valid_dir.join("test_plugin.exe")));
+        write_manifest(&valid_dir, "test-plugin", "test_plugin.exe", HashMap::new());
+        let process_manager = assert_ok!(ProcessManager::from_dir_path(plugins_dir));
+        assert_eq!(1, process_manager.get_processes().len());
+        assert_eq!(valid_dir.join("test_plugin.exe"), process_manager.get_processes()[0].get_path());
+    }
+
+    /**
+     * This function verifies that a plugin whose manifest needs an API version
+     * the core isn't compatible with is never started.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_process_manager_incompatible_manifest() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let plugin_dir: PathBuf = testdir!();
+        assert_ok!(std::fs::copy(&test_plugin_binary, plugin_dir.join("test_plugin.exe")));
+        let manifest = PluginManifest {
+            api_version: ApiVersionRange {
+                min: Version { major: 99, minor: 0, patch: 0 },
+                max: Version { major: 99, minor: 0, patch: 0 },
+            },
+            ..create_manifest("test-plugin", "test_plugin.exe", HashMap::new())
+        };
+        assert_ok!(std::fs::write(plugin_dir.join(MANIFEST_FILE_NAME), assert_ok!(serde_json::to_string(&manifest))));
+
+        let mut process_manager = ProcessManager::new();
+        let err = assert_err!(process_manager.load_plugin(&plugin_dir));
+        let err = assert_some!(err.downcast_ref::<ProcessManagerError>());
+        assert!(matches!(err, ProcessManagerError::IncompatibleManifest { .. }), "Unexpected error {}", err);
+        assert!(process_manager.get_processes().is_empty());
+    }
+
+    /**
+     * This function verifies that when two plugins provide the same protocol,
+     * only the newest version is kept once they have both sent Init.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_process_manager_keeps_newest_version() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let plugins_dir: PathBuf = testdir!();
+        for version in ["0.1.0", "0.2.0"] {
+            let plugin_dir = plugins_dir.join(version);
+            assert_ok!(std::fs::create_dir(&plugin_dir));
+            assert_ok!(std::fs::copy(&test_plugin_binary, plugin_dir.join("test_plugin.exe")));
+            let env = HashMap::from([("TEST_PLUGIN_VERSION".to_string(), version.to_string())]);
+            write_manifest(&plugin_dir, "test-plugin", "test_plugin.exe", env);
+        }
+        let mut process_manager = assert_ok!(ProcessManager::from_dir_path(plugins_dir.clone()));
+
+        let err = assert_some!(assert_ok!(timeout(Duration::from_secs(10), process_manager.next_error()).await));
+        match err {
+            PluginError::Superseded { process_path, version, protocol_service_name, kept_path, kept_version } => {
+                assert_eq!(plugins_dir.join("0.1.0").join("test_plugin.exe"), process_path);
+                assert_eq!(Version { major: 0, minor: 1, patch: 0 }, version);
+                assert_eq!("example_protocol", protocol_service_name);
+                assert_eq!(plugins_dir.join("0.2.0").join("test_plugin.exe"), kept_path);
+                assert_eq!(Version { major: 0, minor: 2, patch: 0 }, kept_version);
+            },
+            other => panic!("Unexpected error {}", other)
+        }
+        assert_eq!(1, process_manager.get_processes().len());
+        let init = assert_some!(process_manager.get_processes()[0].get_init_data());
+        assert_eq!(Version { major: 0, minor: 2, patch: 0 }, init.plugin_version);
+    }
+
+    /**
+     * This function verifies that a plugin with an incompatible API version
+     * is rejected and stopped, rather than treated as crashed once it exits.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_process_manager_rejects_incompatible_api() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let plugins_dir: PathBuf = testdir!();
+        let plugin_dir = plugins_dir.join("plugin");
+        assert_ok!(std::fs::create_dir(&plugin_dir));
+        assert_ok!(std::fs::copy(&test_plugin_binary, plugin_dir.join("test_plugin.exe")));
+        let env = HashMap::from([("TEST_PLUGIN_API_VERSION".to_string(), "99.0.0".to_string())]);
+        write_manifest(&plugin_dir, "test-plugin", "test_plugin.exe", env);
+        let mut process_manager = assert_ok!(ProcessManager::from_dir_path(plugins_dir));
+
+        let err = assert_some!(assert_ok!(timeout(Duration::from_secs(10), process_manager.next_error()).await));
+        assert!(matches!(err, PluginError::IncompatibleApi { .. }), "Unexpected error {}", err);
+        assert!(process_manager.get_processes().is_empty());
+        // The plugin exits after being rejected, which is not a crash.
+        assert_err!(timeout(Duration::from_millis(500), process_manager.next_error()).await);
+    }
+
+    /**
+     * This function verifies that the plugin exits on its own when it's asked
+     * to shut down, well before it would be terminated, and that exiting
+     * isn't reported as a crash.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_plugin_shutdown() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let socket = create_handler("test_plugin_test_shutdown".to_string());
+        let config = ProcessConfig {
+            shutdown: ShutdownConfig { grace: Duration::from_secs(30), terminate_grace: Duration::from_secs(30) },
+            ..Default::default()
+        };
+        let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+        let process = assert_ok!(Process::with_config(test_plugin_binary, socket, config, errors_tx));
+        let mut state = process.subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+
+        assert_ok!(timeout(Duration::from_secs(10), process.shutdown(ShutdownReason::Requested)).await);
+        assert_eq!(ProcessState::Stopped, *state.borrow());
+        assert_err!(errors_rx.try_recv());
+    }
+
+    fn write_manifest(plugin_dir: &Path, id: &str, executable: &str, env: HashMap<String, String>) {
+        let manifest = assert_ok!(serde_json::to_string(&create_manifest(id, executable, env)));
+        assert_ok!(std::fs::write(plugin_dir.join(MANIFEST_FILE_NAME), manifest));
+    }
+
+    /// Creates an instruction as if a plugin had sent it.
+    fn create_core_instruction<P: serde::Serialize + std::fmt::Debug>(instruction_type: CoreInstructionType, payload: P)
+        -> DeserializableCoreInstr
+    {
+        let instr = SerializableCoreInstr { instruction_type, request_id: None, payload };
+        assert_ok!(DeserializableCoreInstr::decode(&assert_ok!(instr.encode(Encoding::Json)), Encoding::Json))
+    }
+
+    fn create_manifest(id: &str, executable: &str, env: HashMap<String, String>) -> PluginManifest {
+        PluginManifest {
+            name: "Test Plugin".to_string(),
+            id: id.to_string(),
+            version: Version { major: 0, minor: 1, patch: 0 },
+            executable: PathBuf::from(executable),
+            api_version: ApiVersionRange {
+                min: Version { major: 0, minor: 1, patch: 0 },
+                max: Version { major: 0, minor: 1, patch: 0 },
+            },
+            args: vec![],
+            env,
+        }
     }
 
     /**
@@ -91,4 +327,274 @@ mod test {
         assert_ok!(process_manager.load_process(&test_plugin_binary));
     }
 
+    /**
+     * This function verifies that a real plugin connects and is initialized
+     * over each transport.
+     */
+    #[cfg(unix)]
+    #[rstest]
+    #[case(TransportKind::NamedSocket)]
+    #[case(TransportKind::SocketPair)]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_process_manager_transport(#[case] transport: TransportKind) {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut process_manager = ProcessManager::new();
+        process_manager.set_transport(transport);
+        assert_ok!(process_manager.load_process(&test_plugin_binary));
+
+        let mut state = process_manager.get_processes()[0].subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+    }
+
+    /**
+     * This function verifies that a plugin's named socket is created in the
+     * socket directory, and that the sockets a previous core left behind in it
+     * are removed once the directory is set.
+     */
+    #[cfg(unix)]
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_sockets_are_created_in_socket_dir() {
+        use std::os::unix::net::UnixListener;
+        use polychat_ipc::utils::socket::prepare_socket_dir;
+
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let parent = assert_ok!(tempfile::tempdir());
+        let dir = parent.path().join("sockets");
+        assert_ok!(prepare_socket_dir(&dir));
+        // As if a core crashed while it was listening.
+        let stale = dir.join("stale.sock");
+        drop(assert_ok!(UnixListener::bind(&stale)));
+
+        let mut process_manager = ProcessManager::new();
+        process_manager.set_transport(TransportKind::NamedSocket);
+        process_manager.set_socket_dir(dir.clone());
+        assert!(!stale.exists());
+        assert_ok!(process_manager.load_process(&test_plugin_binary));
+        let mut state = process_manager.get_processes()[0].subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+        let sockets: Vec<_> = assert_ok!(std::fs::read_dir(&dir)).collect();
+        assert_eq!(1, sockets.len());
+    }
+
+    /**
+     * This function verifies that the core doesn't ask a plugin to react to a
+     * message when the plugin's Init says it can't.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_core_checks_capabilities() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        assert_ok!(core.get_process_manager().load_process(&test_plugin_binary));
+        let mut state = core.get_process_manager().get_processes()[0].subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+
+        let react = ReactToMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "42".to_string(),
+            reaction: "👍".to_string(),
+            action: ReactionAction::Add,
+        };
+        let err = assert_err!(core.react_to_message(&test_plugin_binary, react).await);
+        assert!(matches!(err.downcast_ref::<CoreError>(), Some(CoreError::Unsupported { .. })), "Unexpected error {}", err);
+    }
+
+    /**
+     * This function verifies that the core receives the instructions a plugin
+     * sends, and forgets the plugin's accounts once it's restarted.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_core_handles_plugin_events() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        assert_ok!(core.get_process_manager().load_process(&test_plugin_binary));
+
+        let event = assert_some!(assert_ok!(timeout(Duration::from_secs(10), core.next_event()).await));
+        match event {
+            ProcessEvent::Instruction { process_path, instruction } => {
+                assert_eq!(test_plugin_binary, process_path);
+                assert_eq!(CoreInstructionType::Init, instruction.instruction_type);
+            },
+            other => panic!("Unexpected event {:?}", other)
+        }
+
+        let response = AuthAccountResponse {
+            account: AccountIdentity {
+                id: "user".to_string(),
+                display_name: "User".to_string(),
+                protocol: "example_protocol".to_string(),
+            },
+            result: AuthResult::Success,
+            details: String::new(),
+        };
+        let response = create_core_instruction(CoreInstructionType::AuthAccountResponse, response);
+        assert_ok!(core.handle_instruction(&test_plugin_binary, &response));
+        assert_eq!(1, core.get_accounts().get_accounts(&test_plugin_binary).len());
+
+        assert_ok!(core.get_process_manager().restart_process(&test_plugin_binary));
+        let event = assert_some!(assert_ok!(timeout(Duration::from_secs(10), core.next_event()).await));
+        assert!(matches!(&event, ProcessEvent::Unloaded(path) if *path == test_plugin_binary), "Unexpected event {:?}", event);
+        assert!(core.get_accounts().get_accounts(&test_plugin_binary).is_empty());
+    }
+
+    /**
+     * This function verifies that a message the core sends through a plugin
+     * goes from Pending to Sent once the plugin says it was sent.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_sent_message_state() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        assert_ok!(core.get_process_manager().load_process(&test_plugin_binary));
+        let mut state = core.get_process_manager().get_processes()[0].subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+
+        let local_id = assert_ok!(core.send_message(&test_plugin_binary, "user", "conversation", "Hello").await);
+        assert_eq!(DeliveryState::Pending, assert_some!(core.get_outbox().get_message(&test_plugin_binary, &local_id)).state);
+
+        assert_ok!(timeout(Duration::from_secs(10), async {
+            while assert_some!(core.get_outbox().get_message(&test_plugin_binary, &local_id)).state == DeliveryState::Pending {
+                assert_some!(core.next_event().await);
+            }
+        }).await);
+        let message = assert_some!(core.get_outbox().get_message(&test_plugin_binary, &local_id));
+        assert_eq!(DeliveryState::Sent, message.state);
+        assert_eq!(Some(format!("example-{}", local_id)), message.message_id);
+    }
+
+    /**
+     * This function verifies that the core sends a plugin the message changes
+     * its Init declares it supports, and applies the plugin's answers.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_core_changes_messages() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let plugin_dir: PathBuf = testdir!();
+        assert_ok!(std::fs::copy(&test_plugin_binary, plugin_dir.join("test_plugin.exe")));
+        let env = HashMap::from([("TEST_PLUGIN_CAPABILITIES".to_string(), "edit,react".to_string())]);
+        write_manifest(&plugin_dir, "test-plugin", "test_plugin.exe", env);
+        let mut core = Core::with_process_manager(ProcessManager::new());
+        assert_ok!(core.get_process_manager().load_plugin(&plugin_dir));
+        let plugin = core.get_process_manager().get_processes()[0].get_path().to_path_buf();
+        let mut state = core.get_process_manager().get_processes()[0].subscribe_state();
+        assert_ok!(assert_ok!(timeout(Duration::from_secs(10), state.wait_for(|s| *s == ProcessState::Initialized)).await));
+
+        let received = MessageReceivedInstruction {
+            account_id: "user".to_string(),
+            message: Message {
+                id: "42".to_string(),
+                conversation_id: "conversation".to_string(),
+                author: Participant { id: "user".to_string(), display_name: "User".to_string() },
+                body: "Hello".to_string(),
+                sent_at_ms: 1_700_000_000_000,
+            },
+        };
+        assert_ok!(core.handle_instruction(&plugin, &create_core_instruction(CoreInstructionType::MessageReceived, received)));
+
+        let edit = EditMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "42".to_string(),
+            body: "Hello again".to_string(),
+        };
+        assert_ok!(core.edit_message(&plugin, edit).await);
+        let message = assert_some!(core.get_messages().get_message(&plugin, "user", "conversation", "42"));
+        assert_eq!("Hello again", message.message.body);
+        assert_some!(message.edited_at_ms);
+
+        // The test plugin declares it can react, but always fails to.
+        let react = ReactToMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "42".to_string(),
+            reaction: "👍".to_string(),
+            action: ReactionAction::Add,
+        };
+        let err = assert_err!(core.react_to_message(&plugin, react).await);
+        assert!(matches!(err.downcast_ref::<CoreError>(), Some(CoreError::ActionFailed { .. })), "Unexpected error {}", err);
+
+        let delete = DeleteMessageInstruction {
+            account_id: "user".to_string(),
+            conversation_id: "conversation".to_string(),
+            message_id: "42".to_string(),
+        };
+        let err = assert_err!(core.delete_message(&plugin, delete).await);
+        assert!(matches!(err.downcast_ref::<CoreError>(), Some(CoreError::Unsupported { .. })), "Unexpected error {}", err);
+        assert_some!(core.get_messages().get_message(&plugin, "user", "conversation", "42"));
+    }
+
+    /**
+     * This function tests that what the plugin prints is captured instead of lost.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_plugin_output_is_captured() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut process_manager = ProcessManager::new();
+        assert_ok!(process_manager.load_process(&test_plugin_binary));
+
+        let expected = OutputLine { stream: OutputStream::Stdout, line: "Test Example plugin starting.".to_string() };
+        assert_ok!(timeout(Duration::from_secs(5), async {
+            while !process_manager.get_processes()[0].get_recent_output().contains(&expected) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await);
+    }
+
+    /**
+     * This function tests that the keepalive supervisor gets responses from a real plugin.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_keepalive_responses() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let config = ProcessConfig {
+            keepalive: KeepaliveConfig { interval: Duration::from_millis(20), max_missed: 3 },
+            // Don't wait long on a ping that was lost.
+            request_timeouts: RequestTimeouts {
+                default: Duration::from_secs(30),
+                per_type: HashMap::from([(PluginInstructionType::Keepalive, Duration::from_millis(200))]),
+            },
+            ..Default::default()
+        };
+        let mut process_manager = ProcessManager::with_config(config);
+        assert_ok!(process_manager.load_process(&test_plugin_binary));
+
+        let stats = assert_ok!(timeout(Duration::from_secs(5), async {
+            loop {
+                let stats = assert_some!(process_manager.get_keepalive_stats(&test_plugin_binary));
+                if stats.last_rtt.is_some() {
+                    break stats;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await);
+        assert_eq!(0, stats.missed);
+        assert!(!stats.hung);
+    }
+
+    /**
+     * This function verifies that a request made right after the plugin is
+     * loaded waits for it to be initialized, instead of being sent before its
+     * InitAck and ignored.
+     */
+    #[rstest]
+    #[test_log::test(tokio::test)]
+    async fn integration_test_request_before_init_is_answered() {
+        let test_plugin_binary = assert_cmd::cargo::cargo_bin("test-plugin");
+        let mut process_manager = ProcessManager::new();
+        assert_ok!(process_manager.load_process(&test_plugin_binary));
+
+        let handle = process_manager.get_processes()[0].get_handle();
+        let response = assert_ok!(assert_ok!(timeout(Duration::from_secs(10),
+            handle.request(PluginInstructionType::Keepalive, KeepaliveInstruction { id: 7 })).await));
+        assert_eq!(CoreInstructionType::KeepaliveResponse, response.instruction_type);
+        assert_eq!(7, assert_ok!(response.payload.decode::<KeepaliveInstruction>()).id);
+    }
+
 }
\ No newline at end of file